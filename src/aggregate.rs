@@ -0,0 +1,246 @@
+//! Summaries of readings over time windows.
+//!
+//! An [`Aggregator`] turns a stream of readings into one reading per window,
+//! whose measurements are the window means and whose [`Window`] holds the
+//! min, mean, max and last value of every field. Windows end on multiples of
+//! the step since the Unix epoch, so summaries from several readers line up.
+
+use chrono::{DateTime, TimeDelta, Utc};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, VecDeque};
+
+use crate::comfort::Comfort;
+use crate::protocol::SensorData;
+use crate::reading::Reading;
+
+/// Statistics of one field over a window. The mean is rounded to 0.01.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct FieldStats {
+    pub min: f64,
+    pub mean: f64,
+    pub max: f64,
+    pub last: f64,
+}
+
+/// The window a summary [`Reading`] covers.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Window {
+    pub start: DateTime<Utc>,
+    pub end: DateTime<Utc>,
+    /// Readings in the window.
+    pub samples: usize,
+    /// Statistics by field name, for the measurements and any derived
+    /// metrics.
+    pub fields: BTreeMap<String, FieldStats>,
+}
+
+/// Collects readings into tumbling or sliding windows.
+#[derive(Debug, Clone)]
+pub struct Aggregator {
+    window: TimeDelta,
+    step: TimeDelta,
+    samples: VecDeque<Reading>,
+    /// End of the next window to emit.
+    next_end: Option<DateTime<Utc>>,
+}
+
+impl Aggregator {
+    /// Summarizes the last `window` every `step`; tumbling if the two are
+    /// equal, sliding if `step` is shorter.
+    ///
+    /// # Panics
+    ///
+    /// If `step` is not positive.
+    pub fn new(window: TimeDelta, step: TimeDelta) -> Self {
+        assert!(step > TimeDelta::zero(), "step must be positive");
+        Self {
+            window,
+            step,
+            samples: VecDeque::new(),
+            next_end: None,
+        }
+    }
+
+    /// The first window end after `at`.
+    fn boundary_after(&self, at: DateTime<Utc>) -> DateTime<Utc> {
+        let step = self.step.num_milliseconds();
+        let millis = at.timestamp_millis().div_euclid(step) * step + step;
+        DateTime::from_timestamp_millis(millis).unwrap_or(at)
+    }
+
+    /// Adds `reading`, returning the summaries of the windows that ended
+    /// before it was captured.
+    ///
+    /// Windows are only closed by the next reading, so a summary comes late
+    /// when the sensor goes quiet. Windows without readings are skipped.
+    pub fn push(&mut self, reading: Reading) -> Vec<Reading> {
+        let at = reading.captured_at;
+        let mut end = match self.next_end {
+            Some(end) => end,
+            None => self.boundary_after(at),
+        };
+        let mut summaries = Vec::new();
+        while at >= end {
+            summaries.extend(self.summarize(end));
+            end += self.step;
+            while self
+                .samples
+                .front()
+                .is_some_and(|s| s.captured_at < end - self.window)
+            {
+                self.samples.pop_front();
+            }
+            if self.samples.is_empty() {
+                end = end.max(self.boundary_after(at));
+            }
+        }
+        self.next_end = Some(end);
+        self.samples.push_back(reading);
+        summaries
+    }
+
+    /// Summarizes the readings of the current, unfinished window, e.g. on
+    /// shutdown.
+    pub fn flush(&mut self) -> Option<Reading> {
+        let summary = self.summarize(self.next_end?);
+        self.samples.clear();
+        self.next_end = None;
+        summary
+    }
+
+    fn summarize(&self, end: DateTime<Utc>) -> Option<Reading> {
+        let last = self.samples.back()?;
+        let mut values: BTreeMap<&str, Vec<f64>> = BTreeMap::new();
+        for sample in &self.samples {
+            let fields = sample.data.fields().into_iter();
+            for (name, value) in fields.chain(sample.comfort.fields()) {
+                values.entry(name).or_default().push(value);
+            }
+        }
+        let fields: BTreeMap<String, FieldStats> = values
+            .into_iter()
+            .map(|(name, values)| {
+                let stats = FieldStats {
+                    min: values.iter().copied().fold(f64::INFINITY, f64::min),
+                    mean: (values.iter().sum::<f64>() / values.len() as f64 * 100.0).round()
+                        / 100.0,
+                    max: values.iter().copied().fold(f64::NEG_INFINITY, f64::max),
+                    last: values[values.len() - 1],
+                };
+                (name.to_string(), stats)
+            })
+            .collect();
+
+        let mean = |name: &str| fields.get(name).map(|stats| stats.mean);
+        let whole = |name: &str| mean(name).unwrap_or_default().round() as u16;
+        let tenths = |name: &str| mean(name).map(|v| (v * 10.0).round() / 10.0);
+        Some(Reading {
+            captured_at: end,
+            data: SensorData {
+                eco2: whole("eco2"),
+                ech2o: whole("ech2o"),
+                tvoc: whole("tvoc"),
+                pm2_5: whole("pm2_5"),
+                pm10: whole("pm10"),
+                temperature: tenths("temperature").unwrap_or_default() as f32,
+                humidity: tenths("humidity").unwrap_or_default() as f32,
+            },
+            comfort: Comfort {
+                dew_point: tenths("dew_point"),
+                absolute_humidity: tenths("absolute_humidity"),
+                heat_index: tenths("heat_index"),
+                humidex: tenths("humidex"),
+            },
+            window: Some(Window {
+                start: end - self.window,
+                end,
+                samples: self.samples.len(),
+                fields,
+            }),
+            ..last.clone()
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::reading::{DeviceInfo, Stamper};
+
+    fn at(secs: i64) -> DateTime<Utc> {
+        DateTime::from_timestamp(1_714_564_800 + secs, 0).unwrap()
+    }
+
+    fn reading(stamper: &mut Stamper, secs: i64, pm2_5: u16) -> Reading {
+        let data = SensorData {
+            eco2: 400,
+            ech2o: 5,
+            tvoc: 10,
+            pm2_5,
+            pm10: 30,
+            temperature: 20.0,
+            humidity: 50.0,
+        };
+        stamper.stamp_at(data, at(secs))
+    }
+
+    #[test]
+    fn test_tumbling() {
+        let mut stamper = Stamper::new(DeviceInfo::default());
+        let mut aggregator = Aggregator::new(TimeDelta::seconds(10), TimeDelta::seconds(10));
+        for (secs, pm2_5) in [(1, 10), (5, 20), (9, 60)] {
+            assert!(
+                aggregator
+                    .push(reading(&mut stamper, secs, pm2_5))
+                    .is_empty()
+            );
+        }
+
+        let summaries = aggregator.push(reading(&mut stamper, 12, 5));
+        assert_eq!(summaries.len(), 1);
+        let summary = &summaries[0];
+        assert_eq!(summary.captured_at, at(10));
+        assert_eq!(summary.seq, 2);
+        assert_eq!(summary.data.pm2_5, 30);
+        let window = summary.window.as_ref().unwrap();
+        assert_eq!((window.start, window.end), (at(0), at(10)));
+        assert_eq!(window.samples, 3);
+        assert_eq!(
+            window.fields["pm2_5"],
+            FieldStats {
+                min: 10.0,
+                mean: 30.0,
+                max: 60.0,
+                last: 60.0
+            }
+        );
+
+        // Empty windows are skipped
+        let summaries = aggregator.push(reading(&mut stamper, 45, 5));
+        assert_eq!(summaries.len(), 1);
+        assert_eq!(summaries[0].captured_at, at(20));
+        assert_eq!(summaries[0].window.as_ref().unwrap().samples, 1);
+
+        let flushed = aggregator.flush().unwrap();
+        assert_eq!(flushed.captured_at, at(50));
+        assert_eq!(aggregator.flush(), None);
+    }
+
+    #[test]
+    fn test_sliding() {
+        let mut stamper = Stamper::new(DeviceInfo::default());
+        let mut aggregator = Aggregator::new(TimeDelta::seconds(20), TimeDelta::seconds(10));
+        let mut summaries = Vec::new();
+        for secs in [1, 11, 21, 31] {
+            summaries.extend(aggregator.push(reading(&mut stamper, secs, secs as u16)));
+        }
+        let windows: Vec<_> = summaries
+            .iter()
+            .map(|s| {
+                let window = s.window.as_ref().unwrap();
+                (window.end, window.samples, s.data.pm2_5)
+            })
+            .collect();
+        assert_eq!(windows, [(at(10), 1, 1), (at(20), 2, 6), (at(30), 2, 16)]);
+    }
+}
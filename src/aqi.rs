@@ -0,0 +1,412 @@
+//! Air Quality Index from the particulate readings.
+//!
+//! Two standards are supported:
+//!
+//! - US EPA AQI (2024 PM2.5 breakpoints), computed from the NowCast of the
+//!   last 12 hours as AirNow does for current conditions.
+//! - China HJ 633-2012 IAQI, computed from the average of the last hour
+//!   against the 24-hour breakpoints, as the national real-time AQI does.
+
+use chrono::{DateTime, TimeDelta, Utc};
+use serde::{Deserialize, Serialize};
+use std::collections::VecDeque;
+
+use crate::reading::Reading;
+
+/// Which AQI scale to compute.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum AqiStandard {
+    UsEpa,
+    China,
+}
+
+/// Field names of the indices when they are flattened next to the
+/// measurements, in [`AqiStandard`] order.
+pub const AQI_FIELDS: [&str; 2] = ["aqi_us_epa", "aqi_china"];
+
+impl AqiStandard {
+    /// Name of the field the index is exported as, see [`AQI_FIELDS`].
+    pub fn field(self) -> &'static str {
+        match self {
+            AqiStandard::UsEpa => AQI_FIELDS[0],
+            AqiStandard::China => AQI_FIELDS[1],
+        }
+    }
+}
+
+/// A pollutant the index can be driven by.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Pollutant {
+    Pm2_5,
+    Pm10,
+}
+
+/// An index value as attached to a [`Reading`].
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Aqi {
+    pub standard: AqiStandard,
+    /// The higher of the two sub-indices.
+    pub index: u16,
+    pub category: String,
+    /// The pollutant with the highest sub-index. China only names one when
+    /// the index is above 50.
+    pub dominant: Option<Pollutant>,
+    /// Sub-index for PM2.5.
+    pub pm2_5: u16,
+    /// Sub-index for PM10.
+    pub pm10: u16,
+}
+
+/// Breakpoint segments: concentration low and high, index low and high.
+type Scale = [(f64, f64, f64, f64)];
+
+/// US EPA PM2.5, µg/m³ truncated to 0.1.
+const EPA_PM2_5: &Scale = &[
+    (0.0, 9.0, 0.0, 50.0),
+    (9.1, 35.4, 51.0, 100.0),
+    (35.5, 55.4, 101.0, 150.0),
+    (55.5, 125.4, 151.0, 200.0),
+    (125.5, 225.4, 201.0, 300.0),
+    (225.5, 325.4, 301.0, 500.0),
+];
+
+/// US EPA PM10, µg/m³ truncated to an integer.
+const EPA_PM10: &Scale = &[
+    (0.0, 54.0, 0.0, 50.0),
+    (55.0, 154.0, 51.0, 100.0),
+    (155.0, 254.0, 101.0, 150.0),
+    (255.0, 354.0, 151.0, 200.0),
+    (355.0, 424.0, 201.0, 300.0),
+    (425.0, 604.0, 301.0, 500.0),
+];
+
+const EPA_CATEGORIES: [(u16, &str); 6] = [
+    (50, "Good"),
+    (100, "Moderate"),
+    (150, "Unhealthy for Sensitive Groups"),
+    (200, "Unhealthy"),
+    (300, "Very Unhealthy"),
+    (u16::MAX, "Hazardous"),
+];
+
+/// HJ 633-2012 PM2.5 24-hour average, µg/m³.
+const CHINA_PM2_5: &Scale = &[
+    (0.0, 35.0, 0.0, 50.0),
+    (35.0, 75.0, 50.0, 100.0),
+    (75.0, 115.0, 100.0, 150.0),
+    (115.0, 150.0, 150.0, 200.0),
+    (150.0, 250.0, 200.0, 300.0),
+    (250.0, 350.0, 300.0, 400.0),
+    (350.0, 500.0, 400.0, 500.0),
+];
+
+/// HJ 633-2012 PM10 24-hour average, µg/m³.
+const CHINA_PM10: &Scale = &[
+    (0.0, 50.0, 0.0, 50.0),
+    (50.0, 150.0, 50.0, 100.0),
+    (150.0, 250.0, 100.0, 150.0),
+    (250.0, 350.0, 150.0, 200.0),
+    (350.0, 420.0, 200.0, 300.0),
+    (420.0, 500.0, 300.0, 400.0),
+    (500.0, 600.0, 400.0, 500.0),
+];
+
+const CHINA_CATEGORIES: [(u16, &str); 6] = [
+    (50, "Excellent"),
+    (100, "Good"),
+    (150, "Lightly Polluted"),
+    (200, "Moderately Polluted"),
+    (300, "Heavily Polluted"),
+    (u16::MAX, "Severely Polluted"),
+];
+
+/// Interpolates `c` linearly within its segment of `scale`, capped at the top
+/// of the scale.
+fn interpolate(scale: &Scale, c: f64) -> f64 {
+    match scale.iter().find(|(_, c_hi, _, _)| c <= *c_hi) {
+        Some(&(c_lo, c_hi, i_lo, i_hi)) => {
+            (i_hi - i_lo) / (c_hi - c_lo) * (c.max(c_lo) - c_lo) + i_lo
+        }
+        None => scale.last().map_or(0.0, |segment| segment.3),
+    }
+}
+
+fn category(categories: &[(u16, &'static str)], index: u16) -> &'static str {
+    categories
+        .iter()
+        .find(|(max, _)| index <= *max)
+        .map_or("", |(_, name)| name)
+}
+
+/// US EPA sub-index for a concentration in µg/m³.
+pub fn us_epa_index(pollutant: Pollutant, concentration: f64) -> u16 {
+    let (scale, truncated) = match pollutant {
+        Pollutant::Pm2_5 => (EPA_PM2_5, (concentration * 10.0).floor() / 10.0),
+        Pollutant::Pm10 => (EPA_PM10, concentration.floor()),
+    };
+    interpolate(scale, truncated.max(0.0)).round() as u16
+}
+
+/// HJ 633-2012 IAQI for a concentration in µg/m³, rounded up as the standard
+/// requires.
+pub fn china_iaqi(pollutant: Pollutant, concentration: f64) -> u16 {
+    let scale = match pollutant {
+        Pollutant::Pm2_5 => CHINA_PM2_5,
+        Pollutant::Pm10 => CHINA_PM10,
+    };
+    interpolate(scale, concentration.max(0.0)).ceil() as u16
+}
+
+/// EPA NowCast for particulates from hourly averages, most recent first.
+///
+/// Returns `None` unless at least two of the three most recent hours have
+/// data.
+pub fn nowcast(hourly: &[Option<f64>]) -> Option<f64> {
+    let hourly = &hourly[..hourly.len().min(12)];
+    if hourly.iter().take(3).flatten().count() < 2 {
+        return None;
+    }
+    let min = hourly.iter().flatten().copied().reduce(f64::min)?;
+    let max = hourly.iter().flatten().copied().reduce(f64::max)?;
+    let weight = if max > 0.0 { (min / max).max(0.5) } else { 1.0 };
+
+    let mut sum = 0.0;
+    let mut weights = 0.0;
+    for (hour, value) in hourly.iter().enumerate() {
+        if let Some(value) = value {
+            let w = weight.powi(hour as i32);
+            sum += w * value;
+            weights += w;
+        }
+    }
+    Some(sum / weights)
+}
+
+/// Builds an [`Aqi`] from the two sub-indices.
+fn combine(standard: AqiStandard, pm2_5: u16, pm10: u16) -> Aqi {
+    let index = pm2_5.max(pm10);
+    let (categories, named_above) = match standard {
+        AqiStandard::UsEpa => (&EPA_CATEGORIES, 0),
+        AqiStandard::China => (&CHINA_CATEGORIES, 50),
+    };
+    let dominant = (index > named_above).then_some(if pm2_5 >= pm10 {
+        Pollutant::Pm2_5
+    } else {
+        Pollutant::Pm10
+    });
+    Aqi {
+        standard,
+        index,
+        category: category(categories, index).to_string(),
+        dominant,
+        pm2_5,
+        pm10,
+    }
+}
+
+/// Particulate totals for one minute.
+#[derive(Debug, Clone, Copy)]
+struct Minute {
+    start: DateTime<Utc>,
+    pm2_5: f64,
+    pm10: f64,
+    count: u32,
+}
+
+/// Keeps the particulate history an index needs and computes it for every
+/// new reading.
+#[derive(Debug, Clone)]
+pub struct AqiTracker {
+    standard: AqiStandard,
+    minutes: VecDeque<Minute>,
+}
+
+impl AqiTracker {
+    pub fn new(standard: AqiStandard) -> Self {
+        Self {
+            standard,
+            minutes: VecDeque::new(),
+        }
+    }
+
+    fn record(&mut self, reading: &Reading) {
+        let at = reading.captured_at;
+        let start = DateTime::from_timestamp(at.timestamp().div_euclid(60) * 60, 0).unwrap_or(at);
+        let (pm2_5, pm10) = (f64::from(reading.data.pm2_5), f64::from(reading.data.pm10));
+        match self.minutes.back_mut() {
+            Some(minute) if minute.start == start => {
+                minute.pm2_5 += pm2_5;
+                minute.pm10 += pm10;
+                minute.count += 1;
+            }
+            _ => self.minutes.push_back(Minute {
+                start,
+                pm2_5,
+                pm10,
+                count: 1,
+            }),
+        }
+        let cutoff = at - TimeDelta::hours(12);
+        while self.minutes.front().is_some_and(|m| m.start <= cutoff) {
+            self.minutes.pop_front();
+        }
+    }
+
+    /// Hourly averages of PM2.5 and PM10 over the 12 hours up to `now`, most
+    /// recent first. Hours are counted back from `now` rather than on the
+    /// clock.
+    fn hourly(&self, now: DateTime<Utc>) -> [(Option<f64>, Option<f64>); 12] {
+        let mut sums = [(0.0, 0.0, 0u32); 12];
+        for minute in &self.minutes {
+            let hour = (now - minute.start).num_minutes() / 60;
+            if let Some(sum) = usize::try_from(hour).ok().and_then(|h| sums.get_mut(h)) {
+                sum.0 += minute.pm2_5;
+                sum.1 += minute.pm10;
+                sum.2 += minute.count;
+            }
+        }
+        sums.map(|(pm2_5, pm10, n)| {
+            if n == 0 {
+                (None, None)
+            } else {
+                let n = f64::from(n);
+                (Some(pm2_5 / n), Some(pm10 / n))
+            }
+        })
+    }
+
+    /// Adds `reading` to the history and returns the index as of it.
+    ///
+    /// The US EPA index needs readings in at least two of the last three
+    /// hours, so it is `None` for about the first hour.
+    pub fn update(&mut self, reading: &Reading) -> Option<Aqi> {
+        self.record(reading);
+        let hourly = self.hourly(reading.captured_at);
+        let (pm2_5, pm10) = match self.standard {
+            AqiStandard::UsEpa => {
+                let pm2_5: Vec<Option<f64>> = hourly.iter().map(|h| h.0).collect();
+                let pm10: Vec<Option<f64>> = hourly.iter().map(|h| h.1).collect();
+                (
+                    us_epa_index(Pollutant::Pm2_5, nowcast(&pm2_5)?),
+                    us_epa_index(Pollutant::Pm10, nowcast(&pm10)?),
+                )
+            }
+            AqiStandard::China => {
+                let (pm2_5, pm10) = hourly[0];
+                (
+                    china_iaqi(Pollutant::Pm2_5, pm2_5?),
+                    china_iaqi(Pollutant::Pm10, pm10?),
+                )
+            }
+        };
+        Some(combine(self.standard, pm2_5, pm10))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::protocol::SensorData;
+    use crate::reading::{DeviceInfo, Stamper};
+
+    #[test]
+    fn test_us_epa_index() {
+        assert_eq!(us_epa_index(Pollutant::Pm2_5, 0.0), 0);
+        assert_eq!(us_epa_index(Pollutant::Pm2_5, 9.0), 50);
+        assert_eq!(us_epa_index(Pollutant::Pm2_5, 9.05), 50);
+        assert_eq!(us_epa_index(Pollutant::Pm2_5, 9.1), 51);
+        assert_eq!(us_epa_index(Pollutant::Pm2_5, 35.4), 100);
+        assert_eq!(us_epa_index(Pollutant::Pm2_5, 55.5), 151);
+        assert_eq!(us_epa_index(Pollutant::Pm2_5, 500.0), 500);
+        assert_eq!(us_epa_index(Pollutant::Pm10, 54.9), 50);
+        assert_eq!(us_epa_index(Pollutant::Pm10, 100.0), 73);
+        assert_eq!(us_epa_index(Pollutant::Pm10, 604.0), 500);
+    }
+
+    #[test]
+    fn test_china_iaqi() {
+        assert_eq!(china_iaqi(Pollutant::Pm2_5, 35.0), 50);
+        assert_eq!(china_iaqi(Pollutant::Pm2_5, 35.1), 51);
+        assert_eq!(china_iaqi(Pollutant::Pm2_5, 80.0), 107);
+        assert_eq!(china_iaqi(Pollutant::Pm10, 150.0), 100);
+        assert_eq!(china_iaqi(Pollutant::Pm10, 700.0), 500);
+
+        let aqi = combine(AqiStandard::China, 107, 64);
+        assert_eq!(aqi.index, 107);
+        assert_eq!(aqi.category, "Lightly Polluted");
+        assert_eq!(aqi.dominant, Some(Pollutant::Pm2_5));
+        assert_eq!(combine(AqiStandard::China, 20, 30).dominant, None);
+        assert_eq!(
+            combine(AqiStandard::UsEpa, 20, 30).dominant,
+            Some(Pollutant::Pm10)
+        );
+    }
+
+    #[test]
+    fn test_nowcast() {
+        let hourly = [
+            Some(64.0),
+            Some(63.0),
+            Some(72.0),
+            Some(77.0),
+            Some(65.0),
+            Some(61.0),
+            Some(70.0),
+            Some(71.0),
+            Some(64.0),
+            Some(57.0),
+            Some(58.0),
+            Some(64.0),
+        ];
+        let value = nowcast(&hourly).unwrap();
+        assert!((value - 66.57).abs() < 0.01, "{}", value);
+
+        assert_eq!(nowcast(&[Some(10.0), None, None, Some(5.0)]), None);
+        assert_eq!(nowcast(&[Some(10.0), None, Some(10.0)]), Some(10.0));
+    }
+
+    #[test]
+    fn test_tracker() {
+        let mut stamper = Stamper::new(DeviceInfo::default());
+        let start = DateTime::parse_from_rfc3339("2024-05-01T00:00:00Z")
+            .unwrap()
+            .to_utc();
+        let reading = |stamper: &mut Stamper, minutes: i64, pm2_5: u16| {
+            let data = SensorData {
+                eco2: 400,
+                ech2o: 5,
+                tvoc: 10,
+                pm2_5,
+                pm10: 30,
+                temperature: 25.5,
+                humidity: 50.2,
+            };
+            stamper.stamp_at(data, start + TimeDelta::minutes(minutes))
+        };
+
+        let mut epa = AqiTracker::new(AqiStandard::UsEpa);
+        let mut china = AqiTracker::new(AqiStandard::China);
+        let first = reading(&mut stamper, 0, 20);
+        assert_eq!(epa.update(&first), None);
+        assert_eq!(china.update(&first).unwrap().pm2_5, 29);
+
+        let mut latest = None;
+        for minute in 1..=90 {
+            let r = reading(&mut stamper, minute, 20);
+            latest = epa.update(&r);
+            china.update(&r);
+        }
+        let aqi = latest.unwrap();
+        assert_eq!(aqi.pm2_5, us_epa_index(Pollutant::Pm2_5, 20.0));
+        assert_eq!(aqi.index, 71);
+        assert_eq!(aqi.category, "Moderate");
+        assert_eq!(aqi.dominant, Some(Pollutant::Pm2_5));
+
+        // Only the last 12 hours are kept
+        let late = reading(&mut stamper, 24 * 60, 20);
+        epa.update(&late);
+        assert_eq!(epa.minutes.len(), 1);
+    }
+}
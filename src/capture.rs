@@ -0,0 +1,189 @@
+//! Raw serial captures, for replaying exactly what a sensor sent.
+//!
+//! A capture is a text file with one line per read from the port: the time
+//! the bytes arrived in RFC 3339 and the bytes in hex, e.g.
+//! `2024-05-01T12:00:00.123456Z 3c020190...`. Lines starting with `#` are
+//! comments.
+
+use anyhow::{Context, Result, anyhow};
+use chrono::{DateTime, SecondsFormat, Utc};
+use std::fs::{File, OpenOptions};
+use std::io::{BufRead, BufReader, BufWriter, Lines, Write};
+use std::path::Path;
+
+/// Bytes received in a single read.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Chunk {
+    pub at: DateTime<Utc>,
+    pub bytes: Vec<u8>,
+}
+
+/// Formats bytes as lowercase hex without separators.
+pub fn encode_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+/// Parses hex, ignoring whitespace between bytes.
+pub fn decode_hex(s: &str) -> Result<Vec<u8>, String> {
+    let digits: Vec<u8> = s.bytes().filter(|b| !b.is_ascii_whitespace()).collect();
+    if !digits.len().is_multiple_of(2) {
+        return Err("odd number of hex digits".to_string());
+    }
+    digits
+        .chunks(2)
+        .map(|pair| {
+            let pair = std::str::from_utf8(pair).unwrap_or_default();
+            u8::from_str_radix(pair, 16).map_err(|_| format!("invalid hex byte '{}'", pair))
+        })
+        .collect()
+}
+
+fn parse_line(line: &str) -> Result<Chunk, String> {
+    let (at, hex) = line.split_once(' ').unwrap_or((line, ""));
+    let at = DateTime::parse_from_rfc3339(at)
+        .map_err(|e| format!("invalid timestamp '{}': {}", at, e))?
+        .to_utc();
+    Ok(Chunk {
+        at,
+        bytes: decode_hex(hex)?,
+    })
+}
+
+/// Appends everything read from the port to a capture file.
+pub struct CaptureWriter {
+    out: BufWriter<File>,
+}
+
+impl CaptureWriter {
+    /// Opens `path` for appending, so a restart adds to an existing capture.
+    pub fn open(path: &Path, port: &str, baud_rate: u32) -> Result<Self> {
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+            .with_context(|| format!("Failed to open capture file '{}'", path.display()))?;
+        let mut writer = Self {
+            out: BufWriter::new(file),
+        };
+        writer.comment(&format!(
+            "capture of {} at {} baud, started {}",
+            port,
+            baud_rate,
+            Utc::now().to_rfc3339_opts(SecondsFormat::Secs, true)
+        ))?;
+        Ok(writer)
+    }
+
+    /// Writes a comment line, ignored on replay.
+    pub fn comment(&mut self, text: &str) -> Result<()> {
+        writeln!(self.out, "# {}", text)?;
+        self.out.flush()?;
+        Ok(())
+    }
+
+    /// Records `bytes` as received at `at`.
+    ///
+    /// Every chunk is flushed right away, so a capture is complete up to the
+    /// moment the reader crashed or was killed.
+    pub fn record(&mut self, at: DateTime<Utc>, bytes: &[u8]) -> Result<()> {
+        writeln!(
+            self.out,
+            "{} {}",
+            at.to_rfc3339_opts(SecondsFormat::Micros, true),
+            encode_hex(bytes)
+        )?;
+        self.out.flush()?;
+        Ok(())
+    }
+}
+
+/// Reads the chunks of a capture file in order.
+pub struct CaptureReader {
+    lines: Lines<BufReader<File>>,
+    line_no: usize,
+}
+
+impl CaptureReader {
+    pub fn open(path: &Path) -> Result<Self> {
+        let file = File::open(path)
+            .with_context(|| format!("Failed to open capture file '{}'", path.display()))?;
+        Ok(Self {
+            lines: BufReader::new(file).lines(),
+            line_no: 0,
+        })
+    }
+}
+
+impl Iterator for CaptureReader {
+    type Item = Result<Chunk>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            let line = match self.lines.next()? {
+                Ok(line) => line,
+                Err(e) => return Some(Err(e.into())),
+            };
+            self.line_no += 1;
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            return Some(parse_line(line).map_err(|e| anyhow!("line {}: {}", self.line_no, e)));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_hex() {
+        assert_eq!(encode_hex(&[0x3c, 0x02, 0xff]), "3c02ff");
+        assert_eq!(decode_hex("3c02ff"), Ok(vec![0x3c, 0x02, 0xff]));
+        assert_eq!(decode_hex("3C 02\nFF"), Ok(vec![0x3c, 0x02, 0xff]));
+        assert_eq!(decode_hex(""), Ok(vec![]));
+        assert!(decode_hex("3c0").is_err());
+        assert!(decode_hex("zz").is_err());
+    }
+
+    #[test]
+    fn test_round_trip() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("capture.txt");
+        let at = DateTime::parse_from_rfc3339("2024-05-01T12:00:00.123456Z")
+            .unwrap()
+            .to_utc();
+
+        let mut writer = CaptureWriter::open(&path, "/dev/ttyUSB0", 9600).unwrap();
+        writer.record(at, &[0x3c, 0x02]).unwrap();
+        writer.record(at, &[]).unwrap();
+        drop(writer);
+        let mut writer = CaptureWriter::open(&path, "/dev/ttyUSB0", 9600).unwrap();
+        writer.record(at, &[0xff]).unwrap();
+        drop(writer);
+
+        let chunks: Vec<Chunk> = CaptureReader::open(&path)
+            .unwrap()
+            .collect::<Result<_>>()
+            .unwrap();
+        assert_eq!(
+            chunks,
+            [
+                Chunk {
+                    at,
+                    bytes: vec![0x3c, 0x02]
+                },
+                Chunk { at, bytes: vec![] },
+                Chunk {
+                    at,
+                    bytes: vec![0xff]
+                },
+            ]
+        );
+
+        std::fs::write(&path, "# comment\n2024-05-01 3c\n").unwrap();
+        let err = CaptureReader::open(&path).unwrap().next().unwrap();
+        assert!(err.unwrap_err().to_string().starts_with("line 2:"));
+    }
+}
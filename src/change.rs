@@ -0,0 +1,201 @@
+//! Report-on-change: drops readings that do not differ meaningfully from the
+//! last one passed on.
+
+use chrono::{DateTime, TimeDelta, Utc};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+
+use crate::comfort::COMFORT_FIELDS;
+use crate::protocol::SensorData;
+use crate::reading::Reading;
+
+/// How far a field has to move before a reading counts as changed.
+///
+/// The threshold is the larger of the two, so `absolute` acts as a floor for
+/// `relative` near zero.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Deadband {
+    /// In the field's unit.
+    pub absolute: f64,
+    /// As a fraction of the last value passed on, e.g. 0.05 for 5%.
+    pub relative: f64,
+}
+
+impl Deadband {
+    fn exceeded(&self, last: f64, value: f64) -> bool {
+        (value - last).abs() > self.absolute.max(self.relative * last.abs())
+    }
+}
+
+/// Passes a reading on when a field moved beyond its deadband since the last
+/// reading passed on, or when the heartbeat is due.
+///
+/// Comfort metrics only count with a deadband of their own; without one they
+/// follow the temperature and humidity they are derived from. A metric that
+/// becomes defined or undefined counts as changed.
+#[derive(Debug, Clone)]
+pub struct ChangeFilter {
+    /// By field name; measurements without one pass on any change.
+    deadbands: BTreeMap<String, Deadband>,
+    heartbeat: Option<TimeDelta>,
+    last: Option<(DateTime<Utc>, SensorData, [Option<f64>; 4])>,
+}
+
+impl ChangeFilter {
+    pub fn new(deadbands: BTreeMap<String, Deadband>, heartbeat: Option<TimeDelta>) -> Self {
+        Self {
+            deadbands,
+            heartbeat,
+            last: None,
+        }
+    }
+
+    /// Returns whether `reading` should be passed on, and if so remembers it
+    /// as the new reference.
+    pub fn accept(&mut self, reading: &Reading) -> bool {
+        let changed = match &self.last {
+            None => true,
+            Some((at, last, last_comfort)) => {
+                self.heartbeat
+                    .is_some_and(|heartbeat| reading.captured_at - *at >= heartbeat)
+                    || last.fields().iter().zip(reading.data.fields()).any(
+                        |((name, last), (_, value))| {
+                            let deadband = self.deadbands.get(*name).copied().unwrap_or_default();
+                            deadband.exceeded(*last, value)
+                        },
+                    )
+                    || COMFORT_FIELDS
+                        .iter()
+                        .zip(last_comfort.iter().zip(reading.comfort.values()))
+                        .any(|(name, (last, value))| {
+                            self.deadbands
+                                .get(*name)
+                                .is_some_and(|deadband| match (*last, value) {
+                                    (Some(last), Some(value)) => deadband.exceeded(last, value),
+                                    (last, value) => last.is_some() != value.is_some(),
+                                })
+                        })
+            }
+        };
+        if changed {
+            self.last = Some((
+                reading.captured_at,
+                reading.data.clone(),
+                reading.comfort.values(),
+            ));
+        }
+        changed
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::comfort::{Comfort, ComfortMetric};
+    use crate::reading::{DeviceInfo, Stamper};
+
+    fn reading(stamper: &mut Stamper, secs: i64, pm2_5: u16, temperature: f32) -> Reading {
+        let data = SensorData {
+            eco2: 400,
+            ech2o: 5,
+            tvoc: 10,
+            pm2_5,
+            pm10: 30,
+            temperature,
+            humidity: 50.0,
+        };
+        stamper.stamp_at(data, DateTime::from_timestamp(secs, 0).unwrap())
+    }
+
+    #[test]
+    fn test_deadband() {
+        let band = Deadband {
+            absolute: 2.0,
+            relative: 0.1,
+        };
+        assert!(!band.exceeded(10.0, 12.0));
+        assert!(band.exceeded(10.0, 12.5));
+        // 10% of 100 is above the absolute floor
+        assert!(!band.exceeded(100.0, 109.0));
+        assert!(band.exceeded(100.0, 89.0));
+        assert!(Deadband::default().exceeded(1.0, 1.1));
+        assert!(!Deadband::default().exceeded(1.0, 1.0));
+    }
+
+    #[test]
+    fn test_accept() {
+        let mut stamper = Stamper::new(DeviceInfo::default());
+        let mut filter = ChangeFilter::new(
+            BTreeMap::from([
+                (
+                    "pm2_5".to_string(),
+                    Deadband {
+                        absolute: 2.0,
+                        relative: 0.0,
+                    },
+                ),
+                (
+                    "temperature".to_string(),
+                    Deadband {
+                        absolute: 0.5,
+                        relative: 0.0,
+                    },
+                ),
+            ]),
+            Some(TimeDelta::seconds(60)),
+        );
+        let accepted: Vec<bool> = [
+            (0, 10, 20.0),
+            (1, 11, 20.3),
+            // Drift is measured from the last reading passed on
+            (2, 13, 20.3),
+            (3, 14, 20.3),
+            (4, 14, 20.9),
+            // Heartbeat
+            (64, 14, 20.9),
+            (65, 14, 20.9),
+        ]
+        .into_iter()
+        .map(|(secs, pm2_5, temperature)| {
+            filter.accept(&reading(&mut stamper, secs, pm2_5, temperature))
+        })
+        .collect();
+        assert_eq!(accepted, [true, false, true, false, true, true, false]);
+    }
+
+    #[test]
+    fn test_comfort_deadband() {
+        let mut stamper = Stamper::new(DeviceInfo::default());
+        let deadband = |absolute| Deadband {
+            absolute,
+            relative: 0.0,
+        };
+        let mut filter = ChangeFilter::new(
+            BTreeMap::from([
+                ("temperature".to_string(), deadband(5.0)),
+                ("humidity".to_string(), deadband(50.0)),
+                ("dew_point".to_string(), deadband(1.0)),
+            ]),
+            None,
+        );
+        let accepted: Vec<bool> = [(0, 20.0), (1, 20.5), (2, 22.0)]
+            .into_iter()
+            .map(|(secs, temperature)| {
+                let mut reading = reading(&mut stamper, secs, 10, temperature);
+                reading.comfort = Comfort::compute(
+                    &reading.data,
+                    &[ComfortMetric::DewPoint, ComfortMetric::Humidex],
+                );
+                filter.accept(&reading)
+            })
+            .collect();
+        // The dew point moves by about 0.4 and then 1.8 °C; the humidex has
+        // no deadband and does not count
+        assert_eq!(accepted, [true, false, true]);
+
+        let mut reading = reading(&mut stamper, 3, 10, 22.0);
+        reading.comfort = Comfort::default();
+        assert!(filter.accept(&reading));
+    }
+}
@@ -0,0 +1,209 @@
+//! Humidity and comfort metrics derived from temperature and relative
+//! humidity.
+//!
+//! Saturation vapour pressure uses the Magnus formula with the Sonntag (1990)
+//! constants recommended by the WMO, so dew point and absolute humidity agree
+//! with each other.
+
+use serde::{Deserialize, Serialize};
+
+use crate::protocol::SensorData;
+
+/// Magnus coefficients over water, valid from -45 to 60 °C.
+const MAGNUS_A: f64 = 17.62;
+const MAGNUS_B: f64 = 243.12;
+
+/// Names of the [`Comfort`] fields, as serialized.
+pub const COMFORT_FIELDS: [&str; 4] = ["dew_point", "absolute_humidity", "heat_index", "humidex"];
+
+/// A derived metric that can be attached to readings.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ComfortMetric {
+    DewPoint,
+    AbsoluteHumidity,
+    HeatIndex,
+    Humidex,
+}
+
+impl ComfortMetric {
+    /// Name of the [`Comfort`] field the metric is stored in.
+    pub fn field(self) -> &'static str {
+        match self {
+            ComfortMetric::DewPoint => COMFORT_FIELDS[0],
+            ComfortMetric::AbsoluteHumidity => COMFORT_FIELDS[1],
+            ComfortMetric::HeatIndex => COMFORT_FIELDS[2],
+            ComfortMetric::Humidex => COMFORT_FIELDS[3],
+        }
+    }
+}
+
+/// Derived metrics as attached to a [`Reading`](crate::Reading); only the
+/// configured ones are set.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Comfort {
+    /// Dew point in °C.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub dew_point: Option<f64>,
+    /// Water vapour density in g/m³.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub absolute_humidity: Option<f64>,
+    /// NWS heat index (apparent temperature) in °C.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub heat_index: Option<f64>,
+    /// Environment Canada humidex, dimensionless but read like °C.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub humidex: Option<f64>,
+}
+
+impl Comfort {
+    /// Computes `metrics` from `data`, rounded to 0.1.
+    ///
+    /// A metric is left out when it is undefined for the input, e.g. the dew
+    /// point at 0% humidity.
+    pub fn compute(data: &SensorData, metrics: &[ComfortMetric]) -> Self {
+        let temperature = f64::from(data.temperature);
+        let humidity = f64::from(data.humidity);
+        let mut comfort = Self::default();
+        for metric in metrics {
+            let value = match metric {
+                ComfortMetric::DewPoint => dew_point(temperature, humidity),
+                ComfortMetric::AbsoluteHumidity => absolute_humidity(temperature, humidity),
+                ComfortMetric::HeatIndex => heat_index(temperature, humidity),
+                ComfortMetric::Humidex => humidex(temperature, humidity),
+            };
+            let value = value.is_finite().then(|| (value * 10.0).round() / 10.0);
+            match metric {
+                ComfortMetric::DewPoint => comfort.dew_point = value,
+                ComfortMetric::AbsoluteHumidity => comfort.absolute_humidity = value,
+                ComfortMetric::HeatIndex => comfort.heat_index = value,
+                ComfortMetric::Humidex => comfort.humidex = value,
+            }
+        }
+        comfort
+    }
+
+    /// Returns every metric, set or not, in [`COMFORT_FIELDS`] order.
+    pub fn values(&self) -> [Option<f64>; 4] {
+        [
+            self.dew_point,
+            self.absolute_humidity,
+            self.heat_index,
+            self.humidex,
+        ]
+    }
+
+    /// Returns the name and value of every metric that is set.
+    pub fn fields(&self) -> Vec<(&'static str, f64)> {
+        COMFORT_FIELDS
+            .into_iter()
+            .zip(self.values())
+            .filter_map(|(name, value)| Some((name, value?)))
+            .collect()
+    }
+}
+
+/// Saturation vapour pressure over water in hPa at `temperature` °C.
+fn saturation_pressure(temperature: f64) -> f64 {
+    6.112 * (MAGNUS_A * temperature / (MAGNUS_B + temperature)).exp()
+}
+
+/// Dew point in °C from temperature in °C and relative humidity in %.
+pub fn dew_point(temperature: f64, humidity: f64) -> f64 {
+    let gamma = (humidity / 100.0).ln() + MAGNUS_A * temperature / (MAGNUS_B + temperature);
+    MAGNUS_B * gamma / (MAGNUS_A - gamma)
+}
+
+/// Absolute humidity in g/m³ from temperature in °C and relative humidity
+/// in %.
+pub fn absolute_humidity(temperature: f64, humidity: f64) -> f64 {
+    // 216.7 is 100 Pa/hPa * 1000 g/kg divided by the gas constant of water
+    // vapour, 461.5 J/(kg K)
+    216.7 * humidity / 100.0 * saturation_pressure(temperature) / (273.15 + temperature)
+}
+
+/// US NWS heat index in °C from temperature in °C and relative humidity in %.
+///
+/// Uses Steadman's simple formula in mild conditions and the Rothfusz
+/// regression with its adjustments above 80 °F, as the NWS calculator does.
+pub fn heat_index(temperature: f64, humidity: f64) -> f64 {
+    let t = temperature * 9.0 / 5.0 + 32.0;
+    let rh = humidity;
+    let mut hi = 0.5 * (t + 61.0 + (t - 68.0) * 1.2 + rh * 0.094);
+    if (hi + t) / 2.0 >= 80.0 {
+        hi = -42.379 + 2.049_015_23 * t + 10.143_331_27 * rh
+            - 0.224_755_41 * t * rh
+            - 0.006_837_83 * t * t
+            - 0.054_817_17 * rh * rh
+            + 0.001_228_74 * t * t * rh
+            + 0.000_852_82 * t * rh * rh
+            - 0.000_001_99 * t * t * rh * rh;
+        if rh < 13.0 && (80.0..=112.0).contains(&t) {
+            hi -= (13.0 - rh) / 4.0 * ((17.0 - (t - 95.0).abs()) / 17.0).sqrt();
+        } else if rh > 85.0 && (80.0..=87.0).contains(&t) {
+            hi += (rh - 85.0) / 10.0 * ((87.0 - t) / 5.0);
+        }
+    }
+    (hi - 32.0) * 5.0 / 9.0
+}
+
+/// Humidex from temperature in °C and relative humidity in %.
+pub fn humidex(temperature: f64, humidity: f64) -> f64 {
+    let dew_point = dew_point(temperature, humidity) + 273.15;
+    let vapour_pressure = 6.11 * (5417.753 * (1.0 / 273.16 - 1.0 / dew_point)).exp();
+    temperature + 0.5555 * (vapour_pressure - 10.0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn assert_close(actual: f64, expected: f64) {
+        assert!(
+            (actual - expected).abs() < 0.05,
+            "{} != {}",
+            actual,
+            expected
+        );
+    }
+
+    #[test]
+    fn test_formulas() {
+        assert_close(dew_point(20.0, 50.0), 9.26);
+        assert_close(dew_point(25.0, 100.0), 25.0);
+        assert_close(absolute_humidity(20.0, 50.0), 8.62);
+        assert_close(absolute_humidity(20.0, 0.0), 0.0);
+        // NWS table: 90 °F at 70% is 106 °F
+        assert_close(heat_index(32.2, 70.0), 41.0);
+        // Below 80 °F the heat index stays close to the temperature
+        assert_close(heat_index(20.0, 50.0), 19.36);
+        // Environment Canada: 30 °C with a 15 °C dew point is a humidex of 34
+        assert_close(humidex(30.0, 40.0), 33.92);
+    }
+
+    #[test]
+    fn test_compute() {
+        let mut data = SensorData {
+            eco2: 400,
+            ech2o: 5,
+            tvoc: 10,
+            pm2_5: 20,
+            pm10: 30,
+            temperature: 20.0,
+            humidity: 50.0,
+        };
+        let comfort = Comfort::compute(&data, &[ComfortMetric::DewPoint]);
+        assert_eq!(comfort.dew_point, Some(9.3));
+        assert_eq!(comfort.humidex, None);
+        assert_eq!(comfort.fields(), [("dew_point", 9.3)]);
+
+        data.humidity = 0.0;
+        let comfort = Comfort::compute(
+            &data,
+            &[ComfortMetric::DewPoint, ComfortMetric::AbsoluteHumidity],
+        );
+        assert_eq!(comfort.dew_point, None);
+        assert_eq!(comfort.absolute_humidity, Some(0.0));
+    }
+}
@@ -0,0 +1,272 @@
+//! The `decode` subcommand: explains raw frame bytes, e.g. from a
+//! logic-analyzer dump or the datasheet.
+
+use anyhow::{Context, Result, anyhow, bail};
+use clap::{Args, ValueEnum};
+use sensor_reader::{
+    FIELD_LABELS, FRAME_HEADER_1, FRAME_HEADER_2, FRAME_LEN, FrameError, SensorData,
+    calculate_checksum, parse_frame,
+};
+use serde::Serialize;
+use std::fs;
+use std::io::{self, Read};
+use std::path::PathBuf;
+
+use crate::capture::decode_hex;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum OutputFormat {
+    Text,
+    Json,
+}
+
+#[derive(Args, Debug)]
+pub struct DecodeArgs {
+    /// Frame bytes in hex, e.g. 3C0201900005...; spaces, commas and 0x
+    /// prefixes are ignored. Read from standard input if omitted
+    hex: Vec<String>,
+
+    /// Read the bytes from this file instead
+    #[arg(short, long, conflicts_with = "hex")]
+    input: Option<PathBuf>,
+
+    /// The file or standard input holds raw bytes rather than hex
+    #[arg(long)]
+    binary: bool,
+
+    #[arg(long, value_enum, default_value_t = OutputFormat::Text)]
+    format: OutputFormat,
+}
+
+#[derive(Debug, Serialize)]
+struct Checksum {
+    computed: u8,
+    received: u8,
+}
+
+/// What was found at one frame header.
+#[derive(Debug, Serialize)]
+struct FrameReport {
+    offset: usize,
+    bytes: String,
+    valid: bool,
+    /// Decoded as if the checksum matched, so a corrupted frame can still be
+    /// inspected.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    data: Option<SensorData>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    checksum: Option<Checksum>,
+    warnings: Vec<String>,
+}
+
+/// Parses a hex dump, allowing `0x` prefixes and whitespace, comma or colon
+/// separators.
+fn parse_hex_dump(text: &str) -> Result<Vec<u8>, String> {
+    let mut digits = String::with_capacity(text.len());
+    for token in text.split(|c: char| c.is_whitespace() || c == ',' || c == ':') {
+        let token = token
+            .strip_prefix("0x")
+            .or_else(|| token.strip_prefix("0X"))
+            .unwrap_or(token);
+        // A lone 0x3 means 03, as logic-analyzer exports often write it
+        if token.len() == 1 {
+            digits.push('0');
+        }
+        digits.push_str(token);
+    }
+    decode_hex(&digits)
+}
+
+fn spaced_hex(bytes: &[u8]) -> String {
+    bytes
+        .iter()
+        .map(|b| format!("{:02X}", b))
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+fn find_header(bytes: &[u8], from: usize) -> Option<usize> {
+    bytes[from..]
+        .windows(2)
+        .position(|pair| pair == [FRAME_HEADER_1, FRAME_HEADER_2])
+        .map(|i| i + from)
+}
+
+fn inspect_frame(offset: usize, frame: &[u8]) -> FrameReport {
+    let mut report = FrameReport {
+        offset,
+        bytes: spaced_hex(frame),
+        valid: parse_frame(frame).is_ok(),
+        data: None,
+        checksum: None,
+        warnings: Vec::new(),
+    };
+    if frame.len() < FRAME_LEN {
+        report
+            .warnings
+            .push(FrameError::TooShort { len: frame.len() }.to_string());
+        return report;
+    }
+
+    let computed = calculate_checksum(&frame[..FRAME_LEN - 1]);
+    let received = frame[FRAME_LEN - 1];
+    let mut fixed = [0; FRAME_LEN];
+    fixed.copy_from_slice(frame);
+    fixed[FRAME_LEN - 1] = computed;
+    match parse_frame(&fixed) {
+        Ok(data) => report.data = Some(data),
+        Err(e) => report.warnings.push(e.to_string()),
+    }
+    if computed != received {
+        report.warnings.push(
+            FrameError::ChecksumMismatch {
+                expected: computed,
+                actual: received,
+            }
+            .to_string(),
+        );
+    }
+    report.checksum = Some(Checksum { computed, received });
+    report
+}
+
+/// Reports on every frame header in `bytes`.
+fn inspect(bytes: &[u8]) -> Vec<FrameReport> {
+    let mut reports = Vec::new();
+    let mut pos = 0;
+    while let Some(start) = find_header(bytes, pos) {
+        let end = (start + FRAME_LEN).min(bytes.len());
+        let mut report = inspect_frame(start, &bytes[start..end]);
+        if start > pos {
+            report.warnings.insert(
+                0,
+                format!("{} bytes skipped before this frame", start - pos),
+            );
+        }
+        reports.push(report);
+        pos = end;
+    }
+    if let Some(last) = reports.last_mut()
+        && pos < bytes.len()
+    {
+        last.warnings
+            .push(format!("{} bytes after this frame", bytes.len() - pos));
+    }
+    reports
+}
+
+fn print_text(reports: &[FrameReport]) {
+    for (i, report) in reports.iter().enumerate() {
+        if i > 0 {
+            println!();
+        }
+        println!("Frame at byte {}: {}", report.offset, report.bytes);
+        if let Some(data) = &report.data {
+            for ((name, unit), (_, value)) in FIELD_LABELS.iter().zip(data.fields()) {
+                println!("  {:<12} {} {}", name, value, unit);
+            }
+        }
+        if let Some(checksum) = &report.checksum {
+            println!(
+                "  {:<12} {:02X} (computed {:02X})",
+                "Checksum", checksum.received, checksum.computed
+            );
+        }
+        for warning in &report.warnings {
+            println!("  Warning: {}", warning);
+        }
+        println!("  {}", if report.valid { "Valid" } else { "Invalid" });
+    }
+}
+
+fn read_input(args: &DecodeArgs) -> Result<Vec<u8>> {
+    if !args.hex.is_empty() {
+        return parse_hex_dump(&args.hex.join(" ")).map_err(|e| anyhow!("Invalid hex: {}", e));
+    }
+    let raw = match &args.input {
+        Some(path) => {
+            fs::read(path).with_context(|| format!("Failed to read '{}'", path.display()))?
+        }
+        None => {
+            let mut raw = Vec::new();
+            io::stdin()
+                .read_to_end(&mut raw)
+                .context("Failed to read standard input")?;
+            raw
+        }
+    };
+    if args.binary {
+        return Ok(raw);
+    }
+    let text = String::from_utf8(raw).context("Input is not text; use --binary for raw bytes")?;
+    parse_hex_dump(&text).map_err(|e| anyhow!("Invalid hex: {}", e))
+}
+
+pub fn run(args: &DecodeArgs) -> Result<()> {
+    let bytes = read_input(args)?;
+    let reports = inspect(&bytes);
+    if reports.is_empty() {
+        bail!(
+            "No frame header ({:02X} {:02X}) in {} bytes",
+            FRAME_HEADER_1,
+            FRAME_HEADER_2,
+            bytes.len()
+        );
+    }
+
+    match args.format {
+        OutputFormat::Text => print_text(&reports),
+        OutputFormat::Json => println!("{}", serde_json::to_string_pretty(&reports)?),
+    }
+
+    let invalid = reports.iter().filter(|r| !r.valid).count();
+    if invalid > 0 {
+        bail!("{} of {} frames are invalid", invalid, reports.len());
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const FRAME: &str = "3C 02 01 90 00 05 00 0A 00 14 00 1E 19 05 32 02 62";
+
+    #[test]
+    fn test_parse_hex_dump() {
+        let bytes = parse_hex_dump(FRAME).unwrap();
+        assert_eq!(bytes.len(), FRAME_LEN);
+        assert_eq!(parse_hex_dump("0x3C,0x2, 0x01"), Ok(vec![0x3c, 0x02, 0x01]));
+        assert_eq!(parse_hex_dump("3c:02"), Ok(vec![0x3c, 0x02]));
+        assert!(parse_hex_dump("3C 0G").is_err());
+    }
+
+    #[test]
+    fn test_inspect() {
+        let mut bytes = vec![0xff, 0x3c];
+        bytes.extend(parse_hex_dump(FRAME).unwrap());
+        let mut corrupted = parse_hex_dump(FRAME).unwrap();
+        corrupted[16] = 0x00;
+        bytes.extend(&corrupted);
+        bytes.extend([0x3c, 0x02, 0x01]);
+
+        let reports = inspect(&bytes);
+        assert_eq!(reports.len(), 3);
+
+        assert_eq!(reports[0].offset, 2);
+        assert!(reports[0].valid);
+        assert_eq!(reports[0].data.as_ref().unwrap().eco2, 400);
+        assert_eq!(reports[0].warnings, ["2 bytes skipped before this frame"]);
+
+        assert!(!reports[1].valid);
+        assert_eq!(reports[1].data.as_ref().unwrap().humidity, 50.2);
+        assert_eq!(
+            reports[1].warnings,
+            ["checksum mismatch: expected 62, got 00"]
+        );
+
+        assert!(!reports[2].valid);
+        assert!(reports[2].checksum.is_none());
+        assert_eq!(reports[2].warnings, ["frame too short: 3 of 17 bytes"]);
+    }
+}
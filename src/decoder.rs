@@ -0,0 +1,293 @@
+//! Incremental frame decoding for a raw M701 byte stream.
+
+use std::collections::VecDeque;
+
+use crate::protocol::{
+    FRAME_HEADER_1, FRAME_HEADER_2, FRAME_LEN, FrameError, SensorData, parse_frame,
+};
+
+/// Something the decoder found while scanning the stream.
+#[derive(Debug, Clone, PartialEq)]
+pub enum DecodeEvent {
+    /// A complete, valid frame.
+    Frame(SensorData),
+    /// Bytes that were dropped while searching for a frame header.
+    GarbageSkipped { bytes: usize },
+    /// A header candidate that turned out not to be a valid frame.
+    ///
+    /// Frames with a valid checksum but out-of-range fields are dropped as a
+    /// whole; for every other error the decoder drops only the first byte and
+    /// resynchronises from the next one.
+    Rejected(FrameError),
+}
+
+/// Running totals of the events produced by a [`FrameDecoder`].
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct DecodeStats {
+    pub frames: u64,
+    pub garbage_bytes: u64,
+    pub bad_headers: u64,
+    pub checksum_mismatches: u64,
+    pub out_of_range: u64,
+}
+
+impl DecodeStats {
+    /// Adds `event` to the totals.
+    pub fn record(&mut self, event: &DecodeEvent) {
+        match event {
+            DecodeEvent::Frame(_) => self.frames += 1,
+            DecodeEvent::GarbageSkipped { bytes } => self.garbage_bytes += *bytes as u64,
+            DecodeEvent::Rejected(FrameError::BadHeader(..)) => self.bad_headers += 1,
+            DecodeEvent::Rejected(FrameError::ChecksumMismatch { .. }) => {
+                self.checksum_mismatches += 1
+            }
+            DecodeEvent::Rejected(FrameError::OutOfRange { .. }) => self.out_of_range += 1,
+            // The decoder waits for a full frame, so it never reports this
+            DecodeEvent::Rejected(FrameError::TooShort { .. }) => {}
+        }
+    }
+}
+
+/// Stateful decoder that turns arbitrary byte chunks into frames.
+///
+/// Bytes are buffered in a ring buffer until a whole frame is available, so
+/// frames split across reads are reassembled and every input byte is only
+/// scanned a bounded number of times, however noisy the line is.
+///
+/// ```
+/// use sensor_reader::{DecodeEvent, FrameDecoder};
+///
+/// let mut decoder = FrameDecoder::new();
+/// for event in decoder.feed(&[0xFF, 0x3C]) {
+///     assert_eq!(event, DecodeEvent::GarbageSkipped { bytes: 1 });
+/// }
+/// ```
+#[derive(Debug, Default)]
+pub struct FrameDecoder {
+    buffer: VecDeque<u8>,
+}
+
+impl FrameDecoder {
+    pub fn new() -> Self {
+        Self {
+            buffer: VecDeque::with_capacity(FRAME_LEN * 4),
+        }
+    }
+
+    /// Appends `bytes` to the internal buffer without decoding anything.
+    pub fn push(&mut self, bytes: &[u8]) {
+        self.buffer.extend(bytes);
+    }
+
+    /// Appends `bytes` and returns an iterator over the resulting events.
+    ///
+    /// Events that are not consumed stay available through
+    /// [`next_event`](Self::next_event).
+    pub fn feed(&mut self, bytes: &[u8]) -> Events<'_> {
+        self.push(bytes);
+        Events { decoder: self }
+    }
+
+    /// Number of bytes buffered while waiting for the rest of a frame.
+    pub fn buffered(&self) -> usize {
+        self.buffer.len()
+    }
+
+    /// Drops all buffered bytes.
+    pub fn clear(&mut self) {
+        self.buffer.clear();
+    }
+
+    /// Decodes the next event from the buffered bytes, or returns `None` if
+    /// more input is needed.
+    pub fn next_event(&mut self) -> Option<DecodeEvent> {
+        // Drop everything in front of the first header candidate
+        match self.buffer.iter().position(|&b| b == FRAME_HEADER_1) {
+            Some(0) => {}
+            Some(start) => {
+                self.buffer.drain(..start);
+                return Some(DecodeEvent::GarbageSkipped { bytes: start });
+            }
+            None if self.buffer.is_empty() => return None,
+            None => {
+                let bytes = self.buffer.len();
+                self.buffer.clear();
+                return Some(DecodeEvent::GarbageSkipped { bytes });
+            }
+        }
+
+        // Reject a false header as soon as its second byte arrives
+        match self.buffer.get(1) {
+            None => return None,
+            Some(&second) if second != FRAME_HEADER_2 => {
+                self.buffer.pop_front();
+                return Some(DecodeEvent::Rejected(FrameError::BadHeader(
+                    FRAME_HEADER_1,
+                    second,
+                )));
+            }
+            Some(_) => {}
+        }
+
+        if self.buffer.len() < FRAME_LEN {
+            return None; // Wait for more data
+        }
+
+        let mut frame = [0u8; FRAME_LEN];
+        for (dst, src) in frame.iter_mut().zip(self.buffer.iter()) {
+            *dst = *src;
+        }
+
+        match parse_frame(&frame) {
+            Ok(data) => {
+                self.buffer.drain(..FRAME_LEN);
+                Some(DecodeEvent::Frame(data))
+            }
+            Err(e @ FrameError::OutOfRange { .. }) => {
+                // The checksum matched, so this really was a whole frame
+                self.buffer.drain(..FRAME_LEN);
+                Some(DecodeEvent::Rejected(e))
+            }
+            Err(e) => {
+                // The real header may start inside the rejected bytes
+                self.buffer.pop_front();
+                Some(DecodeEvent::Rejected(e))
+            }
+        }
+    }
+}
+
+/// Iterator returned by [`FrameDecoder::feed`].
+#[derive(Debug)]
+pub struct Events<'a> {
+    decoder: &'a mut FrameDecoder,
+}
+
+impl Iterator for Events<'_> {
+    type Item = DecodeEvent;
+
+    fn next(&mut self) -> Option<DecodeEvent> {
+        self.decoder.next_event()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::protocol::calculate_checksum;
+
+    fn frame(eco2: u16) -> Vec<u8> {
+        let [hi, lo] = eco2.to_be_bytes();
+        let mut data = vec![
+            0x3C, 0x02, hi, lo, 0x00, 0x05, 0x00, 0x0A, 0x00, 0x14, 0x00, 0x1E, 25, 5, 50, 2,
+        ];
+        data.push(calculate_checksum(&data));
+        data
+    }
+
+    fn decode_all(decoder: &mut FrameDecoder, bytes: &[u8]) -> Vec<DecodeEvent> {
+        decoder.feed(bytes).collect()
+    }
+
+    fn eco2_values(events: &[DecodeEvent]) -> Vec<u16> {
+        events
+            .iter()
+            .filter_map(|e| match e {
+                DecodeEvent::Frame(data) => Some(data.eco2),
+                _ => None,
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_split_frame() {
+        let mut decoder = FrameDecoder::new();
+        let data = frame(400);
+
+        assert!(decode_all(&mut decoder, &data[..1]).is_empty());
+        assert!(decode_all(&mut decoder, &data[1..9]).is_empty());
+        let events = decode_all(&mut decoder, &data[9..]);
+        assert_eq!(eco2_values(&events), vec![400]);
+        assert_eq!(decoder.buffered(), 0);
+    }
+
+    #[test]
+    fn test_back_to_back_frames() {
+        let mut decoder = FrameDecoder::new();
+        let mut data = frame(400);
+        data.extend(frame(401));
+        data.extend(frame(402));
+
+        let events = decode_all(&mut decoder, &data);
+        assert_eq!(events.len(), 3);
+        assert_eq!(eco2_values(&events), vec![400, 401, 402]);
+    }
+
+    #[test]
+    fn test_garbage_and_false_header() {
+        let mut decoder = FrameDecoder::new();
+        let mut data = vec![0x00, 0xFF, 0x3C, 0x3C, 0x01];
+        data.extend(frame(500));
+
+        let events = decode_all(&mut decoder, &data);
+        assert_eq!(
+            events,
+            vec![
+                DecodeEvent::GarbageSkipped { bytes: 2 },
+                DecodeEvent::Rejected(FrameError::BadHeader(0x3C, 0x3C)),
+                DecodeEvent::Rejected(FrameError::BadHeader(0x3C, 0x01)),
+                DecodeEvent::GarbageSkipped { bytes: 1 },
+                DecodeEvent::Frame(parse_frame(&frame(500)).unwrap()),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_corrupted_checksum_resyncs() {
+        let mut decoder = FrameDecoder::new();
+        let mut bad = frame(400);
+        bad[16] = bad[16].wrapping_add(1);
+        let mut data = bad.clone();
+        data.extend(frame(401));
+
+        let events = decode_all(&mut decoder, &data);
+        assert_eq!(
+            events[0],
+            DecodeEvent::Rejected(FrameError::ChecksumMismatch {
+                expected: bad[16].wrapping_sub(1),
+                actual: bad[16],
+            })
+        );
+        assert_eq!(eco2_values(&events), vec![401]);
+        assert_eq!(decoder.buffered(), 0);
+    }
+
+    #[test]
+    fn test_stats() {
+        let mut decoder = FrameDecoder::new();
+        let mut bad = frame(400);
+        bad[16] = bad[16].wrapping_add(1);
+        let mut data = vec![0x00, 0x3C, 0x00];
+        data.extend(bad);
+        data.extend(frame(401));
+
+        let mut stats = DecodeStats::default();
+        for event in decoder.feed(&data) {
+            stats.record(&event);
+        }
+        assert_eq!(stats.frames, 1);
+        assert_eq!(stats.bad_headers, 1);
+        assert_eq!(stats.checksum_mismatches, 1);
+        // Leading 0x00, the 0x00 after the false header and the 16 bytes
+        // left over from the corrupted frame
+        assert_eq!(stats.garbage_bytes, 18);
+    }
+
+    #[test]
+    fn test_pure_noise_is_discarded() {
+        let mut decoder = FrameDecoder::new();
+        let events = decode_all(&mut decoder, &[0x11; 100]);
+        assert_eq!(events, vec![DecodeEvent::GarbageSkipped { bytes: 100 }]);
+        assert_eq!(decoder.buffered(), 0);
+    }
+}
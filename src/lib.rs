@@ -0,0 +1,24 @@
+//! Decoding support for the M701 air quality sensor.
+//!
+//! This crate contains the protocol layer only, so it can be used without the
+//! serial port and HTTP dependencies of the `sensor_reader` binary. Build it
+//! with `default-features = false` to get just the decoder.
+
+pub mod aggregate;
+pub mod aqi;
+pub mod change;
+pub mod comfort;
+pub mod decoder;
+pub mod protocol;
+pub mod reading;
+
+pub use aggregate::{Aggregator, FieldStats, Window};
+pub use aqi::{AQI_FIELDS, Aqi, AqiStandard, AqiTracker, Pollutant};
+pub use change::{ChangeFilter, Deadband};
+pub use comfort::{COMFORT_FIELDS, Comfort, ComfortMetric};
+pub use decoder::{DecodeEvent, DecodeStats, Events, FrameDecoder};
+pub use protocol::{
+    FIELD_LABELS, FIELD_NAMES, FRAME_HEADER_1, FRAME_HEADER_2, FRAME_LEN, FrameError, SensorData,
+    calculate_checksum, encode_frame, parse_frame,
+};
+pub use reading::{DeviceInfo, Reading, SCHEMA_VERSION, Stamper};
@@ -1,180 +1,421 @@
-use anyhow::{Context, Result};
-use clap::Parser;
+mod capture;
+mod config;
+mod decode;
+mod metrics;
+mod monitor;
+mod outbox;
+mod query;
+mod queue;
+mod retry;
+mod serial;
+#[cfg(unix)]
+mod simulate;
+mod sinks;
+
+use anyhow::{Context, Result, anyhow};
+use capture::{CaptureReader, CaptureWriter};
+use chrono::{DateTime, Utc};
+use clap::{Parser, Subcommand};
+use config::{Config, OutboxSection, Overrides};
 use dotenvy::dotenv;
-use serde::Serialize;
-use std::env;
+use log::{error, info, warn};
+use metrics::Metrics;
+use monitor::{LogBuffer, MonitorArgs};
+use outbox::{Outbox, OutboxConfig};
+use retry::RetryPolicy;
+use sensor_reader::{
+    AqiTracker, Comfort, ComfortMetric, DecodeEvent, DecodeStats, DeviceInfo, FrameDecoder,
+    FrameError, Reading, Stamper,
+};
+use signal_hook::consts::{SIGINT, SIGTERM};
+use sinks::file::FileSink;
+use sinks::http::{BatchConfig, DeadLetter, HttpSink, Uploader};
+use sinks::influx::InfluxSink;
+use sinks::mqtt::MqttSink;
+use sinks::sqlite::History;
+use sinks::{SinkHandle, Sinks};
 use std::io::{self, Read};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread;
 use std::time::Duration;
 
 #[derive(Parser, Debug)]
 #[command(author, version, about, long_about = None)]
-struct Args {
-    /// Name of the serial port to use (e.g., /dev/ttyUSB0 or COM3)
-    #[arg(short, long)]
-    port: String,
+struct Cli {
+    #[command(flatten)]
+    overrides: Overrides,
 
-    /// Baud rate (default: 9600)
-    #[arg(short, long, default_value_t = 9600)]
-    baud_rate: u32,
-
-    /// Server URL to send data to
-    #[arg(long, default_value = "https://localhost:3000/api/readings")]
-    server_url: String,
+    #[command(subcommand)]
+    command: Option<Command>,
 }
 
-#[derive(Debug, Clone, Serialize)]
-struct SensorData {
-    eco2: u16,
-    ech2o: u16,
-    tvoc: u16,
-    pm2_5: u16,
-    pm10: u16,
-    temperature: f32,
-    humidity: f32,
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// Read the sensor and deliver readings to the sinks (the default)
+    Run,
+    /// Like run, with a live dashboard of the readings, counters and sink
+    /// status in place of the log
+    Monitor(MonitorArgs),
+    /// Feed a file recorded with --capture through the decoder and sinks
+    /// instead of reading the serial port
+    Replay {
+        /// Capture file to read
+        file: PathBuf,
+        /// Wait between chunks as long as the sensor did, instead of
+        /// replaying as fast as possible
+        #[arg(long)]
+        realtime: bool,
+    },
+    /// Pretend to be an M701 on a pseudo-terminal, for testing without
+    /// hardware
+    #[cfg(unix)]
+    Simulate(simulate::SimulateArgs),
+    /// Explain frame bytes given in hex, field by field
+    Decode(decode::DecodeArgs),
+    /// List the serial ports with their USB vendor/product IDs, serial
+    /// numbers and product names
+    ListPorts,
+    /// Export readings from the SQLite history
+    Query(query::QueryArgs),
+    /// Inspect the configuration
+    Config {
+        #[command(subcommand)]
+        action: ConfigAction,
+    },
 }
 
-const FRAME_HEADER_1: u8 = 0x3C;
-const FRAME_HEADER_2: u8 = 0x02;
-const FRAME_LEN: usize = 17;
+#[derive(Subcommand, Debug)]
+enum ConfigAction {
+    /// Validate the configuration and print the effective values, with
+    /// secrets redacted
+    Check,
+}
 
-fn calculate_checksum(data: &[u8]) -> u8 {
-    let mut sum: u16 = 0;
-    for &b in data {
-        sum = sum.wrapping_add(b as u16);
+fn main() -> Result<()> {
+    dotenv().ok(); // Load .env file
+    let cli = Cli::parse();
+    let config = Config::load(&cli.overrides)?;
+
+    let logs = LogBuffer::default();
+    let mut logger = env_logger::Builder::new();
+    logger
+        .filter_level(config.logging.level.parse()?)
+        .format_target(false);
+    if let Some(Command::Monitor(_)) = &cli.command {
+        logger.target(env_logger::Target::Pipe(Box::new(logs.clone())));
+    }
+    logger.init();
+
+    match cli.command.unwrap_or(Command::Run) {
+        Command::Run => run(&config),
+        Command::Monitor(args) => monitor(&config, &args, logs),
+        Command::Replay { file, realtime } => replay(&config, &file, realtime),
+        #[cfg(unix)]
+        Command::Simulate(args) => simulate::run(&args),
+        Command::Decode(args) => decode::run(&args),
+        Command::ListPorts => serial::list_ports(),
+        Command::Query(args) => query::run(&config, &args),
+        Command::Config {
+            action: ConfigAction::Check,
+        } => {
+            print!("{}", config.to_redacted_toml()?);
+            Ok(())
+        }
     }
-    (sum & 0xFF) as u8
 }
 
-fn parse_frame(buffer: &[u8]) -> Option<SensorData> {
-    if buffer.len() < FRAME_LEN {
-        return None;
-    }
+/// Opens the outbox in `section`, if it has a directory.
+fn open_outbox(section: &OutboxSection) -> Result<Option<Outbox<Reading>>> {
+    let Some(dir) = &section.dir else {
+        return Ok(None);
+    };
+    Ok(Some(Outbox::open(
+        dir,
+        OutboxConfig {
+            max_bytes: section.max_bytes,
+            max_age: Duration::from_secs(section.max_age_hours * 3600),
+            ..OutboxConfig::default()
+        },
+    )?))
+}
 
-    // Verify headers
-    if buffer[0] != FRAME_HEADER_1 || buffer[1] != FRAME_HEADER_2 {
-        return None;
-    }
+fn spawn_http_sink(config: &Config, stop: &Arc<AtomicBool>) -> Result<SinkHandle> {
+    let http = &config.sinks.http;
+    let uploader = Uploader::new(
+        HttpSink::new(http.url.clone(), http.api_key.clone(), http.batch.size > 1)?,
+        RetryPolicy {
+            max_attempts: http.retry.attempts,
+            initial_delay: Duration::from_millis(http.retry.initial_delay_ms),
+            max_delay: Duration::from_millis(http.retry.max_delay_ms),
+        },
+        BatchConfig {
+            max_size: http.batch.size,
+            max_latency: Duration::from_millis(http.batch.max_latency_ms),
+            on_reject: http.batch.reject,
+        },
+        http.dead_letter
+            .as_deref()
+            .map(DeadLetter::open)
+            .transpose()?,
+        open_outbox(&http.outbox)?,
+    )
+    .stop_retrying_on(Arc::clone(stop));
+    Ok(SinkHandle::spawn(
+        "http",
+        http.queue_size,
+        http.overflow,
+        move |queue, stats| sinks::http::run(queue, stats, uploader),
+    ))
+}
 
-    // Verify checksum
-    let calculated_sum = calculate_checksum(&buffer[0..16]);
-    if calculated_sum != buffer[16] {
-        eprintln!(
-            "Checksum mismatch: expected {:02X}, got {:02X}",
-            calculated_sum, buffer[16]
-        );
-        return None;
-    }
+fn spawn_influx_sink(config: &Config, stop: &Arc<AtomicBool>) -> Result<SinkHandle> {
+    let influx = &config.sinks.influx;
+    let uploader = Uploader::new(
+        InfluxSink::new(influx)?,
+        RetryPolicy {
+            max_attempts: influx.retry.attempts,
+            initial_delay: Duration::from_millis(influx.retry.initial_delay_ms),
+            max_delay: Duration::from_millis(influx.retry.max_delay_ms),
+        },
+        BatchConfig {
+            max_size: influx.batch.size,
+            max_latency: Duration::from_millis(influx.batch.max_latency_ms),
+            on_reject: influx.batch.reject,
+        },
+        influx
+            .dead_letter
+            .as_deref()
+            .map(DeadLetter::open)
+            .transpose()?,
+        open_outbox(&influx.outbox)?,
+    )
+    .stop_retrying_on(Arc::clone(stop));
+    Ok(SinkHandle::spawn(
+        "influx",
+        influx.queue_size,
+        influx.overflow,
+        move |queue, stats| sinks::http::run(queue, stats, uploader),
+    ))
+}
 
-    let eco2 = u16::from_be_bytes([buffer[2], buffer[3]]);
-    let ech2o = u16::from_be_bytes([buffer[4], buffer[5]]);
-    let tvoc = u16::from_be_bytes([buffer[6], buffer[7]]);
-    let pm2_5 = u16::from_be_bytes([buffer[8], buffer[9]]);
-    let pm10 = u16::from_be_bytes([buffer[10], buffer[11]]);
+fn spawn_file_sink(config: &Config) -> Result<SinkHandle> {
+    let file = &config.sinks.file;
+    let sink = FileSink::open(file, &config.comfort.metrics, &config.aqi.standards)?;
+    Ok(SinkHandle::spawn(
+        "file",
+        file.queue_size,
+        file.overflow,
+        move |queue, stats| sinks::file::run(queue, stats, sink),
+    ))
+}
 
-    let temp_int = buffer[12];
-    let temp_dec = buffer[13];
-    let temperature = temp_int as f32 + (temp_dec as f32 / 10.0);
+fn spawn_sqlite_sink(config: &Config) -> Result<SinkHandle> {
+    let sqlite = &config.sinks.sqlite;
+    let history = History::open(&sqlite.path)?;
+    let max_age = sqlite
+        .max_age_days
+        .map(|days| Duration::from_secs(days * 24 * 3600));
+    Ok(SinkHandle::spawn(
+        "sqlite",
+        sqlite.queue_size,
+        sqlite.overflow,
+        move |queue, stats| sinks::sqlite::run(queue, stats, history, max_age),
+    ))
+}
 
-    let hum_int = buffer[14];
-    let hum_dec = buffer[15];
-    let humidity = hum_int as f32 + (hum_dec as f32 / 10.0);
+fn spawn_mqtt_sink(config: &Config, device: &DeviceInfo) -> Result<SinkHandle> {
+    let mqtt = &config.sinks.mqtt;
+    let sink = MqttSink::connect(mqtt, device, &config.comfort.metrics, &config.aqi.standards)?;
+    Ok(SinkHandle::spawn(
+        "mqtt",
+        mqtt.queue_size,
+        mqtt.overflow,
+        move |queue, stats| sinks::mqtt::run(queue, stats, sink),
+    ))
+}
 
-    Some(SensorData {
-        eco2,
-        ech2o,
-        tvoc,
-        pm2_5,
-        pm10,
-        temperature,
-        humidity,
-    })
+fn start_sinks(config: &Config, device: &DeviceInfo, stop: &Arc<AtomicBool>) -> Result<Sinks> {
+    let mut sinks = Sinks::default();
+    if config.sinks.http.enabled {
+        sinks.add(
+            spawn_http_sink(config, stop)?
+                .aggregate(&config.sinks.http.aggregate)
+                .change(&config.sinks.http.change),
+        );
+    }
+    if config.sinks.mqtt.enabled {
+        sinks.add(
+            spawn_mqtt_sink(config, device)?
+                .aggregate(&config.sinks.mqtt.aggregate)
+                .change(&config.sinks.mqtt.change),
+        );
+    }
+    if config.sinks.influx.enabled {
+        sinks.add(
+            spawn_influx_sink(config, stop)?
+                .aggregate(&config.sinks.influx.aggregate)
+                .change(&config.sinks.influx.change),
+        );
+    }
+    if config.sinks.file.enabled {
+        sinks.add(
+            spawn_file_sink(config)?
+                .aggregate(&config.sinks.file.aggregate)
+                .change(&config.sinks.file.change),
+        );
+    }
+    if config.sinks.sqlite.enabled {
+        sinks.add(
+            spawn_sqlite_sink(config)?
+                .aggregate(&config.sinks.sqlite.aggregate)
+                .change(&config.sinks.sqlite.change),
+        );
+    }
+    Ok(sinks)
 }
 
-fn main() -> Result<()> {
-    dotenv().ok(); // Load .env file
-    let args = Args::parse();
-    let client = reqwest::blocking::Client::builder()
-        .use_rustls_tls()
-        .build()?;
+/// Decodes bytes from the sensor and hands the readings to the sinks.
+struct Pipeline {
+    decoder: FrameDecoder,
+    stamper: Stamper,
+    stats: DecodeStats,
+    metrics: Arc<Metrics>,
+    comfort: Vec<ComfortMetric>,
+    aqi: Vec<AqiTracker>,
+    sinks: Sinks,
+}
 
-    println!("Opening port {} at {} baud...", args.port, args.baud_rate);
+impl Pipeline {
+    /// Starts the sinks; those that retry give up once `stop` is set.
+    fn start(config: &Config, stop: &Arc<AtomicBool>) -> Result<Self> {
+        let device = DeviceInfo {
+            id: config.device.id.clone(),
+            location: config.device.location.clone(),
+            tags: config.device.tags.clone(),
+        };
+        let sinks = start_sinks(config, &device, stop)?;
+        let metrics = Arc::new(Metrics::new(&device.id, sinks.monitors()));
+        Ok(Self {
+            decoder: FrameDecoder::new(),
+            stamper: Stamper::new(device),
+            stats: DecodeStats::default(),
+            metrics,
+            comfort: config.comfort.metrics.clone(),
+            aqi: config
+                .aqi
+                .standards
+                .iter()
+                .map(|&s| AqiTracker::new(s))
+                .collect(),
+            sinks,
+        })
+    }
 
-    let mut port = serialport::new(&args.port, args.baud_rate)
-        .timeout(Duration::from_millis(1000))
-        .open()
-        .with_context(|| format!("Failed to open port '{}'", args.port))?;
+    /// Decodes `bytes`, received at `at`.
+    fn feed(&mut self, bytes: &[u8], at: DateTime<Utc>) {
+        for event in self.decoder.feed(bytes) {
+            self.stats.record(&event);
+            match event {
+                DecodeEvent::Frame(data) => {
+                    let mut reading = self.stamper.stamp_at(data, at);
+                    reading.comfort = Comfort::compute(&reading.data, &self.comfort);
+                    reading.aqi = self
+                        .aqi
+                        .iter_mut()
+                        .filter_map(|t| t.update(&reading))
+                        .collect();
+                    info!("Received #{}: {:?}", reading.seq, reading.data);
+                    self.metrics.record_reading(&reading);
+                    self.sinks.send(&reading);
+                }
+                DecodeEvent::Rejected(e @ FrameError::ChecksumMismatch { .. }) => {
+                    warn!("{} ({} so far)", e, self.stats.checksum_mismatches);
+                }
+                DecodeEvent::Rejected(e @ FrameError::OutOfRange { .. }) => {
+                    warn!("Dropping frame, {} ({} so far)", e, self.stats.out_of_range);
+                }
+                // Stray bytes and false headers are expected while resyncing
+                DecodeEvent::Rejected(_) | DecodeEvent::GarbageSkipped { .. } => {}
+            }
+        }
+        self.metrics.record_decode(self.stats);
+    }
 
-    println!("Port opened. Waiting for data...");
+    /// Logs the decode totals and flushes the sinks.
+    fn finish(self) {
+        let stats = self.stats;
+        info!(
+            "Decoded {} frames; discarded {} garbage bytes, {} bad headers, {} checksum mismatches, {} out-of-range frames",
+            stats.frames,
+            stats.garbage_bytes,
+            stats.bad_headers,
+            stats.checksum_mismatches,
+            stats.out_of_range
+        );
+        self.sinks.shutdown();
+    }
+}
+
+/// Reads the serial port into `pipeline` until the port fails for good or
+/// `stop` is set.
+fn read_serial(config: &Config, pipeline: &mut Pipeline, stop: &AtomicBool) -> Result<()> {
+    let Some(mut port) = serial::connect(&config.serial, stop)? else {
+        return Ok(());
+    };
+    let mut capture = match &config.serial.capture {
+        Some(path) => {
+            let name = port.name().unwrap_or_default();
+            info!("Capturing raw bytes to {}", path.display());
+            Some(CaptureWriter::open(path, &name, config.serial.baud_rate)?)
+        }
+        None => None,
+    };
+    info!("Waiting for data...");
 
     let mut serial_buf: Vec<u8> = vec![0; 1000];
-    let mut buffer: Vec<u8> = Vec::new();
+    let mut reconnects = 0u64;
 
-    loop {
+    while !stop.load(Ordering::Relaxed) {
         match port.read(serial_buf.as_mut_slice()) {
             Ok(t) => {
-                buffer.extend_from_slice(&serial_buf[..t]);
-
-                // Process buffer
-                while buffer.len() >= FRAME_LEN {
-                    // Look for header
-                    if let Some(start_idx) = buffer.iter().position(|&x| x == FRAME_HEADER_1) {
-                        // Remove garbage before header
-                        if start_idx > 0 {
-                            buffer.drain(0..start_idx);
-                        }
-
-                        // Check if we have enough data for a full frame
-                        if buffer.len() < FRAME_LEN {
-                            break; // Wait for more data
-                        }
-
-                        // Check second header byte
-                        if buffer[1] != FRAME_HEADER_2 {
-                            // Invalid header sequence, remove the first byte and try again
-                            buffer.remove(0);
-                            continue;
-                        }
-
-                        // Try to parse the frame
-                        let frame_bytes = &buffer[0..FRAME_LEN];
-                        if let Some(data) = parse_frame(frame_bytes) {
-                            println!("Received: {:?}", data);
-
-                            // Send to server (include `x-api-key` if provided in env)
-                            let mut req = client.post(&args.server_url).json(&data);
-                            if let Ok(api_key) = env::var("SENSOR_API_KEY")
-                                && !api_key.is_empty()
-                            {
-                                req = req.header("x-api-key", api_key);
-                            }
-
-                            match req.send() {
-                                Ok(resp) => {
-                                    if resp.status().is_success() {
-                                        println!("Sent to server");
-                                    } else {
-                                        eprintln!("Server returned error: {}", resp.status());
-                                    }
-                                }
-                                Err(e) => eprintln!("Failed to send to server: {}", e),
-                            }
-
-                            // Remove the processed frame
-                            buffer.drain(0..FRAME_LEN);
-                        } else {
-                            buffer.remove(0);
-                        }
-                    } else {
-                        // No header found in the entire buffer, clear it
-                        buffer.clear();
-                    }
+                let now = Utc::now();
+                if let Some(writer) = &mut capture
+                    && let Err(e) = writer.record(now, &serial_buf[..t])
+                {
+                    warn!("Failed to write capture, stopping it: {:#}", e);
+                    capture = None;
                 }
+                pipeline.feed(&serial_buf[..t], now);
             }
-            Err(ref e) if e.kind() == io::ErrorKind::TimedOut => {
+            // Interrupted by a signal, which may have set `stop`
+            Err(ref e)
+                if matches!(
+                    e.kind(),
+                    io::ErrorKind::TimedOut | io::ErrorKind::Interrupted
+                ) =>
+            {
                 continue;
             }
+            Err(e) if config.serial.reconnect.enabled => {
+                warn!("Serial port disconnected: {}", e);
+                // A partial frame from before the disconnect cannot be completed
+                pipeline.decoder.clear();
+                drop(port);
+                port = match serial::connect(&config.serial, stop)? {
+                    Some(port) => port,
+                    None => break,
+                };
+                reconnects += 1;
+                pipeline.metrics.record_reconnect();
+                if let Some(writer) = &mut capture {
+                    let _ = writer.comment("reconnected");
+                }
+                info!("Reconnected ({} reconnects so far)", reconnects);
+            }
             Err(e) => {
-                eprintln!("Error reading serial port: {:?}", e);
+                error!("Error reading serial port: {:?}", e);
                 break;
             }
         }
@@ -183,44 +424,140 @@ fn main() -> Result<()> {
     Ok(())
 }
 
+fn run(config: &Config) -> Result<()> {
+    serial::check_port(&config.serial)?;
+    // Ctrl-C or a service manager stopping us ends the read loop, so that the
+    // sinks still get to flush what they hold. A second signal exits at once.
+    let stop = Arc::new(AtomicBool::new(false));
+    for signal in [SIGINT, SIGTERM] {
+        signal_hook::flag::register_conditional_shutdown(signal, 1, Arc::clone(&stop))
+            .and_then(|_| signal_hook::flag::register(signal, Arc::clone(&stop)))
+            .context("Failed to install signal handler")?;
+    }
+    let mut pipeline = Pipeline::start(config, &stop)?;
+    if config.metrics.enabled {
+        metrics::serve(&config.metrics.listen, Arc::clone(&pipeline.metrics))?;
+    }
+
+    // Flush what the sinks hold even if the port failed for good
+    let result = read_serial(config, &mut pipeline, &stop);
+    pipeline.finish();
+    result
+}
+
+/// Like [`run`], with the dashboard in place of the log.
+fn monitor(config: &Config, args: &MonitorArgs, logs: LogBuffer) -> Result<()> {
+    serial::check_port(&config.serial)?;
+    let stop = Arc::new(AtomicBool::new(false));
+    let mut pipeline = Pipeline::start(config, &stop)?;
+    let metrics = Arc::clone(&pipeline.metrics);
+    if config.metrics.enabled {
+        metrics::serve(&config.metrics.listen, Arc::clone(&metrics))?;
+    }
+
+    let reader = {
+        let config = config.clone();
+        let stop = Arc::clone(&stop);
+        thread::spawn(move || {
+            let result = read_serial(&config, &mut pipeline, &stop);
+            (pipeline, result)
+        })
+    };
+    let title = match &config.device.location {
+        Some(location) => format!("{} ({})", config.device.id, location),
+        None => config.device.id.clone(),
+    };
+    let shown = monitor::show(title, args, &metrics, &logs, || reader.is_finished());
+    logs.passthrough();
+
+    // The reader stops within a read timeout, or between reconnect attempts
+    // if it is waiting for the port
+    stop.store(true, Ordering::Relaxed);
+    let (pipeline, result) = reader
+        .join()
+        .map_err(|_| anyhow!("Serial reader panicked"))?;
+    pipeline.finish();
+    shown.and(result)
+}
+
+/// Feeds a capture file through the decoder and sinks.
+fn replay(config: &Config, file: &Path, realtime: bool) -> Result<()> {
+    let mut chunks = CaptureReader::open(file)?.peekable();
+    let started = match chunks.peek() {
+        Some(Ok(chunk)) => chunk.at,
+        _ => Utc::now(),
+    };
+    let mut pipeline = Pipeline::start(config, &Arc::new(AtomicBool::new(false)))?;
+    info!("Replaying {}", file.display());
+
+    let mut previous = started;
+    let mut result = Ok(());
+    for chunk in chunks {
+        let chunk =
+            match chunk.with_context(|| format!("Invalid capture file '{}'", file.display())) {
+                Ok(chunk) => chunk,
+                Err(e) => {
+                    result = Err(e);
+                    break;
+                }
+            };
+        // The gap is negative if the clock stepped back while capturing
+        if realtime && let Ok(gap) = (chunk.at - previous).to_std() {
+            thread::sleep(gap);
+        }
+        previous = chunk.at;
+        pipeline.feed(&chunk.bytes, chunk.at);
+    }
+
+    // Flush what was replayed even if the rest of the file is unreadable
+    pipeline.finish();
+    result
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
+    use sensor_reader::{SensorData, encode_frame};
+    use std::fs;
 
     #[test]
-    fn test_calculate_checksum() {
-        let data = vec![
-            0x3C, 0x02, 0x00, 0x01, 0x00, 0x01, 0x00, 0x01, 0x00, 0x01, 0x00, 0x01, 10, 5, 20, 5,
-        ];
-        let checksum = calculate_checksum(&data);
-        assert_eq!(checksum, 107);
-    }
-
-    #[test]
-    fn test_parse_frame_valid() {
-        let mut data = vec![
-            0x3C, 0x02, // Header
-            0x01, 0x90, // eCO2 = 400
-            0x00, 0x05, // eCH2O = 5
-            0x00, 0x0A, // TVOC = 10
-            0x00, 0x14, // PM2.5 = 20
-            0x00, 0x1E, // PM10 = 30
-            25, 5, // Temp = 25.5
-            50, 2, // Hum = 50.2
-        ];
-        let checksum = calculate_checksum(&data);
-        data.push(checksum);
-
-        let result = parse_frame(&data);
-        assert!(result.is_some());
-        let sensor_data = result.unwrap();
-
-        assert_eq!(sensor_data.eco2, 400);
-        assert_eq!(sensor_data.ech2o, 5);
-        assert_eq!(sensor_data.tvoc, 10);
-        assert_eq!(sensor_data.pm2_5, 20);
-        assert_eq!(sensor_data.pm10, 30);
-        assert_eq!(sensor_data.temperature, 25.5);
-        assert_eq!(sensor_data.humidity, 50.2);
+    fn test_replay_flushes_before_a_bad_line() {
+        let dir = tempfile::tempdir().unwrap();
+        let data = SensorData {
+            eco2: 400,
+            ech2o: 5,
+            tvoc: 10,
+            pm2_5: 20,
+            pm10: 30,
+            temperature: 20.0,
+            humidity: 50.0,
+        };
+        let hex = capture::encode_hex(&encode_frame(&data));
+        let capture = dir.path().join("capture.txt");
+        fs::write(
+            &capture,
+            format!("2024-05-01T12:00:01Z {hex}\n2024-05-01T12:00:02Z {hex}\n2024-05-01T12:00:0\n"),
+        )
+        .unwrap();
+        let config = Config::parse(&format!(
+            "[sinks.http]\nenabled = false\n\
+             [sinks.file]\nenabled = true\ndir = {:?}\nformat = \"jsonl\"\n\
+             [sinks.file.aggregate]\nwindow_secs = 60\n",
+            dir.path().join("readings")
+        ))
+        .unwrap();
+
+        let err = replay(&config, &capture, false).unwrap_err();
+        assert!(format!("{:#}", err).contains("line 3"), "{:#}", err);
+
+        // The open window was summarized and written out
+        let text =
+            fs::read_to_string(dir.path().join("readings/readings-2024-05-01.jsonl")).unwrap();
+        let readings: Vec<Reading> = text
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect();
+        assert_eq!(readings.len(), 1);
+        assert_eq!(readings[0].window.as_ref().unwrap().samples, 2);
     }
 }
@@ -0,0 +1,305 @@
+//! Prometheus exporter for the latest reading and the reader's own counters.
+
+use anyhow::{Result, anyhow};
+use log::{info, warn};
+use sensor_reader::{DecodeStats, Reading};
+use std::fmt::Write;
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::thread;
+use std::time::{Duration, Instant};
+use tiny_http::{Header, Response, Server};
+
+use crate::sinks::SinkMonitor;
+
+/// Gauge name and help text for every [`SensorData`](sensor_reader::SensorData)
+/// field, in [`FIELD_NAMES`](sensor_reader::FIELD_NAMES) order.
+const FIELD_GAUGES: [(&str, &str); 7] = [
+    ("sensor_reader_eco2_ppm", "Equivalent CO2 in ppm."),
+    (
+        "sensor_reader_ech2o_micrograms_per_cubic_meter",
+        "Equivalent formaldehyde in µg/m³.",
+    ),
+    (
+        "sensor_reader_tvoc_micrograms_per_cubic_meter",
+        "Total volatile organic compounds in µg/m³.",
+    ),
+    (
+        "sensor_reader_pm2_5_micrograms_per_cubic_meter",
+        "PM2.5 particulate concentration in µg/m³.",
+    ),
+    (
+        "sensor_reader_pm10_micrograms_per_cubic_meter",
+        "PM10 particulate concentration in µg/m³.",
+    ),
+    ("sensor_reader_temperature_celsius", "Temperature in °C."),
+    ("sensor_reader_humidity_percent", "Relative humidity in %."),
+];
+
+/// Gauge name and help text for every [`Comfort`](sensor_reader::Comfort)
+/// field, by field name.
+const COMFORT_GAUGES: [(&str, &str, &str); 4] = [
+    (
+        "dew_point",
+        "sensor_reader_dew_point_celsius",
+        "Dew point in °C.",
+    ),
+    (
+        "absolute_humidity",
+        "sensor_reader_absolute_humidity_grams_per_cubic_meter",
+        "Absolute humidity in g/m³.",
+    ),
+    (
+        "heat_index",
+        "sensor_reader_heat_index_celsius",
+        "Heat index in °C.",
+    ),
+    ("humidex", "sensor_reader_humidex", "Humidex."),
+];
+
+const CONTENT_TYPE: &str = "text/plain; version=0.0.4";
+
+/// Values shared between the serial reader and the exporter thread.
+pub struct Metrics {
+    device_id: String,
+    started: Instant,
+    sinks: Vec<SinkMonitor>,
+    state: Mutex<State>,
+}
+
+#[derive(Default)]
+struct State {
+    latest: Option<Reading>,
+    last_frame: Option<Instant>,
+    decode: DecodeStats,
+    reconnects: u64,
+}
+
+/// The reader's state at one moment, as shown by the exporter and the
+/// `monitor` dashboard.
+pub struct Snapshot {
+    pub latest: Option<Reading>,
+    /// Time since the last valid frame, or since startup if there was none.
+    pub last_frame_age: Duration,
+    pub decode: DecodeStats,
+    pub reconnects: u64,
+}
+
+/// Escapes a label value for the text exposition format.
+fn escape(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+impl Metrics {
+    pub fn new(device_id: &str, sinks: Vec<SinkMonitor>) -> Self {
+        Self {
+            device_id: device_id.to_string(),
+            started: Instant::now(),
+            sinks,
+            state: Mutex::default(),
+        }
+    }
+
+    fn lock(&self) -> MutexGuard<'_, State> {
+        self.state.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    pub fn record_reading(&self, reading: &Reading) {
+        let mut state = self.lock();
+        state.latest = Some(reading.clone());
+        state.last_frame = Some(Instant::now());
+    }
+
+    pub fn record_decode(&self, stats: DecodeStats) {
+        self.lock().decode = stats;
+    }
+
+    pub fn record_reconnect(&self) {
+        self.lock().reconnects += 1;
+    }
+
+    pub fn snapshot(&self) -> Snapshot {
+        let state = self.lock();
+        Snapshot {
+            latest: state.latest.clone(),
+            // Time since startup until the first frame arrives, so a sensor
+            // that never sends anything still trips a staleness alert
+            last_frame_age: state.last_frame.unwrap_or(self.started).elapsed(),
+            decode: state.decode,
+            reconnects: state.reconnects,
+        }
+    }
+
+    pub fn sinks(&self) -> &[SinkMonitor] {
+        &self.sinks
+    }
+
+    /// Renders every metric in the Prometheus text exposition format.
+    pub fn render(&self) -> String {
+        let state = self.snapshot();
+        let device = format!("device=\"{}\"", escape(&self.device_id));
+        let mut out = String::new();
+        let mut metric = |name: &str, kind: &str, help: &str, samples: &[(String, f64)]| {
+            let _ = writeln!(out, "# HELP {} {}", name, help);
+            let _ = writeln!(out, "# TYPE {} {}", name, kind);
+            for (labels, value) in samples {
+                let _ = writeln!(out, "{}{{{}}} {}", name, labels, value);
+            }
+        };
+
+        if let Some(reading) = &state.latest {
+            for ((name, help), (_, value)) in FIELD_GAUGES.iter().zip(reading.data.fields()) {
+                metric(name, "gauge", help, &[(device.clone(), value)]);
+            }
+            for (field, value) in reading.comfort.fields() {
+                if let Some((_, name, help)) = COMFORT_GAUGES.iter().find(|(f, ..)| *f == field) {
+                    metric(name, "gauge", help, &[(device.clone(), value)]);
+                }
+            }
+        }
+
+        metric(
+            "sensor_reader_last_frame_age_seconds",
+            "gauge",
+            "Seconds since the last valid frame.",
+            &[(device.clone(), state.last_frame_age.as_secs_f64())],
+        );
+
+        let decode = &state.decode;
+        for (name, help, value) in [
+            (
+                "sensor_reader_frames_decoded_total",
+                "Valid frames decoded.",
+                decode.frames,
+            ),
+            (
+                "sensor_reader_checksum_failures_total",
+                "Frames dropped on a checksum mismatch.",
+                decode.checksum_mismatches,
+            ),
+            (
+                "sensor_reader_out_of_range_frames_total",
+                "Frames dropped for holding impossible values.",
+                decode.out_of_range,
+            ),
+            (
+                "sensor_reader_bad_headers_total",
+                "False frame headers skipped while resyncing.",
+                decode.bad_headers,
+            ),
+            (
+                "sensor_reader_discarded_bytes_total",
+                "Bytes discarded while resyncing to a frame header.",
+                decode.garbage_bytes,
+            ),
+            (
+                "sensor_reader_serial_reconnects_total",
+                "Times the serial port was reopened after an error.",
+                state.reconnects,
+            ),
+        ] {
+            metric(name, "counter", help, &[(device.clone(), value as f64)]);
+        }
+
+        let sink_samples = |value: &dyn Fn(&SinkMonitor) -> f64| -> Vec<(String, f64)> {
+            self.sinks
+                .iter()
+                .map(|sink| (format!("{},sink=\"{}\"", device, sink.name), value(sink)))
+                .collect()
+        };
+        metric(
+            "sensor_reader_sink_delivered_total",
+            "counter",
+            "Readings accepted by the sink.",
+            &sink_samples(&|sink| sink.delivered() as f64),
+        );
+        metric(
+            "sensor_reader_sink_failed_total",
+            "counter",
+            "Readings the sink gave up on.",
+            &sink_samples(&|sink| sink.failed() as f64),
+        );
+        metric(
+            "sensor_reader_sink_dropped_total",
+            "counter",
+            "Readings dropped because the sink queue was full.",
+            &sink_samples(&|sink| sink.dropped() as f64),
+        );
+        metric(
+            "sensor_reader_sink_queue_depth",
+            "gauge",
+            "Readings waiting in the sink queue.",
+            &sink_samples(&|sink| sink.queue_depth() as f64),
+        );
+
+        out
+    }
+}
+
+/// Serves `/metrics` on `listen` from a background thread.
+pub fn serve(listen: &str, metrics: Arc<Metrics>) -> Result<()> {
+    let server = Server::http(listen)
+        .map_err(|e| anyhow!("Failed to listen for metrics on '{}': {}", listen, e))?;
+    info!("Serving metrics on http://{}/metrics", listen);
+    let content_type = Header::from_bytes("Content-Type", CONTENT_TYPE).expect("valid header");
+
+    thread::spawn(move || {
+        for request in server.incoming_requests() {
+            let response = match request.url() {
+                "/metrics" => Response::from_string(metrics.render())
+                    .with_header(content_type.clone())
+                    .boxed(),
+                _ => Response::from_string("Not found\n")
+                    .with_status_code(404)
+                    .boxed(),
+            };
+            if let Err(e) = request.respond(response) {
+                warn!("Failed to answer metrics request: {}", e);
+            }
+        }
+    });
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use sensor_reader::{DeviceInfo, SensorData, Stamper};
+
+    #[test]
+    fn test_render() {
+        let metrics = Metrics::new("m701 \"lab\"", Vec::new());
+        assert!(!metrics.render().contains("sensor_reader_eco2_ppm"));
+
+        let mut stamper = Stamper::new(DeviceInfo::default());
+        metrics.record_reading(&stamper.stamp(SensorData {
+            eco2: 400,
+            ech2o: 5,
+            tvoc: 10,
+            pm2_5: 20,
+            pm10: 30,
+            temperature: 25.5,
+            humidity: 50.2,
+        }));
+        metrics.record_decode(DecodeStats {
+            frames: 3,
+            garbage_bytes: 7,
+            ..DecodeStats::default()
+        });
+
+        let text = metrics.render();
+        assert!(text.contains("# TYPE sensor_reader_eco2_ppm gauge\n"));
+        assert!(text.contains("sensor_reader_eco2_ppm{device=\"m701 \\\"lab\\\"\"} 400\n"));
+        assert!(
+            text.contains("sensor_reader_humidity_percent{device=\"m701 \\\"lab\\\"\"} 50.2\n")
+        );
+        assert!(
+            text.contains("sensor_reader_frames_decoded_total{device=\"m701 \\\"lab\\\"\"} 3\n")
+        );
+        assert!(
+            text.contains("sensor_reader_discarded_bytes_total{device=\"m701 \\\"lab\\\"\"} 7\n")
+        );
+    }
+}
@@ -0,0 +1,507 @@
+//! The `monitor` subcommand's dashboard: live values, trends and counters in
+//! the terminal.
+
+use anyhow::Result;
+use chrono::{DateTime, TimeDelta, Utc};
+use clap::Args;
+use ratatui::Frame;
+use ratatui::crossterm::event::{self, Event, KeyCode, KeyEventKind, KeyModifiers};
+use ratatui::layout::{Constraint, Layout, Rect};
+use ratatui::style::{Color, Modifier, Style, Stylize};
+use ratatui::text::{Line, Span};
+use ratatui::widgets::{Block, Paragraph, Row, Sparkline, Table};
+use sensor_reader::{FIELD_LABELS, Reading};
+use std::collections::VecDeque;
+use std::io::{self, Write};
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::Duration;
+
+use crate::metrics::{Metrics, Snapshot};
+
+/// How often the dashboard is redrawn.
+const TICK: Duration = Duration::from_millis(250);
+/// Log lines kept for the dashboard.
+const MAX_LOG_LINES: usize = 200;
+
+/// Upper bounds of the good, moderate and poor bands for the pollutants.
+///
+/// eCO2 follows common ventilation guidance, eCH2O and TVOC the GB/T 18883
+/// indoor limits, and PM the US EPA AQI breakpoints.
+const LIMITS: [(&str, [f64; 3]); 5] = [
+    ("eco2", [800.0, 1000.0, 1500.0]),
+    ("ech2o", [50.0, 80.0, 100.0]),
+    ("tvoc", [300.0, 600.0, 1000.0]),
+    ("pm2_5", [9.0, 35.4, 55.4]),
+    ("pm10", [54.0, 154.0, 254.0]),
+];
+
+#[derive(Args, Debug)]
+pub struct MonitorArgs {
+    /// Minutes of history shown in the trend lines
+    #[arg(long, default_value_t = 10, value_parser = clap::value_parser!(u32).range(1..))]
+    minutes: u32,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Band {
+    Good,
+    Moderate,
+    Poor,
+    Bad,
+}
+
+impl Band {
+    fn of(field: &str, value: f64) -> Self {
+        let comfort = |good: (f64, f64), fair: (f64, f64)| {
+            if (good.0..=good.1).contains(&value) {
+                Band::Good
+            } else if (fair.0..=fair.1).contains(&value) {
+                Band::Moderate
+            } else {
+                Band::Poor
+            }
+        };
+        match field {
+            "temperature" => comfort((20.0, 26.0), (18.0, 28.0)),
+            "humidity" => comfort((40.0, 60.0), (30.0, 70.0)),
+            _ => {
+                let limits = LIMITS
+                    .iter()
+                    .find(|(name, _)| *name == field)
+                    .map_or([f64::INFINITY; 3], |(_, limits)| *limits);
+                match limits.iter().position(|&limit| value <= limit) {
+                    Some(0) => Band::Good,
+                    Some(1) => Band::Moderate,
+                    Some(_) => Band::Poor,
+                    None => Band::Bad,
+                }
+            }
+        }
+    }
+
+    fn label(self) -> &'static str {
+        match self {
+            Band::Good => "good",
+            Band::Moderate => "moderate",
+            Band::Poor => "poor",
+            Band::Bad => "bad",
+        }
+    }
+
+    fn color(self) -> Color {
+        match self {
+            Band::Good => Color::Green,
+            Band::Moderate => Color::Yellow,
+            Band::Poor => Color::LightRed,
+            Band::Bad => Color::Red,
+        }
+    }
+}
+
+/// Log output captured while the dashboard owns the terminal.
+///
+/// Used as the logger's target, since lines written to stderr would scribble
+/// over the screen.
+#[derive(Clone, Default)]
+pub struct LogBuffer(Arc<Mutex<LogState>>);
+
+#[derive(Default)]
+struct LogState {
+    partial: Vec<u8>,
+    lines: VecDeque<String>,
+    /// Set once the dashboard is closed; writes go straight to stderr.
+    passthrough: bool,
+}
+
+impl LogBuffer {
+    fn lock(&self) -> MutexGuard<'_, LogState> {
+        self.0.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    /// Writes everything logged from now on to stderr.
+    pub fn passthrough(&self) {
+        self.lock().passthrough = true;
+    }
+
+    fn last(&self, n: usize) -> Vec<String> {
+        let state = self.lock();
+        state
+            .lines
+            .iter()
+            .skip(state.lines.len().saturating_sub(n))
+            .cloned()
+            .collect()
+    }
+}
+
+impl Write for LogBuffer {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let mut state = self.lock();
+        if state.passthrough {
+            return io::stderr().write(buf);
+        }
+        state.partial.extend_from_slice(buf);
+        while let Some(end) = state.partial.iter().position(|&b| b == b'\n') {
+            let line: Vec<u8> = state.partial.drain(..=end).collect();
+            let line = String::from_utf8_lossy(&line).trim_end().to_string();
+            state.lines.push_back(line);
+            if state.lines.len() > MAX_LOG_LINES {
+                state.lines.pop_front();
+            }
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Recent values of every field, for the trend lines.
+struct Trends {
+    window: TimeDelta,
+    last_seq: Option<u64>,
+    series: [VecDeque<(DateTime<Utc>, f64)>; 7],
+}
+
+impl Trends {
+    fn new(window: TimeDelta) -> Self {
+        Self {
+            window,
+            last_seq: None,
+            series: Default::default(),
+        }
+    }
+
+    fn record(&mut self, reading: &Reading) {
+        if self.last_seq == Some(reading.seq) {
+            return;
+        }
+        self.last_seq = Some(reading.seq);
+        let cutoff = reading.captured_at - self.window;
+        for (series, (_, value)) in self.series.iter_mut().zip(reading.data.fields()) {
+            series.push_back((reading.captured_at, value));
+            while series.front().is_some_and(|(at, _)| *at < cutoff) {
+                series.pop_front();
+            }
+        }
+    }
+
+    /// Averages field `index` over `width` equal slices of the window ending
+    /// at `now`, scaled to 1..=8 between the lowest and highest average.
+    /// Slices without readings are 0.
+    ///
+    /// Returns the bars and the range they span.
+    fn bars(
+        &self,
+        index: usize,
+        now: DateTime<Utc>,
+        width: usize,
+    ) -> (Vec<u64>, Option<(f64, f64)>) {
+        let start = now - self.window;
+        let window_ms = self.window.num_milliseconds().max(1) as f64;
+        let mut sums = vec![(0.0, 0u32); width];
+        for (at, value) in &self.series[index] {
+            let offset = (*at - start).num_milliseconds() as f64 / window_ms;
+            if (0.0..=1.0).contains(&offset) && width > 0 {
+                let slot = ((offset * width as f64) as usize).min(width - 1);
+                sums[slot].0 += value;
+                sums[slot].1 += 1;
+            }
+        }
+
+        let averages: Vec<Option<f64>> = sums
+            .into_iter()
+            .map(|(sum, n)| (n > 0).then(|| sum / f64::from(n)))
+            .collect();
+        let lo = averages.iter().flatten().copied().reduce(f64::min);
+        let hi = averages.iter().flatten().copied().reduce(f64::max);
+        let (Some(lo), Some(hi)) = (lo, hi) else {
+            return (vec![0; width], None);
+        };
+        let bars = averages
+            .into_iter()
+            .map(|average| match average {
+                None => 0,
+                Some(_) if hi == lo => 4,
+                Some(v) => 1 + ((v - lo) / (hi - lo) * 7.0).round() as u64,
+            })
+            .collect();
+        (bars, Some((lo, hi)))
+    }
+}
+
+/// Formats a value, or an average of values, at the sensor's resolution.
+fn format_value(field: &str, value: f64) -> String {
+    match field {
+        "temperature" | "humidity" => format!("{:.1}", value),
+        _ => format!("{:.0}", value),
+    }
+}
+
+struct Dashboard<'a> {
+    title: String,
+    metrics: &'a Metrics,
+    logs: &'a LogBuffer,
+    trends: Trends,
+    minutes: u32,
+}
+
+impl Dashboard<'_> {
+    fn draw(&self, frame: &mut Frame, snapshot: &Snapshot) {
+        let [readings, counters, logs, footer] = Layout::vertical([
+            Constraint::Length(9),
+            Constraint::Length(9),
+            Constraint::Min(3),
+            Constraint::Length(1),
+        ])
+        .areas(frame.area());
+        self.draw_readings(frame, readings, snapshot);
+
+        let [decoder, sinks] =
+            Layout::horizontal([Constraint::Percentage(40), Constraint::Percentage(60)])
+                .areas(counters);
+        self.draw_decoder(frame, decoder, snapshot);
+        self.draw_sinks(frame, sinks);
+
+        let block = Block::bordered().title(" Log ");
+        let height = block.inner(logs).height as usize;
+        let lines: Vec<Line> = self.logs.last(height).into_iter().map(Line::from).collect();
+        frame.render_widget(Paragraph::new(lines).block(block), logs);
+
+        frame.render_widget(
+            Line::from(vec![
+                " q ".bold().reversed(),
+                Span::raw(" quit   "),
+                Span::raw(format!(
+                    "last frame {:.1}s ago",
+                    snapshot.last_frame_age.as_secs_f32()
+                )),
+            ]),
+            footer,
+        );
+    }
+
+    fn draw_readings(&self, frame: &mut Frame, area: Rect, snapshot: &Snapshot) {
+        let block =
+            Block::bordered().title(format!(" {} — last {} min ", self.title, self.minutes));
+        let inner = block.inner(area);
+        frame.render_widget(block, area);
+
+        let Some(reading) = &snapshot.latest else {
+            frame.render_widget(Paragraph::new("Waiting for the first frame..."), inner);
+            return;
+        };
+        let rows = Layout::vertical([Constraint::Length(1); 7]).split(inner);
+        for (i, ((name, value), (label, unit))) in reading
+            .data
+            .fields()
+            .into_iter()
+            .zip(FIELD_LABELS)
+            .enumerate()
+        {
+            let band = Band::of(name, value);
+            let [name_area, value_area, band_area, trend_area, range_area] = Layout::horizontal([
+                Constraint::Length(12),
+                Constraint::Length(14),
+                Constraint::Length(10),
+                Constraint::Fill(1),
+                Constraint::Length(16),
+            ])
+            .spacing(1)
+            .areas(rows[i]);
+
+            frame.render_widget(Span::raw(label).bold(), name_area);
+            frame.render_widget(
+                Line::from(format!("{} {}", format_value(name, value), unit)).right_aligned(),
+                value_area,
+            );
+            frame.render_widget(
+                Span::styled(band.label(), Style::default().fg(band.color())),
+                band_area,
+            );
+
+            let (bars, range) = self
+                .trends
+                .bars(i, reading.captured_at, trend_area.width as usize);
+            frame.render_widget(
+                Sparkline::default()
+                    .data(&bars)
+                    .max(8)
+                    .style(Style::default().fg(band.color())),
+                trend_area,
+            );
+            if let Some((lo, hi)) = range {
+                frame.render_widget(
+                    Span::raw(format!(
+                        "{}–{}",
+                        format_value(name, lo),
+                        format_value(name, hi)
+                    ))
+                    .dim(),
+                    range_area,
+                );
+            }
+        }
+    }
+
+    fn draw_decoder(&self, frame: &mut Frame, area: Rect, snapshot: &Snapshot) {
+        let decode = &snapshot.decode;
+        let errors = |n: u64| {
+            let span = Span::raw(n.to_string());
+            if n > 0 {
+                span.fg(Color::LightRed)
+            } else {
+                span
+            }
+        };
+        let rows = [
+            ("Frames", Span::raw(decode.frames.to_string())),
+            ("Checksum errors", errors(decode.checksum_mismatches)),
+            ("Out of range", errors(decode.out_of_range)),
+            ("Bad headers", Span::raw(decode.bad_headers.to_string())),
+            (
+                "Discarded bytes",
+                Span::raw(decode.garbage_bytes.to_string()),
+            ),
+            ("Reconnects", errors(snapshot.reconnects)),
+        ];
+        let table = Table::new(
+            rows.into_iter().map(|(name, value)| {
+                Row::new([Line::from(name), Line::from(value).right_aligned()])
+            }),
+            [Constraint::Fill(1), Constraint::Length(12)],
+        )
+        .block(Block::bordered().title(" Decoder "));
+        frame.render_widget(table, area);
+    }
+
+    fn draw_sinks(&self, frame: &mut Frame, area: Rect) {
+        let block = Block::bordered().title(" Sinks ");
+        let sinks = self.metrics.sinks();
+        if sinks.is_empty() {
+            frame.render_widget(Paragraph::new("No sinks enabled").block(block), area);
+            return;
+        }
+        let header = Row::new(["Sink", "Delivered", "Failed", "Dropped", "Queued"])
+            .style(Style::default().add_modifier(Modifier::BOLD));
+        let rows = sinks.iter().map(|sink| {
+            let failed = sink.failed() + sink.dropped() > 0;
+            Row::new([
+                sink.name.to_string(),
+                sink.delivered().to_string(),
+                sink.failed().to_string(),
+                sink.dropped().to_string(),
+                sink.queue_depth().to_string(),
+            ])
+            .style(if failed {
+                Style::default().fg(Color::LightRed)
+            } else {
+                Style::default()
+            })
+        });
+        let table = Table::new(
+            rows,
+            [
+                Constraint::Fill(1),
+                Constraint::Length(10),
+                Constraint::Length(8),
+                Constraint::Length(8),
+                Constraint::Length(8),
+            ],
+        )
+        .header(header)
+        .block(block);
+        frame.render_widget(table, area);
+    }
+}
+
+/// Shows the dashboard until the user quits or `finished` returns `true`.
+pub fn show(
+    title: String,
+    args: &MonitorArgs,
+    metrics: &Metrics,
+    logs: &LogBuffer,
+    finished: impl Fn() -> bool,
+) -> Result<()> {
+    let mut dashboard = Dashboard {
+        title,
+        metrics,
+        logs,
+        trends: Trends::new(TimeDelta::minutes(i64::from(args.minutes))),
+        minutes: args.minutes,
+    };
+    let mut terminal = ratatui::try_init()?;
+    let result = (|| -> Result<()> {
+        while !finished() {
+            let snapshot = metrics.snapshot();
+            if let Some(reading) = &snapshot.latest {
+                dashboard.trends.record(reading);
+            }
+            terminal.draw(|frame| dashboard.draw(frame, &snapshot))?;
+
+            if event::poll(TICK)?
+                && let Event::Key(key) = event::read()?
+                && key.kind == KeyEventKind::Press
+            {
+                let ctrl_c =
+                    key.code == KeyCode::Char('c') && key.modifiers.contains(KeyModifiers::CONTROL);
+                if ctrl_c || matches!(key.code, KeyCode::Char('q') | KeyCode::Esc) {
+                    break;
+                }
+            }
+        }
+        Ok(())
+    })();
+    ratatui::try_restore()?;
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use sensor_reader::{DeviceInfo, SensorData, Stamper};
+
+    #[test]
+    fn test_band() {
+        assert_eq!(Band::of("eco2", 450.0), Band::Good);
+        assert_eq!(Band::of("eco2", 1000.0), Band::Moderate);
+        assert_eq!(Band::of("eco2", 1200.0), Band::Poor);
+        assert_eq!(Band::of("pm2_5", 80.0), Band::Bad);
+        assert_eq!(Band::of("temperature", 22.0), Band::Good);
+        assert_eq!(Band::of("temperature", 27.5), Band::Moderate);
+        assert_eq!(Band::of("humidity", 85.0), Band::Poor);
+    }
+
+    #[test]
+    fn test_trend_bars() {
+        let mut trends = Trends::new(TimeDelta::minutes(4));
+        let mut stamper = Stamper::new(DeviceInfo::default());
+        let start = DateTime::parse_from_rfc3339("2024-05-01T12:00:00Z")
+            .unwrap()
+            .to_utc();
+        for (minute, eco2) in [(0, 400), (1, 400), (3, 800), (5, 600), (6, 800)] {
+            let data = SensorData {
+                eco2,
+                ech2o: 5,
+                tvoc: 10,
+                pm2_5: 20,
+                pm10: 30,
+                temperature: 25.5,
+                humidity: 50.2,
+            };
+            let reading = stamper.stamp_at(data, start + TimeDelta::minutes(minute));
+            trends.record(&reading);
+            trends.record(&reading);
+        }
+        // Only minutes 3, 5 and 6 are within the window
+        assert_eq!(trends.series[0].len(), 3);
+
+        let now = start + TimeDelta::minutes(7);
+        let (bars, range) = trends.bars(0, now, 4);
+        assert_eq!(bars, [8, 0, 1, 8]);
+        assert_eq!(range, Some((600.0, 800.0)));
+        let (bars, _) = trends.bars(5, now, 4);
+        assert_eq!(bars, [4, 0, 4, 4]);
+    }
+}
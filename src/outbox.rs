@@ -0,0 +1,499 @@
+use anyhow::{Context, Result};
+use log::warn;
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use std::collections::VecDeque;
+use std::fs::{self, File, OpenOptions};
+use std::io::{BufRead, BufReader, Seek, SeekFrom, Write};
+use std::marker::PhantomData;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+const SEGMENT_EXT: &str = "jsonl";
+const CURSOR_FILE: &str = "cursor";
+
+/// Limits applied to the on-disk outbox.
+#[derive(Debug, Clone, Copy)]
+pub struct OutboxConfig {
+    /// Total size of all segments; the oldest segments are evicted beyond it.
+    pub max_bytes: u64,
+    /// Records older than this are discarded instead of replayed.
+    pub max_age: Duration,
+    /// Size at which the active segment is closed and a new one started.
+    pub segment_bytes: u64,
+}
+
+impl Default for OutboxConfig {
+    fn default() -> Self {
+        Self {
+            max_bytes: 64 * 1024 * 1024,
+            max_age: Duration::from_secs(7 * 24 * 3600),
+            segment_bytes: 1024 * 1024,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+struct Record<T> {
+    /// Unix time in seconds at which the record was spooled.
+    stored_at: u64,
+    reading: T,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Segment {
+    id: u64,
+    bytes: u64,
+}
+
+/// Position of the next record to replay.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Cursor {
+    segment: u64,
+    offset: u64,
+}
+
+/// Persistent FIFO of records that could not be delivered yet.
+///
+/// Records are appended as JSON lines to numbered segment files in `dir` and
+/// flushed to disk before [`append`](Self::append) returns. Replay progress is
+/// kept in a separate cursor file, so records survive restarts and are
+/// replayed in the order they were stored. A record torn by a power loss is
+/// skipped on replay.
+pub struct Outbox<T> {
+    dir: PathBuf,
+    config: OutboxConfig,
+    segments: VecDeque<Segment>,
+    /// Segment currently appended to. Always a fresh segment per process, so
+    /// we never append after a torn line left behind by a crash.
+    active: Option<(u64, File)>,
+    cursor: Cursor,
+    /// Offset just past each record returned by the last `peek`.
+    peeked: Vec<u64>,
+    _marker: PhantomData<T>,
+}
+
+fn unix_now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
+impl<T: Serialize + DeserializeOwned> Outbox<T> {
+    pub fn open(dir: &Path, config: OutboxConfig) -> Result<Self> {
+        fs::create_dir_all(dir)
+            .with_context(|| format!("Failed to create outbox directory '{}'", dir.display()))?;
+
+        let mut segments = Vec::new();
+        for entry in fs::read_dir(dir)? {
+            let path = entry?.path();
+            if path.extension().and_then(|e| e.to_str()) != Some(SEGMENT_EXT) {
+                continue;
+            }
+            if let Some(id) = path
+                .file_stem()
+                .and_then(|s| s.to_str())
+                .and_then(|s| s.parse().ok())
+            {
+                let bytes = fs::metadata(&path)?.len();
+                segments.push(Segment { id, bytes });
+            }
+        }
+        segments.sort_by_key(|s| s.id);
+
+        let mut outbox = Self {
+            dir: dir.to_path_buf(),
+            config,
+            segments: segments.into(),
+            active: None,
+            cursor: Cursor {
+                segment: 0,
+                offset: 0,
+            },
+            peeked: Vec::new(),
+            _marker: PhantomData,
+        };
+
+        let saved = outbox.read_cursor();
+        outbox.cursor = match outbox.segments.front() {
+            Some(first) => saved.filter(|c| c.segment >= first.id).unwrap_or(Cursor {
+                segment: first.id,
+                offset: 0,
+            }),
+            None => saved.unwrap_or(Cursor {
+                segment: 0,
+                offset: 0,
+            }),
+        };
+
+        // Segments in front of the cursor have been replayed completely
+        while let Some(first) = outbox.segments.front().copied() {
+            if first.id >= outbox.cursor.segment {
+                break;
+            }
+            outbox.remove_front()?;
+        }
+        Ok(outbox)
+    }
+
+    fn segment_path(&self, id: u64) -> PathBuf {
+        self.dir.join(format!("{:020}.{}", id, SEGMENT_EXT))
+    }
+
+    fn read_cursor(&self) -> Option<Cursor> {
+        let text = fs::read_to_string(self.dir.join(CURSOR_FILE)).ok()?;
+        let (segment, offset) = text.trim().split_once(' ')?;
+        Some(Cursor {
+            segment: segment.parse().ok()?,
+            offset: offset.parse().ok()?,
+        })
+    }
+
+    fn write_cursor(&self) -> Result<()> {
+        let tmp = self.dir.join(format!("{}.tmp", CURSOR_FILE));
+        let mut file = File::create(&tmp)?;
+        writeln!(file, "{} {}", self.cursor.segment, self.cursor.offset)?;
+        file.sync_all()?;
+        fs::rename(&tmp, self.dir.join(CURSOR_FILE))
+            .with_context(|| format!("Failed to update outbox cursor in '{}'", self.dir.display()))
+    }
+
+    fn total_bytes(&self) -> u64 {
+        self.segments.iter().map(|s| s.bytes).sum()
+    }
+
+    fn is_active(&self, id: u64) -> bool {
+        self.active
+            .as_ref()
+            .is_some_and(|(active, _)| *active == id)
+    }
+
+    /// Deletes the oldest segment and moves the cursor past it if needed.
+    fn remove_front(&mut self) -> Result<Option<Segment>> {
+        let Some(segment) = self.segments.pop_front() else {
+            return Ok(None);
+        };
+        if self.is_active(segment.id) {
+            self.active = None;
+        }
+        let path = self.segment_path(segment.id);
+        if let Err(e) = fs::remove_file(&path)
+            && e.kind() != std::io::ErrorKind::NotFound
+        {
+            return Err(e).with_context(|| format!("Failed to remove '{}'", path.display()));
+        }
+        if self.cursor.segment <= segment.id {
+            self.cursor = Cursor {
+                segment: self.segments.front().map_or(segment.id + 1, |s| s.id),
+                offset: 0,
+            };
+            self.peeked.clear();
+            self.write_cursor()?;
+        }
+        Ok(Some(segment))
+    }
+
+    /// Returns `true` if there is nothing left to replay.
+    pub fn is_empty(&self) -> bool {
+        match self.segments.back() {
+            None => true,
+            Some(last) => self.cursor.segment == last.id && self.cursor.offset >= last.bytes,
+        }
+    }
+
+    /// Durably appends `reading` to the end of the outbox.
+    pub fn append(&mut self, reading: &T) -> Result<()> {
+        let mut line = serde_json::to_vec(&Record {
+            stored_at: unix_now(),
+            reading,
+        })?;
+        line.push(b'\n');
+
+        let roll = match (&self.active, self.segments.back()) {
+            (Some(_), Some(last)) => last.bytes >= self.config.segment_bytes,
+            _ => true,
+        };
+        if roll {
+            let id = self
+                .segments
+                .back()
+                .map_or(self.cursor.segment.max(1), |s| s.id + 1);
+            let path = self.segment_path(id);
+            let file = OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(&path)
+                .with_context(|| format!("Failed to create '{}'", path.display()))?;
+            self.active = Some((id, file));
+            self.segments.push_back(Segment { id, bytes: 0 });
+            if self.segments.len() == 1 {
+                self.cursor = Cursor {
+                    segment: id,
+                    offset: 0,
+                };
+                self.write_cursor()?;
+            }
+        }
+
+        let (_, file) = self.active.as_mut().expect("active segment is open");
+        file.write_all(&line)?;
+        file.sync_data()?;
+        if let Some(active) = self.segments.back_mut() {
+            active.bytes += line.len() as u64;
+        }
+
+        self.evict()
+    }
+
+    /// Enforces the size and age limits by dropping whole segments, oldest
+    /// first. The active segment is never evicted.
+    fn evict(&mut self) -> Result<()> {
+        while self.segments.len() > 1 && self.total_bytes() > self.config.max_bytes {
+            if let Some(segment) = self.remove_front()? {
+                warn!(
+                    "Outbox over {} bytes, evicted {} bytes of unsent readings",
+                    self.config.max_bytes, segment.bytes
+                );
+            }
+        }
+
+        let now = SystemTime::now();
+        while self.segments.len() > 1 {
+            let path = self.segment_path(self.segments[0].id);
+            let expired = fs::metadata(&path)
+                .and_then(|m| m.modified())
+                .ok()
+                .and_then(|modified| now.duration_since(modified).ok())
+                .is_some_and(|age| age > self.config.max_age);
+            if !expired {
+                break;
+            }
+            if let Some(segment) = self.remove_front()? {
+                warn!(
+                    "Evicted {} bytes of unsent readings older than {}s",
+                    segment.bytes,
+                    self.config.max_age.as_secs()
+                );
+            }
+        }
+        Ok(())
+    }
+
+    /// Returns up to `max` of the oldest records that have not been
+    /// acknowledged yet, or an empty vector if there are none.
+    ///
+    /// Calling `peek` again without [`ack`](Self::ack) returns the same
+    /// records. Expired and corrupted records are skipped. A batch never
+    /// spans more than one segment, so it may hold fewer than `max` records
+    /// even if more are stored.
+    pub fn peek(&mut self, max: usize) -> Result<Vec<T>> {
+        let oldest_allowed = unix_now().saturating_sub(self.config.max_age.as_secs());
+        loop {
+            let Some(index) = self
+                .segments
+                .iter()
+                .position(|s| s.id == self.cursor.segment)
+            else {
+                return Ok(Vec::new());
+            };
+            let segment = self.segments[index];
+
+            if self.cursor.offset >= segment.bytes {
+                if index + 1 == self.segments.len() {
+                    return Ok(Vec::new());
+                }
+                // Fully replayed; move on to the next segment
+                self.remove_front()?;
+                continue;
+            }
+
+            let path = self.segment_path(segment.id);
+            let mut file = File::open(&path)
+                .with_context(|| format!("Failed to open '{}'", path.display()))?;
+            file.seek(SeekFrom::Start(self.cursor.offset))?;
+            let mut reader = BufReader::new(file);
+            let mut line = Vec::new();
+            let read = reader.read_until(b'\n', &mut line)? as u64;
+            let mut next = self.cursor.offset + read;
+
+            if line.last() != Some(&b'\n') {
+                if self.is_active(segment.id) {
+                    return Ok(Vec::new());
+                }
+                // Torn write from a crash at the end of an old segment
+                warn!("Skipping incomplete record in '{}'", path.display());
+                self.cursor.offset = segment.bytes;
+                continue;
+            }
+
+            match serde_json::from_slice::<Record<T>>(&line) {
+                Ok(record) if record.stored_at >= oldest_allowed => {
+                    let mut batch = vec![record.reading];
+                    let mut ends = vec![next];
+                    // Extend the batch with the following intact records;
+                    // anything unusual is left for the next call to deal with
+                    while batch.len() < max {
+                        line.clear();
+                        let read = reader.read_until(b'\n', &mut line)? as u64;
+                        if line.last() != Some(&b'\n') {
+                            break;
+                        }
+                        match serde_json::from_slice::<Record<T>>(&line) {
+                            Ok(record) if record.stored_at >= oldest_allowed => {
+                                batch.push(record.reading);
+                                next += read;
+                                ends.push(next);
+                            }
+                            _ => break,
+                        }
+                    }
+                    self.peeked = ends;
+                    return Ok(batch);
+                }
+                Ok(_) => {}
+                Err(e) => warn!("Skipping corrupted record in '{}': {}", path.display(), e),
+            }
+            self.cursor.offset = next;
+            self.write_cursor()?;
+        }
+    }
+
+    /// Marks the first `n` records returned by the last [`peek`](Self::peek)
+    /// as delivered, so the next `peek` starts with the rest.
+    pub fn ack(&mut self, n: usize) -> Result<()> {
+        let peeked = std::mem::take(&mut self.peeked);
+        if let Some(&next) = n.checked_sub(1).and_then(|i| peeked.get(i)) {
+            self.cursor.offset = next;
+            self.write_cursor()?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config() -> OutboxConfig {
+        OutboxConfig {
+            max_bytes: 1024 * 1024,
+            max_age: Duration::from_secs(3600),
+            segment_bytes: 64,
+        }
+    }
+
+    fn drain(outbox: &mut Outbox<u32>) -> Vec<u32> {
+        let mut out = Vec::new();
+        loop {
+            let batch = outbox.peek(3).unwrap();
+            if batch.is_empty() {
+                return out;
+            }
+            outbox.ack(batch.len()).unwrap();
+            out.extend(batch);
+        }
+    }
+
+    #[test]
+    fn test_replays_in_order_across_segments() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut outbox = Outbox::open(dir.path(), config()).unwrap();
+        assert!(outbox.is_empty());
+
+        for i in 0..20 {
+            outbox.append(&i).unwrap();
+        }
+        assert!(!outbox.is_empty());
+        assert!(outbox.segments.len() > 1);
+
+        // Peeking twice without ack yields the same records
+        assert_eq!(outbox.peek(2).unwrap(), vec![0, 1]);
+        assert_eq!(outbox.peek(1).unwrap(), vec![0]);
+
+        assert_eq!(drain(&mut outbox), (0..20).collect::<Vec<_>>());
+        assert!(outbox.is_empty());
+    }
+
+    #[test]
+    fn test_survives_restart() {
+        let dir = tempfile::tempdir().unwrap();
+        {
+            let mut outbox = Outbox::open(dir.path(), config()).unwrap();
+            for i in 0..10 {
+                outbox.append(&i).unwrap();
+            }
+            let mut acked = 0;
+            while acked < 4 {
+                let n = outbox.peek(4 - acked).unwrap().len();
+                outbox.ack(n).unwrap();
+                acked += n;
+            }
+        }
+
+        let mut outbox = Outbox::open(dir.path(), config()).unwrap();
+        outbox.append(&10).unwrap();
+        assert_eq!(drain(&mut outbox), (4..11).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn test_partial_ack() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = OutboxConfig {
+            segment_bytes: 1024,
+            ..config()
+        };
+        let mut outbox = Outbox::open(dir.path(), config).unwrap();
+        for i in 0..3 {
+            outbox.append(&i).unwrap();
+        }
+        assert_eq!(outbox.peek(3).unwrap(), [0, 1, 2]);
+        outbox.ack(0).unwrap();
+        assert_eq!(outbox.peek(3).unwrap(), [0, 1, 2]);
+        outbox.ack(2).unwrap();
+        assert_eq!(drain(&mut outbox), [2]);
+    }
+
+    #[test]
+    fn test_skips_torn_record() {
+        let dir = tempfile::tempdir().unwrap();
+        {
+            let mut outbox = Outbox::open(dir.path(), config()).unwrap();
+            outbox.append(&1).unwrap();
+        }
+        // Simulate a crash halfway through writing the second record
+        let segment = fs::read_dir(dir.path())
+            .unwrap()
+            .map(|e| e.unwrap().path())
+            .find(|p| p.extension().is_some_and(|e| e == SEGMENT_EXT))
+            .unwrap();
+        let mut file = OpenOptions::new().append(true).open(segment).unwrap();
+        file.write_all(b"{\"stored_at\":").unwrap();
+
+        let mut outbox = Outbox::open(dir.path(), config()).unwrap();
+        outbox.append(&2).unwrap();
+        assert_eq!(drain(&mut outbox), vec![1, 2]);
+    }
+
+    #[test]
+    fn test_evicts_oldest_when_full() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut outbox = Outbox::open(
+            dir.path(),
+            OutboxConfig {
+                max_bytes: 200,
+                ..config()
+            },
+        )
+        .unwrap();
+        for i in 0..50 {
+            outbox.append(&i).unwrap();
+        }
+
+        assert!(outbox.total_bytes() <= 200 + config().segment_bytes);
+        let replayed = drain(&mut outbox);
+        assert_eq!(replayed.last(), Some(&49));
+        assert!(replayed.len() < 50);
+        assert!(replayed.windows(2).all(|w| w[0] + 1 == w[1]));
+    }
+}
@@ -0,0 +1,321 @@
+//! M701 UART frame format.
+//!
+//! The sensor emits a fixed 17-byte frame roughly once per second:
+//!
+//! | Offset | Size | Field                                   |
+//! |--------|------|-----------------------------------------|
+//! | 0      | 1    | Header `0x3C`                           |
+//! | 1      | 1    | Header `0x02`                           |
+//! | 2      | 2    | eCO2 (ppm, big endian)                  |
+//! | 4      | 2    | eCH2O (µg/m³, big endian)               |
+//! | 6      | 2    | TVOC (µg/m³, big endian)                |
+//! | 8      | 2    | PM2.5 (µg/m³, big endian)               |
+//! | 10     | 2    | PM10 (µg/m³, big endian)                |
+//! | 12     | 2    | Temperature, integer and decimal part   |
+//! | 14     | 2    | Humidity, integer and decimal part      |
+//! | 16     | 1    | Checksum, low byte of the sum of 0..16  |
+
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+
+/// First header byte of every frame.
+pub const FRAME_HEADER_1: u8 = 0x3C;
+/// Second header byte of every frame.
+pub const FRAME_HEADER_2: u8 = 0x02;
+/// Total length of a frame in bytes, including headers and checksum.
+pub const FRAME_LEN: usize = 17;
+
+/// One decoded M701 measurement.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct SensorData {
+    /// Equivalent CO2 in ppm.
+    pub eco2: u16,
+    /// Equivalent formaldehyde in µg/m³.
+    pub ech2o: u16,
+    /// Total volatile organic compounds in µg/m³.
+    pub tvoc: u16,
+    /// PM2.5 particulate concentration in µg/m³.
+    pub pm2_5: u16,
+    /// PM10 particulate concentration in µg/m³.
+    pub pm10: u16,
+    /// Temperature in °C.
+    pub temperature: f32,
+    /// Relative humidity in %.
+    pub humidity: f32,
+}
+
+/// Names of the [`SensorData`] fields, in frame order.
+pub const FIELD_NAMES: [&str; 7] = [
+    "eco2",
+    "ech2o",
+    "tvoc",
+    "pm2_5",
+    "pm10",
+    "temperature",
+    "humidity",
+];
+
+/// Display name and unit of the [`SensorData`] fields, in [`FIELD_NAMES`]
+/// order.
+pub const FIELD_LABELS: [(&str, &str); 7] = [
+    ("eCO2", "ppm"),
+    ("eCH2O", "µg/m³"),
+    ("TVOC", "µg/m³"),
+    ("PM2.5", "µg/m³"),
+    ("PM10", "µg/m³"),
+    ("Temperature", "°C"),
+    ("Humidity", "%"),
+];
+
+impl SensorData {
+    /// Returns every field as a name/value pair, in [`FIELD_NAMES`] order.
+    ///
+    /// Temperature and humidity are rounded to the sensor's 0.1 resolution,
+    /// so `50.2` does not come out as `50.20000076293945`.
+    pub fn fields(&self) -> [(&'static str, f64); 7] {
+        let tenths = |v: f32| (f64::from(v) * 10.0).round() / 10.0;
+        [
+            (FIELD_NAMES[0], f64::from(self.eco2)),
+            (FIELD_NAMES[1], f64::from(self.ech2o)),
+            (FIELD_NAMES[2], f64::from(self.tvoc)),
+            (FIELD_NAMES[3], f64::from(self.pm2_5)),
+            (FIELD_NAMES[4], f64::from(self.pm10)),
+            (FIELD_NAMES[5], tenths(self.temperature)),
+            (FIELD_NAMES[6], tenths(self.humidity)),
+        ]
+    }
+}
+
+/// Reasons a byte slice could not be decoded as a frame.
+#[derive(Debug, Clone, PartialEq, Error)]
+pub enum FrameError {
+    /// Fewer than [`FRAME_LEN`] bytes were supplied.
+    #[error("frame too short: {len} of {FRAME_LEN} bytes")]
+    TooShort { len: usize },
+
+    /// The first two bytes are not `0x3C 0x02`.
+    #[error("bad frame header: {0:02X} {1:02X}")]
+    BadHeader(u8, u8),
+
+    /// The trailing checksum byte does not match the computed sum.
+    #[error("checksum mismatch: expected {expected:02X}, got {actual:02X}")]
+    ChecksumMismatch { expected: u8, actual: u8 },
+
+    /// The checksum is valid but a field holds a value the sensor cannot
+    /// produce, e.g. a decimal byte above 9 or humidity above 100 %.
+    #[error("{field} out of range: {value}")]
+    OutOfRange { field: &'static str, value: f32 },
+}
+
+/// Computes the M701 checksum: the low byte of the sum of `data`.
+pub fn calculate_checksum(data: &[u8]) -> u8 {
+    let mut sum: u16 = 0;
+    for &b in data {
+        sum = sum.wrapping_add(b as u16);
+    }
+    (sum & 0xFF) as u8
+}
+
+/// Combines an integer byte and a single decimal digit into one value.
+fn decimal(field: &'static str, int: u8, dec: u8) -> Result<f32, FrameError> {
+    if dec > 9 {
+        return Err(FrameError::OutOfRange {
+            field,
+            value: dec as f32,
+        });
+    }
+    Ok(int as f32 + (dec as f32 / 10.0))
+}
+
+/// Decodes the frame at the start of `buffer`.
+///
+/// Only the first [`FRAME_LEN`] bytes are inspected; anything after them is
+/// ignored.
+pub fn parse_frame(buffer: &[u8]) -> Result<SensorData, FrameError> {
+    if buffer.len() < FRAME_LEN {
+        return Err(FrameError::TooShort { len: buffer.len() });
+    }
+
+    // Verify headers
+    if buffer[0] != FRAME_HEADER_1 || buffer[1] != FRAME_HEADER_2 {
+        return Err(FrameError::BadHeader(buffer[0], buffer[1]));
+    }
+
+    // Verify checksum
+    let calculated_sum = calculate_checksum(&buffer[0..16]);
+    if calculated_sum != buffer[16] {
+        return Err(FrameError::ChecksumMismatch {
+            expected: calculated_sum,
+            actual: buffer[16],
+        });
+    }
+
+    let eco2 = u16::from_be_bytes([buffer[2], buffer[3]]);
+    let ech2o = u16::from_be_bytes([buffer[4], buffer[5]]);
+    let tvoc = u16::from_be_bytes([buffer[6], buffer[7]]);
+    let pm2_5 = u16::from_be_bytes([buffer[8], buffer[9]]);
+    let pm10 = u16::from_be_bytes([buffer[10], buffer[11]]);
+
+    let temperature = decimal("temperature", buffer[12], buffer[13])?;
+    let humidity = decimal("humidity", buffer[14], buffer[15])?;
+    if humidity > 100.0 {
+        return Err(FrameError::OutOfRange {
+            field: "humidity",
+            value: humidity,
+        });
+    }
+
+    Ok(SensorData {
+        eco2,
+        ech2o,
+        tvoc,
+        pm2_5,
+        pm10,
+        temperature,
+        humidity,
+    })
+}
+
+/// Encodes `data` as a frame, the inverse of [`parse_frame`].
+///
+/// Temperature and humidity are rounded to one decimal and clamped to
+/// 0.0..=255.9, the range the frame can hold.
+pub fn encode_frame(data: &SensorData) -> [u8; FRAME_LEN] {
+    let decimal = |value: f32| {
+        let tenths = (value * 10.0).round().clamp(0.0, 2559.0) as u16;
+        [(tenths / 10) as u8, (tenths % 10) as u8]
+    };
+    let mut frame = [0; FRAME_LEN];
+    frame[0] = FRAME_HEADER_1;
+    frame[1] = FRAME_HEADER_2;
+    frame[2..4].copy_from_slice(&data.eco2.to_be_bytes());
+    frame[4..6].copy_from_slice(&data.ech2o.to_be_bytes());
+    frame[6..8].copy_from_slice(&data.tvoc.to_be_bytes());
+    frame[8..10].copy_from_slice(&data.pm2_5.to_be_bytes());
+    frame[10..12].copy_from_slice(&data.pm10.to_be_bytes());
+    frame[12..14].copy_from_slice(&decimal(data.temperature));
+    frame[14..16].copy_from_slice(&decimal(data.humidity));
+    frame[16] = calculate_checksum(&frame[..16]);
+    frame
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn valid_frame() -> Vec<u8> {
+        let mut data = vec![
+            0x3C, 0x02, // Header
+            0x01, 0x90, // eCO2 = 400
+            0x00, 0x05, // eCH2O = 5
+            0x00, 0x0A, // TVOC = 10
+            0x00, 0x14, // PM2.5 = 20
+            0x00, 0x1E, // PM10 = 30
+            25, 5, // Temp = 25.5
+            50, 2, // Hum = 50.2
+        ];
+        let checksum = calculate_checksum(&data);
+        data.push(checksum);
+        data
+    }
+
+    #[test]
+    fn test_fields() {
+        let data = parse_frame(&valid_frame()).unwrap();
+        let fields = data.fields();
+        assert_eq!(fields[0], ("eco2", 400.0));
+        assert_eq!(fields[6], ("humidity", 50.2));
+        assert_eq!(fields[6].1.to_string(), "50.2");
+    }
+
+    #[test]
+    fn test_calculate_checksum() {
+        let data = vec![
+            0x3C, 0x02, 0x00, 0x01, 0x00, 0x01, 0x00, 0x01, 0x00, 0x01, 0x00, 0x01, 10, 5, 20, 5,
+        ];
+        let checksum = calculate_checksum(&data);
+        assert_eq!(checksum, 107);
+    }
+
+    #[test]
+    fn test_parse_frame_valid() {
+        let data = valid_frame();
+
+        let result = parse_frame(&data);
+        assert!(result.is_ok());
+        let sensor_data = result.unwrap();
+
+        assert_eq!(sensor_data.eco2, 400);
+        assert_eq!(sensor_data.ech2o, 5);
+        assert_eq!(sensor_data.tvoc, 10);
+        assert_eq!(sensor_data.pm2_5, 20);
+        assert_eq!(sensor_data.pm10, 30);
+        assert_eq!(sensor_data.temperature, 25.5);
+        assert_eq!(sensor_data.humidity, 50.2);
+    }
+
+    #[test]
+    fn test_parse_frame_out_of_range() {
+        let mut data = valid_frame();
+        data[13] = 12; // temperature decimal digit
+        data[16] = calculate_checksum(&data[..16]);
+        assert_eq!(
+            parse_frame(&data),
+            Err(FrameError::OutOfRange {
+                field: "temperature",
+                value: 12.0,
+            })
+        );
+
+        let mut data = valid_frame();
+        data[14] = 101; // humidity integer part
+        data[16] = calculate_checksum(&data[..16]);
+        assert_eq!(
+            parse_frame(&data),
+            Err(FrameError::OutOfRange {
+                field: "humidity",
+                value: 101.2,
+            })
+        );
+    }
+
+    #[test]
+    fn test_parse_frame_errors() {
+        let data = valid_frame();
+        assert_eq!(
+            parse_frame(&data[..10]),
+            Err(FrameError::TooShort { len: 10 })
+        );
+
+        let mut bad_header = data.clone();
+        bad_header[1] = 0x03;
+        assert_eq!(
+            parse_frame(&bad_header),
+            Err(FrameError::BadHeader(0x3C, 0x03))
+        );
+
+        let mut bad_sum = data.clone();
+        bad_sum[16] = bad_sum[16].wrapping_add(1);
+        assert_eq!(
+            parse_frame(&bad_sum),
+            Err(FrameError::ChecksumMismatch {
+                expected: data[16],
+                actual: data[16].wrapping_add(1),
+            })
+        );
+    }
+
+    #[test]
+    fn test_encode_frame() {
+        let data = valid_frame();
+        let decoded = parse_frame(&data).unwrap();
+        assert_eq!(encode_frame(&decoded).as_slice(), data.as_slice());
+
+        let clamped = encode_frame(&SensorData {
+            temperature: -3.0,
+            humidity: 99.96,
+            ..decoded
+        });
+        assert_eq!(&clamped[12..16], &[0, 0, 100, 0]);
+    }
+}
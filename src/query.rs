@@ -0,0 +1,190 @@
+//! The `query` subcommand: reads the SQLite history back out.
+
+use anyhow::{Result, bail};
+use chrono::{DateTime, NaiveDate, TimeDelta, Utc};
+use clap::{Args, ValueEnum};
+use sensor_reader::FIELD_NAMES;
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+use std::path::PathBuf;
+use std::time::Duration;
+
+use crate::config::Config;
+use crate::sinks::file::{CsvColumns, csv_header, csv_row, csv_value};
+use crate::sinks::sqlite::{Bucket, Filter, History};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum ExportFormat {
+    Csv,
+    Json,
+}
+
+#[derive(Args, Debug)]
+pub struct QueryArgs {
+    /// Start of the range (inclusive): RFC 3339, YYYY-MM-DD or relative to
+    /// now, e.g. -24h
+    #[arg(long, value_parser = parse_time, allow_hyphen_values = true)]
+    from: Option<DateTime<Utc>>,
+
+    /// End of the range (exclusive), in the same formats as --from
+    #[arg(long, value_parser = parse_time, allow_hyphen_values = true)]
+    to: Option<DateTime<Utc>>,
+
+    /// Only include readings from this device
+    #[arg(long)]
+    device: Option<String>,
+
+    /// Summarize every field as min/avg/max per interval, e.g. 15m, 1h or 1d
+    #[arg(long, value_parser = parse_duration)]
+    interval: Option<Duration>,
+
+    #[arg(long, value_enum, default_value_t = ExportFormat::Csv)]
+    format: ExportFormat,
+
+    /// Write to this file instead of standard output
+    #[arg(short, long)]
+    output: Option<PathBuf>,
+
+    /// Database to read [default: sinks.sqlite.path]
+    #[arg(long)]
+    db: Option<PathBuf>,
+}
+
+/// Parses a duration such as `90s`, `15m`, `1h` or `7d`.
+pub fn parse_duration(s: &str) -> Result<Duration, String> {
+    let invalid = || format!("invalid duration '{}', expected e.g. 15m", s);
+    let split = s.find(|c: char| !c.is_ascii_digit()).unwrap_or(s.len());
+    let (digits, unit) = s.split_at(split);
+    let n: u64 = digits.parse().map_err(|_| invalid())?;
+    let scale = match unit {
+        "s" => 1,
+        "m" => 60,
+        "h" => 3600,
+        "d" => 86_400,
+        _ => {
+            return Err(format!(
+                "invalid duration '{}', expected a unit of s, m, h or d",
+                s
+            ));
+        }
+    };
+    let secs = n.checked_mul(scale).ok_or_else(invalid)?;
+    if secs == 0 {
+        return Err("duration must not be zero".to_string());
+    }
+    Ok(Duration::from_secs(secs))
+}
+
+fn parse_time(s: &str) -> Result<DateTime<Utc>, String> {
+    if let Some(ago) = s.strip_prefix('-') {
+        return TimeDelta::from_std(parse_duration(ago)?)
+            .ok()
+            .and_then(|ago| Utc::now().checked_sub_signed(ago))
+            .ok_or_else(|| format!("invalid duration '{}', expected e.g. 15m", ago));
+    }
+    if let Ok(time) = DateTime::parse_from_rfc3339(s) {
+        return Ok(time.to_utc());
+    }
+    match NaiveDate::parse_from_str(s, "%Y-%m-%d") {
+        Ok(date) => Ok(date.and_time(Default::default()).and_utc()),
+        Err(_) => Err(format!(
+            "invalid time '{}', expected RFC 3339, YYYY-MM-DD or e.g. -24h",
+            s
+        )),
+    }
+}
+
+fn write_buckets_csv(out: &mut dyn Write, buckets: &[Bucket]) -> io::Result<()> {
+    write!(out, "start,device_id,count")?;
+    for name in FIELD_NAMES {
+        write!(out, ",{name}_min,{name}_avg,{name}_max")?;
+    }
+    writeln!(out)?;
+
+    for bucket in buckets {
+        write!(
+            out,
+            "{},{},{}",
+            bucket.start.to_rfc3339(),
+            csv_value(&bucket.device_id),
+            bucket.count
+        )?;
+        for name in FIELD_NAMES {
+            let summary = &bucket.fields[name];
+            write!(out, ",{},{},{}", summary.min, summary.avg, summary.max)?;
+        }
+        writeln!(out)?;
+    }
+    Ok(())
+}
+
+pub fn run(config: &Config, args: &QueryArgs) -> Result<()> {
+    let path = args.db.as_ref().unwrap_or(&config.sinks.sqlite.path);
+    if !path.exists() {
+        bail!("No history database at '{}'", path.display());
+    }
+    let history = History::open_read_only(path)?;
+    let filter = Filter {
+        from: args.from,
+        to: args.to,
+        device_id: args.device.clone(),
+    };
+
+    let mut out: Box<dyn Write> = match &args.output {
+        Some(path) => Box::new(BufWriter::new(File::create(path)?)),
+        None => Box::new(BufWriter::new(io::stdout().lock())),
+    };
+    match (args.interval, args.format) {
+        (Some(interval), ExportFormat::Csv) => {
+            write_buckets_csv(&mut out, &history.aggregate(&filter, interval)?)?;
+        }
+        (Some(interval), ExportFormat::Json) => {
+            serde_json::to_writer_pretty(&mut out, &history.aggregate(&filter, interval)?)?;
+            writeln!(out)?;
+        }
+        (None, ExportFormat::Csv) => {
+            let readings = history.readings(&filter)?;
+            let columns = CsvColumns::of(&readings);
+            out.write_all(csv_header(&columns).as_bytes())?;
+            for reading in &readings {
+                out.write_all(csv_row(reading, &columns).as_bytes())?;
+            }
+        }
+        (None, ExportFormat::Json) => {
+            serde_json::to_writer_pretty(&mut out, &history.readings(&filter)?)?;
+            writeln!(out)?;
+        }
+    }
+    out.flush()?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_duration() {
+        assert_eq!(parse_duration("90s"), Ok(Duration::from_secs(90)));
+        assert_eq!(parse_duration("15m"), Ok(Duration::from_secs(900)));
+        assert_eq!(parse_duration("1d"), Ok(Duration::from_secs(86_400)));
+        assert!(parse_duration("15").is_err());
+        assert!(parse_duration("0h").is_err());
+        assert!(parse_duration("h").is_err());
+        assert!(parse_duration("99999999999999999d").is_err());
+    }
+
+    #[test]
+    fn test_parse_time() {
+        let expected = DateTime::parse_from_rfc3339("2024-05-01T00:00:00Z")
+            .unwrap()
+            .to_utc();
+        assert_eq!(parse_time("2024-05-01"), Ok(expected));
+        assert_eq!(parse_time("2024-05-01T02:00:00+02:00"), Ok(expected));
+
+        let ago = Utc::now() - parse_time("-1h").unwrap();
+        assert!((3599..=3601).contains(&ago.num_seconds()));
+        assert!(parse_time("yesterday").is_err());
+        assert!(parse_time("-9999999999d").is_err());
+    }
+}
@@ -0,0 +1,222 @@
+use serde::{Deserialize, Serialize};
+use std::collections::VecDeque;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Condvar, Mutex, MutexGuard};
+use std::time::{Duration, Instant};
+
+/// What to do when a reading arrives and the queue is already full.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum OverflowPolicy {
+    /// Discard the oldest queued reading to make room.
+    DropOldest,
+    /// Discard the incoming reading.
+    DropNewest,
+    /// Wait until the consumer frees a slot.
+    Block,
+}
+
+/// Bounded multi-producer, multi-consumer queue between the serial reader
+/// and a sink worker.
+pub struct BoundedQueue<T> {
+    state: Mutex<State<T>>,
+    not_empty: Condvar,
+    not_full: Condvar,
+    capacity: usize,
+    policy: OverflowPolicy,
+    dropped: AtomicU64,
+}
+
+struct State<T> {
+    items: VecDeque<T>,
+    closed: bool,
+}
+
+impl<T> BoundedQueue<T> {
+    pub fn new(capacity: usize, policy: OverflowPolicy) -> Self {
+        let capacity = capacity.max(1);
+        Self {
+            state: Mutex::new(State {
+                items: VecDeque::with_capacity(capacity),
+                closed: false,
+            }),
+            not_empty: Condvar::new(),
+            not_full: Condvar::new(),
+            capacity,
+            policy,
+            dropped: AtomicU64::new(0),
+        }
+    }
+
+    fn lock(&self) -> MutexGuard<'_, State<T>> {
+        self.state.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    /// Enqueues `item`, applying the overflow policy if the queue is full.
+    ///
+    /// Returns `false` if a reading had to be dropped (either `item` or an
+    /// older one) or the queue has been closed.
+    pub fn push(&self, item: T) -> bool {
+        let mut state = self.lock();
+        if state.closed {
+            return false;
+        }
+
+        let mut accepted = true;
+        if state.items.len() >= self.capacity {
+            match self.policy {
+                OverflowPolicy::DropOldest => {
+                    state.items.pop_front();
+                    self.dropped.fetch_add(1, Ordering::Relaxed);
+                    accepted = false;
+                }
+                OverflowPolicy::DropNewest => {
+                    self.dropped.fetch_add(1, Ordering::Relaxed);
+                    return false;
+                }
+                OverflowPolicy::Block => {
+                    while state.items.len() >= self.capacity && !state.closed {
+                        state = self.not_full.wait(state).unwrap_or_else(|e| e.into_inner());
+                    }
+                    if state.closed {
+                        return false;
+                    }
+                }
+            }
+        }
+
+        state.items.push_back(item);
+        self.not_empty.notify_one();
+        accepted
+    }
+
+    /// Dequeues the oldest item, waiting for one to arrive.
+    ///
+    /// Returns `None` once the queue is closed and empty.
+    pub fn pop(&self) -> Option<T> {
+        let mut state = self.lock();
+        loop {
+            if let Some(item) = state.items.pop_front() {
+                self.not_full.notify_one();
+                return Some(item);
+            }
+            if state.closed {
+                return None;
+            }
+            state = self
+                .not_empty
+                .wait(state)
+                .unwrap_or_else(|e| e.into_inner());
+        }
+    }
+
+    /// Dequeues the oldest item, waiting up to `timeout` for one to arrive.
+    ///
+    /// Returns `None` on timeout or once the queue is closed and empty; use
+    /// [`is_closed`](Self::is_closed) to tell the two apart.
+    pub fn pop_timeout(&self, timeout: Duration) -> Option<T> {
+        let deadline = Instant::now() + timeout;
+        let mut state = self.lock();
+        loop {
+            if let Some(item) = state.items.pop_front() {
+                self.not_full.notify_one();
+                return Some(item);
+            }
+            let now = Instant::now();
+            if state.closed || now >= deadline {
+                return None;
+            }
+            state = self
+                .not_empty
+                .wait_timeout(state, deadline - now)
+                .unwrap_or_else(|e| e.into_inner())
+                .0;
+        }
+    }
+
+    /// Returns `true` once [`close`](Self::close) has been called.
+    pub fn is_closed(&self) -> bool {
+        self.lock().closed
+    }
+
+    /// Stops accepting new items. Items already queued can still be popped.
+    pub fn close(&self) {
+        self.lock().closed = true;
+        self.not_empty.notify_all();
+        self.not_full.notify_all();
+    }
+
+    /// Number of items currently waiting.
+    pub fn len(&self) -> usize {
+        self.lock().items.len()
+    }
+
+    /// Total number of items discarded by the overflow policy.
+    pub fn dropped(&self) -> u64 {
+        self.dropped.load(Ordering::Relaxed)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Arc;
+    use std::thread;
+    use std::time::Duration;
+
+    fn drain(queue: &BoundedQueue<u32>) -> Vec<u32> {
+        queue.close();
+        std::iter::from_fn(|| queue.pop_timeout(Duration::ZERO)).collect()
+    }
+
+    #[test]
+    fn test_drop_oldest() {
+        let queue = BoundedQueue::new(2, OverflowPolicy::DropOldest);
+        assert!(queue.push(1));
+        assert!(queue.push(2));
+        assert!(!queue.push(3));
+        assert_eq!(queue.dropped(), 1);
+        assert_eq!(drain(&queue), vec![2, 3]);
+    }
+
+    #[test]
+    fn test_drop_newest() {
+        let queue = BoundedQueue::new(2, OverflowPolicy::DropNewest);
+        assert!(queue.push(1));
+        assert!(queue.push(2));
+        assert!(!queue.push(3));
+        assert_eq!(queue.dropped(), 1);
+        assert_eq!(drain(&queue), vec![1, 2]);
+    }
+
+    #[test]
+    fn test_pop_timeout() {
+        let queue = BoundedQueue::new(2, OverflowPolicy::Block);
+        assert_eq!(queue.pop_timeout(Duration::from_millis(10)), None);
+        assert!(!queue.is_closed());
+
+        queue.push(1);
+        queue.close();
+        assert_eq!(queue.pop_timeout(Duration::from_millis(10)), Some(1));
+        assert_eq!(queue.pop_timeout(Duration::from_millis(10)), None);
+        assert!(queue.is_closed());
+    }
+
+    #[test]
+    fn test_block_waits_for_consumer() {
+        let queue = Arc::new(BoundedQueue::new(1, OverflowPolicy::Block));
+        assert!(queue.push(1));
+
+        let producer = {
+            let queue = Arc::clone(&queue);
+            thread::spawn(move || queue.push(2))
+        };
+        thread::sleep(Duration::from_millis(50));
+        assert_eq!(queue.len(), 1);
+
+        assert_eq!(queue.pop_timeout(Duration::ZERO), Some(1));
+        assert!(producer.join().unwrap());
+        assert_eq!(queue.dropped(), 0);
+        assert_eq!(drain(&queue), vec![2]);
+    }
+}
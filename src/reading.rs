@@ -0,0 +1,208 @@
+//! Decoded measurements annotated with capture time and device identity.
+
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::hash::{BuildHasher, Hasher, RandomState};
+
+use crate::aggregate::Window;
+use crate::aqi::Aqi;
+use crate::comfort::Comfort;
+use crate::protocol::SensorData;
+
+/// Version of the [`Reading`] JSON schema, bumped on incompatible changes.
+///
+/// Derived metrics, air quality indices, aggregation windows and the session
+/// were added as optional keys within version 1: they are left out when
+/// unset, and payloads without them still deserialize.
+pub const SCHEMA_VERSION: u32 = 1;
+
+/// Identifies the sensor a reading came from.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct DeviceInfo {
+    pub id: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub location: Option<String>,
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub tags: BTreeMap<String, String>,
+}
+
+/// A measurement as it is sent to sinks.
+///
+/// The measurement fields are flattened into the top level, so consumers of
+/// the plain [`SensorData`] JSON keep working.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Reading {
+    pub schema_version: u32,
+    /// When the frame was decoded, serialized as RFC 3339.
+    pub captured_at: DateTime<Utc>,
+    /// Position of this reading in the stream, starting at 0 when the
+    /// [`Stamper`] is created, so it only orders readings of one `session`.
+    pub seq: u64,
+    /// Identifies the [`Stamper`] that stamped this reading, a new one every
+    /// time the reader starts. Readings are unique by device id, session and
+    /// `seq`, including those replayed from an outbox after a restart.
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    pub session: String,
+    pub device: DeviceInfo,
+    #[serde(flatten)]
+    pub data: SensorData,
+    /// Derived metrics, flattened next to the measurements they come from.
+    #[serde(flatten)]
+    pub comfort: Comfort,
+    /// Air quality indices, if any are configured.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub aqi: Vec<Aqi>,
+    /// Set on summaries of several readings, see
+    /// [`Aggregator`](crate::Aggregator).
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub window: Option<Window>,
+}
+
+impl Reading {
+    /// Returns the field name and index of every air quality index, for
+    /// exporters that flatten them next to the measurements.
+    pub fn aqi_fields(&self) -> Vec<(&'static str, f64)> {
+        self.aqi
+            .iter()
+            .map(|aqi| (aqi.standard.field(), f64::from(aqi.index)))
+            .collect()
+    }
+}
+
+/// Turns decoded frames into [`Reading`]s for one device.
+#[derive(Debug, Clone)]
+pub struct Stamper {
+    device: DeviceInfo,
+    session: String,
+    next_seq: u64,
+}
+
+impl Stamper {
+    /// Starts a new session for `device`, numbering its readings from 0.
+    pub fn new(device: DeviceInfo) -> Self {
+        // RandomState is keyed from the OS random source, and differently
+        // for every instance, which makes the empty hash a random number
+        let session = format!("{:016x}", RandomState::new().build_hasher().finish());
+        Self {
+            device,
+            session,
+            next_seq: 0,
+        }
+    }
+
+    pub fn device(&self) -> &DeviceInfo {
+        &self.device
+    }
+
+    /// Random id of this stamper's session, 16 hex digits.
+    pub fn session(&self) -> &str {
+        &self.session
+    }
+
+    /// Wraps `data` in a [`Reading`] captured now.
+    pub fn stamp(&mut self, data: SensorData) -> Reading {
+        self.stamp_at(data, Utc::now())
+    }
+
+    /// Wraps `data` in a [`Reading`] captured at `captured_at`.
+    pub fn stamp_at(&mut self, data: SensorData, captured_at: DateTime<Utc>) -> Reading {
+        let seq = self.next_seq;
+        self.next_seq += 1;
+        Reading {
+            schema_version: SCHEMA_VERSION,
+            captured_at,
+            seq,
+            session: self.session.clone(),
+            device: self.device.clone(),
+            data,
+            comfort: Comfort::default(),
+            aqi: Vec::new(),
+            window: None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn data() -> SensorData {
+        SensorData {
+            eco2: 400,
+            ech2o: 5,
+            tvoc: 10,
+            pm2_5: 20,
+            pm10: 30,
+            temperature: 25.5,
+            humidity: 50.2,
+        }
+    }
+
+    #[test]
+    fn test_stamp_json() {
+        let mut stamper = Stamper::new(DeviceInfo {
+            id: "m701-lab".into(),
+            location: Some("lab".into()),
+            tags: BTreeMap::from([("floor".into(), "2".into())]),
+        });
+        let at = DateTime::parse_from_rfc3339("2024-05-01T12:00:00Z")
+            .unwrap()
+            .to_utc();
+
+        assert_eq!(stamper.stamp_at(data(), at).seq, 0);
+        let reading = stamper.stamp_at(data(), at);
+        assert_eq!(reading.seq, 1);
+        assert_eq!(reading.session.len(), 16);
+        // Every restart starts a new session
+        assert_ne!(
+            Stamper::new(DeviceInfo::default()).session(),
+            reading.session
+        );
+
+        let json = serde_json::to_value(&reading).unwrap();
+        assert_eq!(json["schema_version"], SCHEMA_VERSION);
+        assert_eq!(json["captured_at"], "2024-05-01T12:00:00Z");
+        assert_eq!(json["session"], stamper.session());
+        assert_eq!(json["device"]["id"], "m701-lab");
+        assert_eq!(json["device"]["tags"]["floor"], "2");
+        assert_eq!(json["eco2"], 400);
+
+        let back: Reading = serde_json::from_value(json).unwrap();
+        assert_eq!(back, reading);
+    }
+
+    #[test]
+    fn test_plain_payload_deserializes() {
+        // As written before derived metrics, AQI and windows existed
+        let json = r#"{
+            "schema_version": 1,
+            "captured_at": "2024-05-01T12:00:00Z",
+            "seq": 7,
+            "device": {"id": "m701"},
+            "eco2": 400, "ech2o": 5, "tvoc": 10, "pm2_5": 20, "pm10": 30,
+            "temperature": 25.5, "humidity": 50.2
+        }"#;
+        let reading: Reading = serde_json::from_str(json).unwrap();
+        assert_eq!(reading.seq, 7);
+        assert_eq!(reading.session, "");
+        assert_eq!(reading.data, data());
+        assert_eq!(reading.comfort, Comfort::default());
+        assert!(reading.aqi.is_empty());
+        assert_eq!(reading.window, None);
+
+        // And a plain reading still serializes to that shape
+        let json = serde_json::to_value(&reading).unwrap();
+        let keys: Vec<&str> = json
+            .as_object()
+            .unwrap()
+            .keys()
+            .map(|k| k.as_str())
+            .collect();
+        assert!(
+            !keys
+                .iter()
+                .any(|k| ["session", "dew_point", "aqi", "window"].contains(k))
+        );
+    }
+}
@@ -0,0 +1,375 @@
+use anyhow::{Context, Result, bail};
+use log::{debug, info, warn};
+use sensor_reader::{DecodeEvent, FrameDecoder};
+use serialport::{SerialPort, SerialPortInfo, SerialPortType, UsbPortInfo};
+use std::sync::atomic::AtomicBool;
+use std::thread;
+use std::time::{Duration, Instant};
+use thiserror::Error;
+
+use crate::config::SerialConfig;
+use crate::retry::{RetryPolicy, sleep_unless_stopped};
+
+/// How long a single read may block before returning a timeout.
+const READ_TIMEOUT: Duration = Duration::from_millis(1000);
+
+/// Returns `true` if `usb` matches every USB identity set in `config`.
+fn usb_matches(config: &SerialConfig, usb: &UsbPortInfo) -> bool {
+    config.usb_vid.is_none_or(|vid| vid == usb.vid)
+        && config.usb_pid.is_none_or(|pid| pid == usb.pid)
+        && config
+            .usb_serial
+            .as_ref()
+            .is_none_or(|serial| usb.serial_number.as_ref() == Some(serial))
+}
+
+/// Value of `serial.port` that probes for the sensor.
+const AUTO: &str = "auto";
+
+const PORT_NOT_SET: &str = "serial.port is not set; use --port, SENSOR_PORT or the [serial] section of the config file, or --port auto to search for the sensor";
+
+/// Why the port could not be opened, and whether reconnecting can help.
+#[derive(Debug, Error)]
+pub enum OpenError {
+    /// The configuration can never work, however often it is retried.
+    #[error("{0}")]
+    Config(String),
+
+    /// The device is missing, busy or failed; it may come back.
+    #[error("{0}")]
+    Unavailable(String),
+}
+
+/// Returns `true` if frames from the sensor arrive on `name` within
+/// `timeout`.
+fn sends_frames(name: &str, baud_rate: u32, timeout: Duration) -> bool {
+    let deadline = Instant::now() + timeout;
+    let mut port = match serialport::new(name, baud_rate)
+        .timeout(Duration::from_millis(100))
+        .open()
+    {
+        Ok(port) => port,
+        Err(e) => {
+            debug!("Not probing {}: {}", name, e);
+            return false;
+        }
+    };
+    let mut decoder = FrameDecoder::new();
+    let mut buf = [0; 256];
+    while Instant::now() < deadline {
+        match port.read(&mut buf) {
+            Ok(n) => {
+                if decoder
+                    .feed(&buf[..n])
+                    .any(|event| matches!(event, DecodeEvent::Frame(_)))
+                {
+                    return true;
+                }
+            }
+            Err(e) if e.kind() == std::io::ErrorKind::TimedOut => {}
+            Err(e) => {
+                debug!("Stopped probing {}: {}", name, e);
+                return false;
+            }
+        }
+    }
+    false
+}
+
+/// Listens on all `names` at once and returns those that sent a valid frame
+/// within `timeout`, in the given order.
+fn probe(names: &[String], baud_rate: u32, timeout: Duration) -> Vec<String> {
+    thread::scope(|scope| {
+        let probes: Vec<_> = names
+            .iter()
+            .map(|name| scope.spawn(move || sends_frames(name, baud_rate, timeout)))
+            .collect();
+        names
+            .iter()
+            .zip(probes)
+            .filter_map(|(name, probe)| matches!(probe.join(), Ok(true)).then(|| name.clone()))
+            .collect()
+    })
+}
+
+fn find_sensor(config: &SerialConfig) -> Result<String> {
+    let names: Vec<String> = serialport::available_ports()
+        .context("Failed to enumerate serial ports")?
+        .into_iter()
+        .map(|p| p.port_name)
+        .collect();
+    if names.is_empty() {
+        bail!("No serial ports found to probe");
+    }
+
+    let timeout = Duration::from_millis(config.probe_timeout_ms);
+    info!(
+        "Probing {} for M701 frames for {:.1}s",
+        names.join(", "),
+        timeout.as_secs_f32()
+    );
+    let mut found = probe(&names, config.baud_rate, timeout).into_iter();
+    let Some(name) = found.next() else {
+        bail!(
+            "No M701 frames on any serial port within {:.1}s; check the wiring or set serial.port",
+            timeout.as_secs_f32()
+        );
+    };
+    let others: Vec<String> = found.collect();
+    if !others.is_empty() {
+        warn!(
+            "Frames also arrive on {}; set serial.port to choose",
+            others.join(", ")
+        );
+    }
+    info!("Found the sensor on {}", name);
+    Ok(name)
+}
+
+/// Finds the port to open.
+///
+/// If a USB identity is configured, the port is looked up by it on every call,
+/// since the tty name can change when the device is plugged back in. With
+/// `port = "auto"`, every port is probed for frames on every call. Otherwise
+/// the configured port name is used as is.
+pub fn resolve_port(config: &SerialConfig) -> Result<String, OpenError> {
+    if !config.matches_by_usb() {
+        return match config.port.as_deref() {
+            Some(AUTO) => {
+                find_sensor(config).map_err(|e| OpenError::Unavailable(format!("{:#}", e)))
+            }
+            Some(port) => Ok(port.to_string()),
+            None => Err(OpenError::Config(PORT_NOT_SET.to_string())),
+        };
+    }
+
+    let ports = serialport::available_ports()
+        .map_err(|e| OpenError::Unavailable(format!("Failed to enumerate serial ports: {}", e)))?;
+    let found = ports.into_iter().find(|p| match &p.port_type {
+        SerialPortType::UsbPort(usb) => usb_matches(config, usb),
+        _ => false,
+    });
+    match found {
+        Some(port) => Ok(port.port_name),
+        None => Err(OpenError::Unavailable(
+            "No USB serial port matches the configured VID/PID/serial number".to_string(),
+        )),
+    }
+}
+
+/// Fails if the configuration names no port at all, so `run` reports it
+/// right away instead of waiting for the port to appear.
+pub fn check_port(config: &SerialConfig) -> Result<()> {
+    if config.port.is_none() && !config.matches_by_usb() {
+        bail!(PORT_NOT_SET);
+    }
+    Ok(())
+}
+
+/// Describes the hardware behind a port for `list-ports`: its type,
+/// VID:PID, serial number and product name.
+fn describe(port: &SerialPortInfo) -> [String; 4] {
+    let kind = match &port.port_type {
+        SerialPortType::UsbPort(_) => "usb",
+        SerialPortType::PciPort => "pci",
+        SerialPortType::BluetoothPort => "bluetooth",
+        SerialPortType::Unknown => "",
+    };
+    let mut columns = [
+        kind.to_string(),
+        String::new(),
+        String::new(),
+        String::new(),
+    ];
+    if let SerialPortType::UsbPort(usb) = &port.port_type {
+        columns[1] = format!("{:04x}:{:04x}", usb.vid, usb.pid);
+        columns[2] = usb.serial_number.clone().unwrap_or_default();
+        columns[3] = [usb.manufacturer.as_deref(), usb.product.as_deref()]
+            .into_iter()
+            .flatten()
+            .collect::<Vec<_>>()
+            .join(" ");
+    }
+    columns
+}
+
+/// Prints every serial port with its USB identity, for `list-ports`.
+pub fn list_ports() -> Result<()> {
+    let mut ports = serialport::available_ports().context("Failed to enumerate serial ports")?;
+    if ports.is_empty() {
+        println!("No serial ports found");
+        return Ok(());
+    }
+    ports.sort_by(|a, b| a.port_name.cmp(&b.port_name));
+
+    let mut rows = vec![[
+        "PORT".to_string(),
+        "TYPE".to_string(),
+        "VID:PID".to_string(),
+        "SERIAL".to_string(),
+        "PRODUCT".to_string(),
+    ]];
+    for port in &ports {
+        let [kind, ids, serial, product] = describe(port);
+        rows.push([port.port_name.clone(), kind, ids, serial, product]);
+    }
+    let mut widths = [0; 4];
+    for row in &rows {
+        for (width, cell) in widths.iter_mut().zip(row) {
+            *width = (*width).max(cell.chars().count());
+        }
+    }
+    for row in &rows {
+        let mut line = String::new();
+        for (width, cell) in widths.iter().zip(row) {
+            line.push_str(&format!("{:<width$}  ", cell, width = width));
+        }
+        line.push_str(&row[4]);
+        println!("{}", line.trim_end());
+    }
+    Ok(())
+}
+
+fn open(config: &SerialConfig) -> Result<(String, Box<dyn SerialPort>), OpenError> {
+    let name = resolve_port(config)?;
+    match serialport::new(&name, config.baud_rate)
+        .timeout(READ_TIMEOUT)
+        .open()
+    {
+        Ok(port) => Ok((name, port)),
+        Err(e) => {
+            let message = format!("Failed to open port '{}': {}", name, e);
+            // Settings the driver refuses, such as the baud rate
+            if e.kind == serialport::ErrorKind::InvalidInput {
+                Err(OpenError::Config(message))
+            } else {
+                Err(OpenError::Unavailable(message))
+            }
+        }
+    }
+}
+
+/// Opens the configured port, or returns `None` if `stop` is set first.
+///
+/// With reconnect enabled, failures that may go away, such as a missing
+/// device, are retried with backoff until the port can be opened. Errors in
+/// the configuration are always returned at once.
+pub fn connect(config: &SerialConfig, stop: &AtomicBool) -> Result<Option<Box<dyn SerialPort>>> {
+    let backoff = RetryPolicy {
+        max_attempts: u32::MAX,
+        initial_delay: Duration::from_millis(config.reconnect.initial_delay_ms),
+        max_delay: Duration::from_millis(config.reconnect.max_delay_ms),
+    };
+
+    let mut attempt = 1;
+    loop {
+        match open(config) {
+            Ok((name, port)) => {
+                info!("Opened port {} at {} baud", name, config.baud_rate);
+                return Ok(Some(port));
+            }
+            Err(OpenError::Unavailable(e)) if config.reconnect.enabled => {
+                let delay = backoff.delay(attempt);
+                warn!(
+                    "{}; retrying in {:.1}s (attempt {})",
+                    e,
+                    delay.as_secs_f32(),
+                    attempt
+                );
+                if !sleep_unless_stopped(delay, stop) {
+                    return Ok(None);
+                }
+                attempt = attempt.saturating_add(1);
+            }
+            Err(e) => return Err(e.into()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn usb(vid: u16, pid: u16, serial: Option<&str>) -> UsbPortInfo {
+        UsbPortInfo {
+            vid,
+            pid,
+            serial_number: serial.map(str::to_string),
+            manufacturer: None,
+            product: None,
+        }
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_probe() {
+        use sensor_reader::{SensorData, encode_frame};
+        use serialport::TTYPort;
+        use std::io::Write;
+
+        let (mut sensor, sensor_slave) = TTYPort::pair().unwrap();
+        let (mut noise, noise_slave) = TTYPort::pair().unwrap();
+        let names = vec![noise_slave.name().unwrap(), sensor_slave.name().unwrap()];
+        let frame = encode_frame(&SensorData {
+            eco2: 400,
+            ech2o: 5,
+            tvoc: 10,
+            pm2_5: 20,
+            pm10: 30,
+            temperature: 25.5,
+            humidity: 50.2,
+        });
+        let writer = thread::spawn(move || {
+            for _ in 0..10 {
+                sensor.write_all(&frame).unwrap();
+                noise.write_all(&[0x3c, 0x02, 0x00, 0xff]).unwrap();
+                thread::sleep(Duration::from_millis(50));
+            }
+        });
+
+        let found = probe(&names, 9600, Duration::from_secs(2));
+        writer.join().unwrap();
+        assert_eq!(found, [names[1].clone()]);
+    }
+
+    #[test]
+    fn test_usb_matches() {
+        let config = SerialConfig {
+            usb_vid: Some(0x1a86),
+            usb_pid: Some(0x7523),
+            ..SerialConfig::default()
+        };
+        assert!(usb_matches(&config, &usb(0x1a86, 0x7523, None)));
+        assert!(usb_matches(&config, &usb(0x1a86, 0x7523, Some("A1"))));
+        assert!(!usb_matches(&config, &usb(0x1a86, 0x55d4, None)));
+
+        let config = SerialConfig {
+            usb_serial: Some("A1".into()),
+            ..config
+        };
+        assert!(usb_matches(&config, &usb(0x1a86, 0x7523, Some("A1"))));
+        assert!(!usb_matches(&config, &usb(0x1a86, 0x7523, Some("B2"))));
+        assert!(!usb_matches(&config, &usb(0x1a86, 0x7523, None)));
+    }
+
+    #[test]
+    fn test_config_errors_are_not_retried() {
+        let config = SerialConfig::default();
+        assert!(config.reconnect.enabled);
+        assert!(check_port(&config).is_err());
+        // Returns at once instead of retrying forever
+        let err = connect(&config, &AtomicBool::new(false)).err().unwrap();
+        assert!(
+            err.to_string().starts_with("serial.port is not set"),
+            "{}",
+            err
+        );
+
+        let config = SerialConfig {
+            port: Some("/dev/does-not-exist".into()),
+            ..config
+        };
+        assert!(check_port(&config).is_ok());
+        assert!(matches!(open(&config), Err(OpenError::Unavailable(_))));
+    }
+}
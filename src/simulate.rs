@@ -0,0 +1,320 @@
+//! The `simulate` subcommand: an M701 on a pseudo-terminal, for running the
+//! reader without hardware.
+
+use anyhow::{Context, Result, bail};
+use clap::Args;
+use log::{debug, info};
+use sensor_reader::{FRAME_LEN, SensorData, encode_frame};
+use serialport::{SerialPort, TTYPort};
+use std::fs::{self, File};
+use std::io::{BufRead, BufReader, Write};
+use std::os::unix::fs::symlink;
+use std::path::{Path, PathBuf};
+use std::thread;
+use std::time::Duration;
+
+#[derive(Args, Debug)]
+pub struct SimulateArgs {
+    /// Time between frames, in milliseconds
+    #[arg(long, default_value_t = 1000)]
+    interval_ms: u64,
+
+    /// Stop after this many frames instead of running until killed
+    #[arg(long)]
+    count: Option<u64>,
+
+    /// Send the readings in this file in order, repeating at the end, instead
+    /// of random values. One JSON object per line with the SensorData fields,
+    /// such as a JSONL recording
+    #[arg(long)]
+    script: Option<PathBuf>,
+
+    /// Seed for random values and faults, to make a run repeatable
+    #[arg(long)]
+    seed: Option<u64>,
+
+    /// Also make the terminal available under this path
+    #[arg(long)]
+    link: Option<PathBuf>,
+
+    #[command(flatten)]
+    faults: Faults,
+}
+
+/// How often each fault is injected, as a fraction of frames.
+#[derive(Args, Debug, Clone, Default)]
+pub struct Faults {
+    /// Fraction of frames sent with a wrong checksum
+    #[arg(long, default_value_t = 0.0, value_parser = parse_rate)]
+    bad_checksum: f64,
+
+    /// Fraction of frames cut off before the end
+    #[arg(long, default_value_t = 0.0, value_parser = parse_rate)]
+    truncate: f64,
+
+    /// Fraction of frames preceded by random bytes
+    #[arg(long, default_value_t = 0.0, value_parser = parse_rate)]
+    garbage: f64,
+
+    /// Fraction of frames followed by a pause of --pause-ms
+    #[arg(long, default_value_t = 0.0, value_parser = parse_rate)]
+    pause: f64,
+
+    #[arg(long, default_value_t = 5000)]
+    pause_ms: u64,
+}
+
+fn parse_rate(s: &str) -> Result<f64, String> {
+    match s.parse::<f64>() {
+        Ok(rate) if (0.0..=1.0).contains(&rate) => Ok(rate),
+        _ => Err(format!("invalid rate '{}', expected 0 to 1", s)),
+    }
+}
+
+/// Where the simulated measurements come from.
+enum Values {
+    Script {
+        readings: Vec<SensorData>,
+        next: usize,
+    },
+    Random(SensorData),
+}
+
+impl Values {
+    fn load_script(path: &Path) -> Result<Self> {
+        let file = File::open(path)
+            .with_context(|| format!("Failed to open script '{}'", path.display()))?;
+        let mut readings = Vec::new();
+        for (i, line) in BufReader::new(file).lines().enumerate() {
+            let line = line?;
+            if line.trim().is_empty() {
+                continue;
+            }
+            readings.push(serde_json::from_str(&line).with_context(|| {
+                format!("Invalid reading on line {} of '{}'", i + 1, path.display())
+            })?);
+        }
+        if readings.is_empty() {
+            bail!("Script '{}' has no readings", path.display());
+        }
+        Ok(Self::Script { readings, next: 0 })
+    }
+
+    fn random() -> Self {
+        Self::Random(SensorData {
+            eco2: 450,
+            ech2o: 10,
+            tvoc: 50,
+            pm2_5: 12,
+            pm10: 20,
+            temperature: 22.0,
+            humidity: 45.0,
+        })
+    }
+
+    fn next(&mut self, rng: &mut fastrand::Rng) -> SensorData {
+        match self {
+            Self::Script { readings, next } => {
+                let data = readings[*next].clone();
+                *next = (*next + 1) % readings.len();
+                data
+            }
+            Self::Random(data) => {
+                // A bounded random walk, so consecutive values look plausible
+                let mut wander = |value: u16, step: i32, max: i32| {
+                    (i32::from(value) + rng.i32(-step..=step)).clamp(0, max) as u16
+                };
+                data.eco2 = wander(data.eco2, 20, 5000).max(400);
+                data.ech2o = wander(data.ech2o, 2, 1000);
+                data.tvoc = wander(data.tvoc, 5, 5000);
+                data.pm2_5 = wander(data.pm2_5, 2, 500);
+                data.pm10 = wander(data.pm10, 3, 600).max(data.pm2_5);
+                let mut drift = |value: f32, min: f32, max: f32| {
+                    (value + rng.i32(-2..=2) as f32 / 10.0).clamp(min, max)
+                };
+                data.temperature = drift(data.temperature, 10.0, 35.0);
+                data.humidity = drift(data.humidity, 20.0, 80.0);
+                data.clone()
+            }
+        }
+    }
+}
+
+/// Produces the byte stream of a (possibly misbehaving) sensor.
+struct Simulator {
+    values: Values,
+    faults: Faults,
+    rng: fastrand::Rng,
+}
+
+impl Simulator {
+    /// Returns the bytes for the next frame, and a pause to take after it if
+    /// one is due.
+    fn next_frame(&mut self) -> (Vec<u8>, Option<Duration>) {
+        let data = self.values.next(&mut self.rng);
+        let mut frame = encode_frame(&data).to_vec();
+        let mut bytes = Vec::with_capacity(FRAME_LEN + 8);
+        let mut faults = Vec::new();
+
+        if self.rng.f64() < self.faults.garbage {
+            let len = self.rng.usize(1..=8);
+            bytes.extend((0..len).map(|_| self.rng.u8(..)));
+            faults.push("garbage");
+        }
+        if self.rng.f64() < self.faults.bad_checksum {
+            frame[FRAME_LEN - 1] = frame[FRAME_LEN - 1].wrapping_add(self.rng.u8(1..));
+            faults.push("bad checksum");
+        }
+        if self.rng.f64() < self.faults.truncate {
+            frame.truncate(self.rng.usize(1..FRAME_LEN));
+            faults.push("truncated");
+        }
+        bytes.extend(frame);
+
+        if faults.is_empty() {
+            debug!("Sending {:?}", data);
+        } else {
+            info!("Sending {:?} with {}", data, faults.join(", "));
+        }
+
+        let pause = (self.rng.f64() < self.faults.pause)
+            .then(|| Duration::from_millis(self.faults.pause_ms));
+        (bytes, pause)
+    }
+}
+
+/// Removes the `--link` symlink when the simulator exits.
+struct Link(PathBuf);
+
+impl Drop for Link {
+    fn drop(&mut self) {
+        let _ = fs::remove_file(&self.0);
+    }
+}
+
+pub fn run(args: &SimulateArgs) -> Result<()> {
+    let values = match &args.script {
+        Some(path) => Values::load_script(path)?,
+        None => Values::random(),
+    };
+    let mut simulator = Simulator {
+        values,
+        faults: args.faults.clone(),
+        rng: args
+            .seed
+            .map_or_else(fastrand::Rng::new, fastrand::Rng::with_seed),
+    };
+
+    // The slave end stays open here so writes don't fail while no reader has
+    // the port open
+    let (mut master, slave) = TTYPort::pair().context("Failed to create a pseudo-terminal")?;
+    let name = slave.name().context("Pseudo-terminal has no device path")?;
+    let _link = match &args.link {
+        Some(link) => {
+            // Replace a link left behind by a simulator that was killed
+            if fs::symlink_metadata(link).is_ok_and(|m| m.file_type().is_symlink()) {
+                fs::remove_file(link)?;
+            }
+            symlink(&name, link)
+                .with_context(|| format!("Failed to create link '{}'", link.display()))?;
+            Some(Link(link.clone()))
+        }
+        None => None,
+    };
+    info!("Simulating an M701 on {}", name);
+    // On stdout so scripts can pick it up
+    println!(
+        "{}",
+        args.link
+            .as_deref()
+            .map_or(name.clone(), |l| l.display().to_string())
+    );
+
+    let interval = Duration::from_millis(args.interval_ms);
+    let mut sent = 0;
+    while args.count.is_none_or(|count| sent < count) {
+        let (bytes, pause) = simulator.next_frame();
+        master
+            .write_all(&bytes)
+            .context("Failed to write to the pseudo-terminal")?;
+        sent += 1;
+        thread::sleep(interval);
+        if let Some(pause) = pause {
+            info!("Pausing for {:.1}s", pause.as_secs_f32());
+            thread::sleep(pause);
+        }
+    }
+    info!("Sent {} frames", sent);
+    drop(slave);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use sensor_reader::{DecodeEvent, FrameDecoder, FrameError};
+    use std::io::Read;
+
+    fn simulator(faults: Faults) -> Simulator {
+        Simulator {
+            values: Values::random(),
+            faults,
+            rng: fastrand::Rng::with_seed(7),
+        }
+    }
+
+    #[test]
+    fn test_clean_frames_decode() {
+        let mut clean = simulator(Faults::default());
+        let mut decoder = FrameDecoder::new();
+        for _ in 0..100 {
+            let (bytes, pause) = clean.next_frame();
+            assert_eq!(pause, None);
+            let events: Vec<_> = decoder.feed(&bytes).collect();
+            assert!(matches!(events.as_slice(), [DecodeEvent::Frame(_)]));
+        }
+    }
+
+    #[test]
+    fn test_faults() {
+        let mut faulty = simulator(Faults {
+            bad_checksum: 1.0,
+            pause: 1.0,
+            pause_ms: 10,
+            ..Faults::default()
+        });
+        let (bytes, pause) = faulty.next_frame();
+        assert_eq!(pause, Some(Duration::from_millis(10)));
+        let events: Vec<_> = FrameDecoder::new().feed(&bytes).collect();
+        assert!(matches!(
+            events[0],
+            DecodeEvent::Rejected(FrameError::ChecksumMismatch { .. })
+        ));
+        assert!(
+            !events
+                .iter()
+                .any(|event| matches!(event, DecodeEvent::Frame(_)))
+        );
+
+        let mut truncating = simulator(Faults {
+            truncate: 1.0,
+            ..Faults::default()
+        });
+        assert!(truncating.next_frame().0.len() < FRAME_LEN);
+    }
+
+    #[test]
+    fn test_frames_over_pty() {
+        let (mut master, slave) = TTYPort::pair().unwrap();
+        let mut port = serialport::new(slave.name().unwrap(), 9600)
+            .timeout(Duration::from_secs(1))
+            .open()
+            .unwrap();
+        let (bytes, _) = simulator(Faults::default()).next_frame();
+        master.write_all(&bytes).unwrap();
+
+        let mut received = [0; FRAME_LEN];
+        port.read_exact(&mut received).unwrap();
+        assert_eq!(received.as_slice(), bytes.as_slice());
+    }
+}
@@ -0,0 +1,752 @@
+//! Records readings to local CSV or JSONL files.
+
+use anyhow::{Context, Result};
+use chrono::{DateTime, NaiveDate, Utc};
+use flate2::Compression;
+use flate2::write::GzEncoder;
+use log::{error, info, warn};
+use sensor_reader::{AQI_FIELDS, AqiStandard, COMFORT_FIELDS, ComfortMetric, FIELD_NAMES, Reading};
+use serde::{Deserialize, Serialize};
+use std::fmt::Write as _;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufRead, BufReader, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
+
+use crate::config::FileSinkConfig;
+use crate::queue::BoundedQueue;
+use crate::sinks::SinkStats;
+
+/// How readings are written.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum FileFormat {
+    /// One row per reading, with a header line.
+    Csv,
+    /// One JSON reading per line.
+    Jsonl,
+}
+
+impl FileFormat {
+    fn extension(self) -> &'static str {
+        match self {
+            FileFormat::Csv => "csv",
+            FileFormat::Jsonl => "jsonl",
+        }
+    }
+}
+
+/// When to start a new file.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Rotation {
+    /// Once the file reaches `max_bytes`.
+    Size,
+    /// At midnight UTC, going by the capture time.
+    Daily,
+}
+
+/// Quotes a CSV value if it contains a separator, quote or line break.
+pub fn csv_value(value: &str) -> String {
+    if value.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", value.replace('"', "\"\""))
+    } else {
+        value.to_string()
+    }
+}
+
+/// The optional columns of a CSV file.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct CsvColumns {
+    /// Names of the comfort metrics, in [`COMFORT_FIELDS`] order.
+    comfort: Vec<&'static str>,
+    /// Names of the air quality indices, in [`AQI_FIELDS`] order.
+    aqi: Vec<&'static str>,
+    /// Adds the window bounds and per-field statistics of aggregated
+    /// readings, whose value columns hold the window means.
+    windowed: bool,
+}
+
+impl CsvColumns {
+    pub fn new(comfort: &[ComfortMetric], aqi: &[AqiStandard], windowed: bool) -> Self {
+        Self {
+            comfort: COMFORT_FIELDS
+                .into_iter()
+                .filter(|name| comfort.iter().any(|metric| metric.field() == *name))
+                .collect(),
+            aqi: AQI_FIELDS
+                .into_iter()
+                .filter(|name| aqi.iter().any(|standard| standard.field() == *name))
+                .collect(),
+            windowed,
+        }
+    }
+
+    /// The columns that hold everything in `readings`, for exporting them.
+    pub fn of(readings: &[Reading]) -> Self {
+        Self {
+            comfort: COMFORT_FIELDS
+                .into_iter()
+                .enumerate()
+                .filter(|&(i, _)| readings.iter().any(|r| r.comfort.values()[i].is_some()))
+                .map(|(_, name)| name)
+                .collect(),
+            aqi: AQI_FIELDS
+                .into_iter()
+                .filter(|&name| {
+                    readings
+                        .iter()
+                        .any(|r| r.aqi.iter().any(|aqi| aqi.standard.field() == name))
+                })
+                .collect(),
+            windowed: readings.iter().any(|r| r.window.is_some()),
+        }
+    }
+
+    /// The measurement and comfort metric names.
+    fn fields(&self) -> impl Iterator<Item = &'static str> {
+        FIELD_NAMES.into_iter().chain(self.comfort.iter().copied())
+    }
+}
+
+pub fn csv_header(columns: &CsvColumns) -> String {
+    let mut header = String::from("captured_at,session,seq,device_id,location");
+    for name in columns.fields() {
+        header.push(',');
+        header.push_str(name);
+    }
+    for name in &columns.aqi {
+        header.push(',');
+        header.push_str(name);
+    }
+    if columns.windowed {
+        header.push_str(",window_start,window_end,samples");
+        for name in columns.fields() {
+            let _ = write!(header, ",{name}_min,{name}_max,{name}_last");
+        }
+    }
+    header.push('\n');
+    header
+}
+
+pub fn csv_row(reading: &Reading, columns: &CsvColumns) -> String {
+    let mut row = format!(
+        "{},{},{},{},{}",
+        reading.captured_at.to_rfc3339(),
+        reading.session,
+        reading.seq,
+        csv_value(&reading.device.id),
+        csv_value(reading.device.location.as_deref().unwrap_or(""))
+    );
+    for (_, value) in reading.data.fields() {
+        row.push(',');
+        row.push_str(&value.to_string());
+    }
+    // Metrics that are undefined for the reading are left empty
+    for (name, value) in COMFORT_FIELDS.into_iter().zip(reading.comfort.values()) {
+        if columns.comfort.contains(&name) {
+            row.push(',');
+            if let Some(value) = value {
+                row.push_str(&value.to_string());
+            }
+        }
+    }
+    let aqi = reading.aqi_fields();
+    for name in &columns.aqi {
+        row.push(',');
+        if let Some((_, index)) = aqi.iter().find(|(field, _)| field == name) {
+            row.push_str(&index.to_string());
+        }
+    }
+    if columns.windowed {
+        match &reading.window {
+            Some(window) => {
+                let _ = write!(
+                    row,
+                    ",{},{},{}",
+                    window.start.to_rfc3339(),
+                    window.end.to_rfc3339(),
+                    window.samples
+                );
+                for name in columns.fields() {
+                    match window.fields.get(name) {
+                        Some(stats) => {
+                            let _ = write!(row, ",{},{},{}", stats.min, stats.max, stats.last);
+                        }
+                        None => row.push_str(",,,"),
+                    }
+                }
+            }
+            None => row.push_str(&",".repeat(3 + 3 * columns.fields().count())),
+        }
+    }
+    row.push('\n');
+    row
+}
+
+/// Whether the CSV file at `path` starts with `header`, or is yet to be
+/// written.
+fn has_header(path: &Path, header: &str) -> Result<bool> {
+    let file = match File::open(path) {
+        Ok(file) => file,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(true),
+        Err(e) => return Err(e).with_context(|| format!("Failed to open '{}'", path.display())),
+    };
+    let mut first = String::new();
+    BufReader::new(file)
+        .read_line(&mut first)
+        .with_context(|| format!("Failed to read '{}'", path.display()))?;
+    Ok(first.is_empty() || first == header)
+}
+
+/// Compresses `path` to `path.gz` and removes the original. An existing
+/// `.gz` is appended to, which gzip readers see as one stream.
+fn compress(path: &Path) -> Result<()> {
+    let mut gz_name = path.as_os_str().to_owned();
+    gz_name.push(".gz");
+    let gz_path = PathBuf::from(gz_name);
+
+    let mut input =
+        File::open(path).with_context(|| format!("Failed to open '{}'", path.display()))?;
+    let output = OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(&gz_path)
+        .with_context(|| format!("Failed to create '{}'", gz_path.display()))?;
+    let mut encoder = GzEncoder::new(output, Compression::default());
+    io::copy(&mut input, &mut encoder)
+        .and_then(|_| encoder.finish())
+        .and_then(|file| file.sync_all())
+        .with_context(|| format!("Failed to compress '{}'", path.display()))?;
+    fs::remove_file(path).with_context(|| format!("Failed to remove '{}'", path.display()))
+}
+
+/// The file currently written to.
+struct Active {
+    path: PathBuf,
+    file: File,
+    bytes: u64,
+    day: NaiveDate,
+}
+
+/// Appends readings to rotating files in one directory.
+///
+/// Files are named `<prefix>-<start>.<ext>`, so sorting them by name sorts
+/// them by age. A CSV file whose header does not match the current columns,
+/// e.g. after a configuration change, is not appended to; the readings go to
+/// `<prefix>-<start>_<n>.<ext>` instead. Closed files are optionally gzipped,
+/// and the oldest are removed once there are more than `keep_files` or they
+/// are older than `max_age_days`.
+pub struct FileSink {
+    config: FileSinkConfig,
+    columns: CsvColumns,
+    active: Option<Active>,
+}
+
+impl FileSink {
+    /// Opens the sink for readings that carry the `comfort` metrics and
+    /// `aqi` indices.
+    pub fn open(
+        config: &FileSinkConfig,
+        comfort: &[ComfortMetric],
+        aqi: &[AqiStandard],
+    ) -> Result<Self> {
+        fs::create_dir_all(&config.dir)
+            .with_context(|| format!("Failed to create '{}'", config.dir.display()))?;
+        let sink = Self {
+            config: config.clone(),
+            columns: CsvColumns::new(comfort, aqi, config.aggregate.window_secs > 0),
+            active: None,
+        };
+
+        // Files left over by an earlier run are closed by now, except
+        // today's file with daily rotation, which is appended to
+        if sink.config.compress {
+            let today = sink.stem(Utc::now());
+            for path in sink.files()? {
+                let closed = sink.config.rotation == Rotation::Size
+                    || path
+                        .file_name()
+                        .and_then(|name| name.to_str()?.strip_prefix(&today))
+                        .is_none_or(|rest| !rest.starts_with(['.', '_']));
+                if closed && path.extension().is_some_and(|ext| ext != "gz") {
+                    compress(&path)?;
+                }
+            }
+        }
+        sink.enforce_retention(None);
+        info!("Recording readings to {}", config.dir.display());
+        Ok(sink)
+    }
+
+    /// The file name for `at` up to the extension.
+    fn stem(&self, at: DateTime<Utc>) -> String {
+        let stamp = match self.config.rotation {
+            Rotation::Size => at.format("%Y%m%dT%H%M%SZ"),
+            Rotation::Daily => at.format("%Y-%m-%d"),
+        };
+        format!("{}-{}", self.config.prefix, stamp)
+    }
+
+    /// The name of the `part`th file for `at`, counting from 0.
+    fn file_name(&self, at: DateTime<Utc>, part: usize) -> String {
+        let ext = self.config.format.extension();
+        match part {
+            0 => format!("{}.{}", self.stem(at), ext),
+            _ => format!("{}_{}.{}", self.stem(at), part, ext),
+        }
+    }
+
+    /// Every file of this sink, oldest first.
+    fn files(&self) -> Result<Vec<PathBuf>> {
+        let prefix = format!("{}-", self.config.prefix);
+        let ext = format!(".{}", self.config.format.extension());
+        let ext_gz = format!("{}.gz", ext);
+        let mut files = Vec::new();
+        let entries = fs::read_dir(&self.config.dir)
+            .with_context(|| format!("Failed to list '{}'", self.config.dir.display()))?;
+        for entry in entries {
+            let entry = entry?;
+            let name = entry.file_name();
+            let Some(name) = name.to_str() else {
+                continue;
+            };
+            if name.starts_with(&prefix) && (name.ends_with(&ext) || name.ends_with(&ext_gz)) {
+                files.push(entry.path());
+            }
+        }
+        files.sort();
+        Ok(files)
+    }
+
+    /// Removes the oldest files beyond the configured count and age, sparing
+    /// the `active` one.
+    fn enforce_retention(&self, active: Option<&Path>) {
+        let files = match self.files() {
+            Ok(files) => files,
+            Err(e) => {
+                warn!("{:#}", e);
+                return;
+            }
+        };
+        let excess = self
+            .config
+            .keep_files
+            .map_or(0, |keep| files.len().saturating_sub(keep));
+        let max_age = self
+            .config
+            .max_age_days
+            .map(|days| Duration::from_secs(days * 24 * 3600));
+
+        for (i, path) in files.iter().enumerate() {
+            if Some(path.as_path()) == active {
+                continue;
+            }
+            let expired = max_age.is_some_and(|max_age| {
+                fs::metadata(path)
+                    .and_then(|m| m.modified())
+                    .is_ok_and(|modified| {
+                        SystemTime::now()
+                            .duration_since(modified)
+                            .is_ok_and(|age| age > max_age)
+                    })
+            });
+            if i < excess || expired {
+                match fs::remove_file(path) {
+                    Ok(()) => info!("Removed old recording {}", path.display()),
+                    Err(e) => warn!("Failed to remove '{}': {}", path.display(), e),
+                }
+            }
+        }
+    }
+
+    fn create(&self, reading: &Reading) -> Result<Active> {
+        let header = match self.config.format {
+            FileFormat::Csv => Some(csv_header(&self.columns)),
+            FileFormat::Jsonl => None,
+        };
+        let mut part = 0;
+        let mut path = self
+            .config
+            .dir
+            .join(self.file_name(reading.captured_at, part));
+        while let Some(header) = &header
+            && !has_header(&path, header)?
+        {
+            part += 1;
+            let next = self
+                .config
+                .dir
+                .join(self.file_name(reading.captured_at, part));
+            warn!(
+                "'{}' has other columns, writing to '{}'",
+                path.display(),
+                next.display()
+            );
+            path = next;
+        }
+        let mut file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&path)
+            .with_context(|| format!("Failed to open '{}'", path.display()))?;
+        let mut bytes = file.metadata()?.len();
+        if bytes == 0
+            && let Some(header) = header
+        {
+            file.write_all(header.as_bytes())
+                .with_context(|| format!("Failed to write to '{}'", path.display()))?;
+            bytes = header.len() as u64;
+        }
+        Ok(Active {
+            path,
+            file,
+            bytes,
+            day: reading.captured_at.date_naive(),
+        })
+    }
+
+    /// Closes the active file, compressing it if configured.
+    fn rotate(&mut self) {
+        let Some(active) = self.active.take() else {
+            return;
+        };
+        drop(active.file);
+        if self.config.compress
+            && let Err(e) = compress(&active.path)
+        {
+            error!("{:#}", e);
+        }
+    }
+
+    pub fn write(&mut self, reading: &Reading) -> Result<()> {
+        let line = match self.config.format {
+            FileFormat::Csv => csv_row(reading, &self.columns),
+            FileFormat::Jsonl => {
+                let mut line = serde_json::to_string(reading)?;
+                line.push('\n');
+                line
+            }
+        };
+
+        let due = self
+            .active
+            .as_ref()
+            .is_some_and(|active| match self.config.rotation {
+                Rotation::Size => {
+                    active.bytes > 0 && active.bytes + line.len() as u64 > self.config.max_bytes
+                }
+                Rotation::Daily => active.day != reading.captured_at.date_naive(),
+            });
+        if due {
+            self.rotate();
+        }
+
+        let active = match self.active.take() {
+            Some(active) => active,
+            None => {
+                let active = self.create(reading)?;
+                // Counted with the new file, so at most `keep_files` remain
+                self.enforce_retention(Some(&active.path));
+                active
+            }
+        };
+        let active = self.active.insert(active);
+        active
+            .file
+            .write_all(line.as_bytes())
+            .with_context(|| format!("Failed to write to '{}'", active.path.display()))?;
+        active.bytes += line.len() as u64;
+        Ok(())
+    }
+}
+
+/// Records readings from `queue` until it is closed and drained.
+pub fn run(queue: &BoundedQueue<Reading>, stats: Arc<SinkStats>, mut sink: FileSink) {
+    while let Some(reading) = queue.pop() {
+        match sink.write(&reading) {
+            Ok(()) => stats.delivered(1),
+            Err(e) => {
+                error!("Failed to record reading #{}: {:#}", reading.seq, e);
+                stats.failed(1);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::AggregateSection;
+    use chrono::TimeDelta;
+    use flate2::read::GzDecoder;
+    use sensor_reader::{
+        Aggregator, Aqi, Comfort, ComfortMetric, DeviceInfo, Pollutant, SensorData, Stamper,
+    };
+    use std::io::Read;
+
+    fn stamper() -> Stamper {
+        Stamper::new(DeviceInfo {
+            id: "m701, lab".into(),
+            ..DeviceInfo::default()
+        })
+    }
+
+    fn data() -> SensorData {
+        SensorData {
+            eco2: 400,
+            ech2o: 5,
+            tvoc: 10,
+            pm2_5: 20,
+            pm10: 30,
+            temperature: 25.5,
+            humidity: 50.2,
+        }
+    }
+
+    fn at(s: &str) -> DateTime<Utc> {
+        DateTime::parse_from_rfc3339(s).unwrap().to_utc()
+    }
+
+    fn names(dir: &Path) -> Vec<String> {
+        let mut names: Vec<_> = fs::read_dir(dir)
+            .unwrap()
+            .map(|e| e.unwrap().file_name().into_string().unwrap())
+            .collect();
+        names.sort();
+        names
+    }
+
+    #[test]
+    fn test_daily_csv() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = FileSinkConfig {
+            dir: dir.path().to_path_buf(),
+            rotation: Rotation::Daily,
+            ..FileSinkConfig::default()
+        };
+        let mut stamper = stamper();
+        let mut sink = FileSink::open(&config, &[], &[]).unwrap();
+        sink.write(&stamper.stamp_at(data(), at("2024-05-01T23:59:59Z")))
+            .unwrap();
+        sink.write(&stamper.stamp_at(data(), at("2024-05-02T00:00:01Z")))
+            .unwrap();
+        drop(sink);
+
+        // Appends to the day's file after a restart, without a second header
+        let mut sink = FileSink::open(&config, &[], &[]).unwrap();
+        sink.write(&stamper.stamp_at(data(), at("2024-05-02T00:00:02Z")))
+            .unwrap();
+
+        assert_eq!(
+            names(dir.path()),
+            ["readings-2024-05-01.csv", "readings-2024-05-02.csv"]
+        );
+        let text = fs::read_to_string(dir.path().join("readings-2024-05-02.csv")).unwrap();
+        let lines: Vec<_> = text.lines().collect();
+        assert_eq!(
+            lines[0],
+            "captured_at,session,seq,device_id,location,eco2,ech2o,tvoc,pm2_5,pm10,temperature,humidity"
+        );
+        assert_eq!(
+            lines[1],
+            format!(
+                "2024-05-02T00:00:01+00:00,{},1,\"m701, lab\",,400,5,10,20,30,25.5,50.2",
+                stamper.session()
+            )
+        );
+        assert_eq!(lines.len(), 3);
+    }
+
+    #[test]
+    fn test_comfort_columns() {
+        let columns = CsvColumns::new(
+            &[ComfortMetric::Humidex, ComfortMetric::DewPoint],
+            &[],
+            false,
+        );
+        assert!(csv_header(&columns).ends_with(",temperature,humidity,dew_point,humidex\n"));
+
+        let mut reading = stamper().stamp_at(data(), at("2024-05-02T00:00:02Z"));
+        reading.comfort = Comfort::compute(&reading.data, &[ComfortMetric::DewPoint]);
+        assert_eq!(
+            csv_row(&reading, &columns),
+            format!(
+                "2024-05-02T00:00:02+00:00,{},0,\"m701, lab\",,400,5,10,20,30,25.5,50.2,14.4,\n",
+                reading.session
+            )
+        );
+        assert_eq!(
+            CsvColumns::of(&[reading]),
+            CsvColumns::new(&[ComfortMetric::DewPoint], &[], false)
+        );
+    }
+
+    #[test]
+    fn test_aqi_columns() {
+        let columns = CsvColumns::new(&[], &[AqiStandard::China, AqiStandard::UsEpa], false);
+        assert!(csv_header(&columns).ends_with(",humidity,aqi_us_epa,aqi_china\n"));
+
+        let mut reading = stamper().stamp_at(data(), at("2024-05-02T00:00:02Z"));
+        reading.aqi = vec![Aqi {
+            standard: AqiStandard::China,
+            index: 29,
+            category: "Excellent".into(),
+            dominant: Some(Pollutant::Pm10),
+            pm2_5: 29,
+            pm10: 29,
+        }];
+        assert_eq!(
+            csv_row(&reading, &columns),
+            format!(
+                "2024-05-02T00:00:02+00:00,{},0,\"m701, lab\",,400,5,10,20,30,25.5,50.2,,29\n",
+                reading.session
+            )
+        );
+        assert_eq!(
+            CsvColumns::of(&[reading]),
+            CsvColumns::new(&[], &[AqiStandard::China], false)
+        );
+    }
+
+    #[test]
+    fn test_csv_columns_changed() {
+        let dir = tempfile::tempdir().unwrap();
+        let raw = FileSinkConfig {
+            dir: dir.path().to_path_buf(),
+            rotation: Rotation::Daily,
+            ..FileSinkConfig::default()
+        };
+        let windowed = FileSinkConfig {
+            aggregate: AggregateSection {
+                window_secs: 10,
+                step_secs: None,
+            },
+            ..raw.clone()
+        };
+        let mut stamper = stamper();
+        let mut write = |config: &FileSinkConfig, time: &str| {
+            let mut sink = FileSink::open(config, &[], &[]).unwrap();
+            sink.write(&stamper.stamp_at(data(), at(time))).unwrap();
+        };
+        write(&raw, "2024-05-02T00:00:01Z");
+        // The day's file has other columns, so it is left alone
+        write(&windowed, "2024-05-02T00:00:10Z");
+        write(&windowed, "2024-05-02T00:00:20Z");
+        write(&raw, "2024-05-02T00:00:21Z");
+
+        assert_eq!(
+            names(dir.path()),
+            ["readings-2024-05-02.csv", "readings-2024-05-02_1.csv"]
+        );
+        let read = |name: &str| {
+            let text = fs::read_to_string(dir.path().join(name)).unwrap();
+            text.lines().map(str::to_string).collect::<Vec<_>>()
+        };
+        let lines = read("readings-2024-05-02.csv");
+        assert_eq!(lines.len(), 3);
+        assert_eq!(
+            format!("{}\n", lines[0]),
+            csv_header(&CsvColumns::new(&[], &[], false))
+        );
+        let session = stamper.session();
+        assert!(lines[2].starts_with(&format!("2024-05-02T00:00:21+00:00,{session},3,")));
+        let lines = read("readings-2024-05-02_1.csv");
+        assert_eq!(lines.len(), 3);
+        assert_eq!(
+            format!("{}\n", lines[0]),
+            csv_header(&CsvColumns::new(&[], &[], true))
+        );
+    }
+
+    #[test]
+    fn test_windowed_csv() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = FileSinkConfig {
+            dir: dir.path().to_path_buf(),
+            rotation: Rotation::Daily,
+            aggregate: AggregateSection {
+                window_secs: 10,
+                step_secs: None,
+            },
+            ..FileSinkConfig::default()
+        };
+        let mut stamper = Stamper::new(DeviceInfo {
+            id: "lab".into(),
+            ..DeviceInfo::default()
+        });
+        let mut aggregator = Aggregator::new(TimeDelta::seconds(10), TimeDelta::seconds(10));
+        let mut sink = FileSink::open(&config, &[], &[]).unwrap();
+        for (time, pm2_5) in [("12:00:01", 10), ("12:00:05", 30), ("12:00:11", 5)] {
+            let data = SensorData { pm2_5, ..data() };
+            let reading = stamper.stamp_at(data, at(&format!("2024-05-01T{}Z", time)));
+            for summary in aggregator.push(reading) {
+                sink.write(&summary).unwrap();
+            }
+        }
+
+        let text = fs::read_to_string(dir.path().join("readings-2024-05-01.csv")).unwrap();
+        let lines: Vec<_> = text.lines().collect();
+        assert_eq!(lines.len(), 2);
+        let header: Vec<_> = lines[0].split(',').collect();
+        let row: Vec<_> = lines[1].split(',').collect();
+        assert_eq!(header.len(), row.len());
+        let column = |name: &str| row[header.iter().position(|h| *h == name).unwrap()];
+        assert_eq!(column("captured_at"), "2024-05-01T12:00:10+00:00");
+        assert_eq!(column("pm2_5"), "20");
+        assert_eq!(column("window_start"), "2024-05-01T12:00:00+00:00");
+        assert_eq!(column("window_end"), "2024-05-01T12:00:10+00:00");
+        assert_eq!(column("samples"), "2");
+        assert_eq!(column("pm2_5_min"), "10");
+        assert_eq!(column("pm2_5_max"), "30");
+        assert_eq!(column("pm2_5_last"), "30");
+        // Not configured, so no columns either
+        assert!(!header.contains(&"dew_point_min"));
+    }
+
+    #[test]
+    fn test_size_rotation_compression_and_retention() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = FileSinkConfig {
+            dir: dir.path().to_path_buf(),
+            format: FileFormat::Jsonl,
+            rotation: Rotation::Size,
+            max_bytes: 1,
+            compress: true,
+            keep_files: Some(2),
+            ..FileSinkConfig::default()
+        };
+        let mut stamper = stamper();
+        let mut sink = FileSink::open(&config, &[], &[]).unwrap();
+        for second in 1..=3 {
+            let reading = stamper.stamp_at(data(), at(&format!("2024-05-01T12:00:0{}Z", second)));
+            sink.write(&reading).unwrap();
+        }
+
+        assert_eq!(
+            names(dir.path()),
+            [
+                "readings-20240501T120002Z.jsonl.gz",
+                "readings-20240501T120003Z.jsonl"
+            ]
+        );
+        let mut text = String::new();
+        GzDecoder::new(File::open(dir.path().join("readings-20240501T120002Z.jsonl.gz")).unwrap())
+            .read_to_string(&mut text)
+            .unwrap();
+        let reading: Reading = serde_json::from_str(text.trim()).unwrap();
+        assert_eq!(reading.seq, 1);
+
+        // The last file is closed by the next run
+        drop(sink);
+        FileSink::open(&config, &[], &[]).unwrap();
+        assert_eq!(
+            names(dir.path()),
+            [
+                "readings-20240501T120002Z.jsonl.gz",
+                "readings-20240501T120003Z.jsonl.gz"
+            ]
+        );
+    }
+}
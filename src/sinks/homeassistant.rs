@@ -0,0 +1,325 @@
+//! Home Assistant MQTT discovery for the readings published by the MQTT sink.
+//!
+//! Every field and configured comfort metric becomes a `sensor` entity,
+//! grouped under one Home Assistant device per sensor. See
+//! <https://www.home-assistant.io/integrations/mqtt/#mqtt-discovery>.
+
+use sensor_reader::{AqiStandard, ComfortMetric, DeviceInfo, FIELD_LABELS, FIELD_NAMES};
+use serde_json::{Value, json};
+
+use crate::sinks::mqtt::render_topic;
+
+/// How one [`SensorData`](sensor_reader::SensorData) or
+/// [`Comfort`](sensor_reader::Comfort) field is presented in Home Assistant.
+struct Entity {
+    field: &'static str,
+    name: &'static str,
+    /// `None` where Home Assistant has no matching device class.
+    device_class: Option<&'static str>,
+    unit: Option<&'static str>,
+    /// Extracts the value from the JSON reading, if it is not the field of
+    /// the same name.
+    json_template: Option<&'static str>,
+}
+
+/// The measurement at `index` in frame order, with the name and unit used
+/// everywhere else.
+const fn measurement(index: usize, device_class: Option<&'static str>) -> Entity {
+    Entity {
+        field: FIELD_NAMES[index],
+        name: FIELD_LABELS[index].0,
+        device_class,
+        unit: Some(FIELD_LABELS[index].1),
+        json_template: None,
+    }
+}
+
+const ENTITIES: [Entity; 7] = [
+    measurement(0, Some("carbon_dioxide")),
+    measurement(1, None),
+    measurement(2, Some("volatile_organic_compounds")),
+    measurement(3, Some("pm25")),
+    measurement(4, Some("pm10")),
+    measurement(5, Some("temperature")),
+    measurement(6, Some("humidity")),
+];
+
+const COMFORT_ENTITIES: [Entity; 4] = [
+    Entity {
+        field: "dew_point",
+        name: "Dew point",
+        device_class: Some("temperature"),
+        unit: Some("°C"),
+        json_template: None,
+    },
+    Entity {
+        field: "absolute_humidity",
+        name: "Absolute humidity",
+        device_class: Some("absolute_humidity"),
+        unit: Some("g/m³"),
+        json_template: None,
+    },
+    Entity {
+        field: "heat_index",
+        name: "Heat index",
+        device_class: Some("temperature"),
+        unit: Some("°C"),
+        json_template: None,
+    },
+    // Not a temperature, so Home Assistant must not convert it to °F
+    Entity {
+        field: "humidex",
+        name: "Humidex",
+        device_class: None,
+        unit: Some("°C"),
+        json_template: None,
+    },
+];
+
+const AQI_ENTITIES: [Entity; 2] = [
+    Entity {
+        field: "aqi_us_epa",
+        name: "AQI (US EPA)",
+        device_class: Some("aqi"),
+        unit: None,
+        json_template: Some(
+            "{{ value_json.aqi | default([]) | selectattr('standard', 'eq', 'us-epa') \
+             | map(attribute='index') | first | default(none) }}",
+        ),
+    },
+    Entity {
+        field: "aqi_china",
+        name: "AQI (China)",
+        device_class: Some("aqi"),
+        unit: None,
+        json_template: Some(
+            "{{ value_json.aqi | default([]) | selectattr('standard', 'eq', 'china') \
+             | map(attribute='index') | first | default(none) }}",
+        ),
+    },
+];
+
+/// Topic Home Assistant announces itself on after a restart, as a cue to
+/// publish the discovery messages again.
+pub fn status_topic(prefix: &str) -> String {
+    format!("{}/status", prefix)
+}
+
+/// Replaces everything but `[a-zA-Z0-9_-]`, which is all Home Assistant
+/// allows in the node ID of a discovery topic.
+fn node_id(device_id: &str) -> String {
+    device_id
+        .chars()
+        .map(|c| {
+            if c.is_ascii_alphanumeric() || c == '-' || c == '_' {
+                c
+            } else {
+                '_'
+            }
+        })
+        .collect()
+}
+
+/// Builds the retained discovery messages for `device` and the `comfort`
+/// metrics and `aqi` indices it reports, pointing Home Assistant at the
+/// topics rendered from `topic` and `availability_topic`.
+pub fn discovery_messages(
+    prefix: &str,
+    topic: &str,
+    availability_topic: &str,
+    device: &DeviceInfo,
+    comfort: &[ComfortMetric],
+    aqi: &[AqiStandard],
+) -> Vec<(String, Vec<u8>)> {
+    let node_id = node_id(&device.id);
+    let per_field = topic.contains("{field}");
+    let mut ha_device = json!({
+        "identifiers": [format!("sensor_reader_{}", node_id)],
+        "name": device.id,
+        "model": "M701",
+    });
+    if let Some(location) = &device.location {
+        ha_device["suggested_area"] = Value::from(location.as_str());
+    }
+
+    let comfort = COMFORT_ENTITIES
+        .iter()
+        .filter(|entity| comfort.iter().any(|metric| metric.field() == entity.field));
+    let aqi = AQI_ENTITIES
+        .iter()
+        .filter(|entity| aqi.iter().any(|standard| standard.field() == entity.field));
+    ENTITIES
+        .iter()
+        .chain(comfort)
+        .chain(aqi)
+        .map(|entity| {
+            let mut config = json!({
+                "name": entity.name,
+                "unique_id": format!("{}_{}", node_id, entity.field),
+                "state_topic": render_topic(topic, device, entity.field),
+                "state_class": "measurement",
+                "availability_topic": availability_topic,
+                "device": ha_device,
+            });
+            if let Some(unit) = entity.unit {
+                config["unit_of_measurement"] = unit.into();
+            }
+            if !per_field {
+                config["value_template"] = match entity.json_template {
+                    Some(template) => template.into(),
+                    None => format!("{{{{ value_json.{} }}}}", entity.field).into(),
+                };
+            }
+            if let Some(class) = entity.device_class {
+                config["device_class"] = class.into();
+            }
+            (
+                format!("{}/sensor/{}/{}/config", prefix, node_id, entity.field),
+                config.to_string().into_bytes(),
+            )
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use sensor_reader::{AQI_FIELDS, COMFORT_FIELDS, FIELD_NAMES};
+
+    fn device() -> DeviceInfo {
+        DeviceInfo {
+            id: "m701 lab".into(),
+            location: Some("Lab".into()),
+            ..DeviceInfo::default()
+        }
+    }
+
+    fn config(messages: &[(String, Vec<u8>)], field: &str) -> (String, Value) {
+        let (topic, payload) = messages
+            .iter()
+            .find(|(topic, _)| topic.ends_with(&format!("/{}/config", field)))
+            .unwrap();
+        (topic.clone(), serde_json::from_slice(payload).unwrap())
+    }
+
+    #[test]
+    fn test_covers_every_field() {
+        let fields: Vec<_> = ENTITIES.iter().map(|e| e.field).collect();
+        assert_eq!(fields, FIELD_NAMES);
+        let fields: Vec<_> = COMFORT_ENTITIES.iter().map(|e| e.field).collect();
+        assert_eq!(fields, COMFORT_FIELDS);
+        let fields: Vec<_> = AQI_ENTITIES.iter().map(|e| e.field).collect();
+        assert_eq!(fields, AQI_FIELDS);
+    }
+
+    #[test]
+    fn test_json_topic() {
+        let messages = discovery_messages(
+            "homeassistant",
+            "sensors/{device_id}",
+            "sensors/m701 lab/status",
+            &device(),
+            &[],
+            &[],
+        );
+        assert_eq!(messages.len(), 7);
+
+        let (topic, pm) = config(&messages, "pm2_5");
+        assert_eq!(topic, "homeassistant/sensor/m701_lab/pm2_5/config");
+        assert_eq!(pm["state_topic"], "sensors/m701 lab");
+        assert_eq!(pm["value_template"], "{{ value_json.pm2_5 }}");
+        assert_eq!(pm["device_class"], "pm25");
+        assert_eq!(pm["unit_of_measurement"], "µg/m³");
+        assert_eq!(pm["state_class"], "measurement");
+        assert_eq!(pm["unique_id"], "m701_lab_pm2_5");
+        assert_eq!(pm["device"]["identifiers"][0], "sensor_reader_m701_lab");
+        assert_eq!(pm["device"]["suggested_area"], "Lab");
+
+        let (_, ech2o) = config(&messages, "ech2o");
+        assert!(ech2o.get("device_class").is_none());
+    }
+
+    #[test]
+    fn test_per_field_topic() {
+        let messages = discovery_messages(
+            "ha",
+            "sensors/{device_id}/{field}",
+            "sensors/status",
+            &device(),
+            &[],
+            &[],
+        );
+        let (topic, temperature) = config(&messages, "temperature");
+        assert_eq!(topic, "ha/sensor/m701_lab/temperature/config");
+        assert_eq!(temperature["state_topic"], "sensors/m701 lab/temperature");
+        assert!(temperature.get("value_template").is_none());
+        assert_eq!(temperature["unit_of_measurement"], "°C");
+    }
+
+    #[test]
+    fn test_comfort_metrics() {
+        let messages = discovery_messages(
+            "homeassistant",
+            "sensors/{device_id}",
+            "sensors/status",
+            &device(),
+            &[ComfortMetric::DewPoint, ComfortMetric::Humidex],
+            &[],
+        );
+        assert_eq!(messages.len(), 9);
+        assert!(
+            !messages
+                .iter()
+                .any(|(topic, _)| topic.contains("/heat_index/"))
+        );
+
+        let (topic, dew_point) = config(&messages, "dew_point");
+        assert_eq!(topic, "homeassistant/sensor/m701_lab/dew_point/config");
+        assert_eq!(dew_point["value_template"], "{{ value_json.dew_point }}");
+        assert_eq!(dew_point["device_class"], "temperature");
+        assert_eq!(dew_point["unit_of_measurement"], "°C");
+
+        let (_, humidex) = config(&messages, "humidex");
+        assert!(humidex.get("device_class").is_none());
+    }
+
+    #[test]
+    fn test_aqi() {
+        let messages = discovery_messages(
+            "homeassistant",
+            "sensors/{device_id}",
+            "sensors/status",
+            &device(),
+            &[],
+            &[AqiStandard::China],
+        );
+        assert_eq!(messages.len(), 8);
+        assert!(
+            !messages
+                .iter()
+                .any(|(topic, _)| topic.contains("/aqi_us_epa/"))
+        );
+
+        let (topic, aqi) = config(&messages, "aqi_china");
+        assert_eq!(topic, "homeassistant/sensor/m701_lab/aqi_china/config");
+        assert_eq!(aqi["device_class"], "aqi");
+        assert!(aqi.get("unit_of_measurement").is_none());
+        assert_eq!(
+            aqi["value_template"],
+            "{{ value_json.aqi | default([]) | selectattr('standard', 'eq', 'china') \
+             | map(attribute='index') | first | default(none) }}"
+        );
+
+        let messages = discovery_messages(
+            "homeassistant",
+            "sensors/{device_id}/{field}",
+            "sensors/status",
+            &device(),
+            &[],
+            &[AqiStandard::China],
+        );
+        let (_, aqi) = config(&messages, "aqi_china");
+        assert_eq!(aqi["state_topic"], "sensors/m701 lab/aqi_china");
+        assert!(aqi.get("value_template").is_none());
+    }
+}
@@ -0,0 +1,880 @@
+use anyhow::{Context, Result};
+use log::{error, info, warn};
+use reqwest::StatusCode;
+use reqwest::blocking::{Client, Response};
+use reqwest::header::RETRY_AFTER;
+use sensor_reader::Reading;
+use serde::{Deserialize, Serialize};
+use std::fs::{File, OpenOptions};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::{Duration, Instant};
+use thiserror::Error;
+
+use crate::outbox::Outbox;
+use crate::queue::BoundedQueue;
+use crate::retry::{RetryPolicy, sleep_unless_stopped};
+use crate::sinks::SinkStats;
+
+/// Why a delivery attempt failed, and whether trying again can help.
+#[derive(Debug, Error)]
+pub enum SendError {
+    /// Server errors, rate limiting and network failures.
+    #[error("{reason}")]
+    Retryable {
+        reason: String,
+        /// Delay requested by the server through `Retry-After`.
+        retry_after: Option<Duration>,
+    },
+
+    /// The server rejected the reading; sending it again will not help.
+    #[error("{0}")]
+    Permanent(String),
+}
+
+impl SendError {
+    /// Classifies an unsuccessful response.
+    pub fn from_response(resp: &Response) -> Self {
+        let status = resp.status();
+        let reason = format!("server returned error: {}", status);
+        let retryable = status.is_server_error()
+            || status == StatusCode::TOO_MANY_REQUESTS
+            || status == StatusCode::REQUEST_TIMEOUT;
+        if !retryable {
+            return SendError::Permanent(reason);
+        }
+
+        // Only the delay-seconds form of Retry-After is supported
+        let retry_after = resp
+            .headers()
+            .get(RETRY_AFTER)
+            .and_then(|v| v.to_str().ok())
+            .and_then(|v| v.trim().parse().ok())
+            .map(Duration::from_secs);
+        SendError::Retryable {
+            reason,
+            retry_after,
+        }
+    }
+}
+
+impl From<reqwest::Error> for SendError {
+    fn from(e: reqwest::Error) -> Self {
+        // A request that cannot be built, e.g. from a malformed URL, or a
+        // redirect that cannot be followed fails the same way every time.
+        // Anything else went wrong on the way to or from the server
+        let permanent = e.is_builder() || e.is_redirect() || e.is_decode();
+        let reason = format!("{:#}", anyhow::Error::from(e));
+        if permanent {
+            SendError::Permanent(reason)
+        } else {
+            SendError::Retryable {
+                reason,
+                retry_after: None,
+            }
+        }
+    }
+}
+
+/// Version of the batched payload shape, bumped on incompatible changes.
+pub const BATCH_VERSION: u32 = 1;
+
+/// Body of a batched POST. Single readings are posted as a bare object, so
+/// the server can tell the two apart by the presence of `readings`.
+#[derive(Serialize)]
+struct BatchPayload<'a> {
+    version: u32,
+    readings: &'a [Reading],
+}
+
+/// A server the [`Uploader`] can deliver readings to.
+pub trait Endpoint: Send {
+    /// Delivers `readings` in a single request.
+    fn send(&self, readings: &[Reading]) -> Result<(), SendError>;
+}
+
+/// Posts readings as JSON to the configured server.
+pub struct HttpSink {
+    client: Client,
+    url: String,
+    api_key: Option<String>,
+    batched: bool,
+}
+
+impl HttpSink {
+    /// Creates a sink that posts one reading per request, or a
+    /// [`BatchPayload`] per request if `batched` is set.
+    pub fn new(url: String, api_key: Option<String>, batched: bool) -> Result<Self> {
+        let client = Client::builder().use_rustls_tls().build()?;
+        Ok(Self {
+            client,
+            url,
+            api_key,
+            batched,
+        })
+    }
+}
+
+impl Endpoint for HttpSink {
+    /// Posts `readings` in a single request.
+    ///
+    /// In single-reading mode `readings` must hold exactly one reading.
+    fn send(&self, readings: &[Reading]) -> Result<(), SendError> {
+        let req = self.client.post(&self.url);
+        let mut req = match readings {
+            [reading] if !self.batched => req.json(reading),
+            _ => req.json(&BatchPayload {
+                version: BATCH_VERSION,
+                readings,
+            }),
+        };
+
+        // Include `x-api-key` if one was configured
+        if let Some(api_key) = &self.api_key {
+            req = req.header("x-api-key", api_key);
+        }
+
+        let resp = req.send()?;
+        if !resp.status().is_success() {
+            return Err(SendError::from_response(&resp));
+        }
+        Ok(())
+    }
+}
+
+/// Append-only JSONL file for readings that could not be delivered.
+pub struct DeadLetter {
+    path: PathBuf,
+    file: File,
+}
+
+#[derive(Serialize)]
+struct DeadLetterRecord<'a> {
+    reason: String,
+    reading: &'a Reading,
+}
+
+impl DeadLetter {
+    pub fn open(path: &Path) -> Result<Self> {
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+            .with_context(|| format!("Failed to open dead-letter file '{}'", path.display()))?;
+        Ok(Self {
+            path: path.to_path_buf(),
+            file,
+        })
+    }
+
+    pub fn write(&mut self, reading: &Reading, error: &SendError) -> Result<()> {
+        let record = DeadLetterRecord {
+            reason: error.to_string(),
+            reading,
+        };
+        let mut line = serde_json::to_vec(&record)?;
+        line.push(b'\n');
+        self.file
+            .write_all(&line)
+            .with_context(|| format!("Failed to write to '{}'", self.path.display()))
+    }
+}
+
+/// Batches replayed from the outbox per call, so new readings keep flowing
+/// into the outbox while a long backlog is drained.
+const REPLAY_BATCHES: usize = 100;
+
+/// How often the upload thread wakes up to replay the outbox when no new
+/// readings arrive.
+const IDLE_INTERVAL: Duration = Duration::from_secs(1);
+
+/// What to do with a batch the server rejects with a permanent error.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum RejectPolicy {
+    /// Split the batch in halves and resend them, until the readings the
+    /// server refuses are isolated and dead-lettered on their own.
+    Split,
+    /// Dead-letter the whole batch.
+    DeadLetter,
+}
+
+/// How readings are grouped into requests.
+#[derive(Debug, Clone, Copy)]
+pub struct BatchConfig {
+    /// Readings per request; 1 disables batching.
+    pub max_size: usize,
+    /// Longest a reading may wait for its batch to fill up.
+    pub max_latency: Duration,
+    pub on_reject: RejectPolicy,
+}
+
+impl Default for BatchConfig {
+    fn default() -> Self {
+        Self {
+            max_size: 1,
+            max_latency: Duration::from_secs(10),
+            on_reject: RejectPolicy::Split,
+        }
+    }
+}
+
+/// Delivers readings to the server, retrying transient failures.
+///
+/// Without an outbox, a batch is retried in place until it is delivered
+/// or runs out of attempts. With an outbox, a batch that fails is spooled
+/// to disk right away and replayed in order, with backoff, once the server
+/// accepts requests again.
+pub struct Uploader<E> {
+    sink: E,
+    retry: RetryPolicy,
+    batch: BatchConfig,
+    dead_letter: Option<DeadLetter>,
+    outbox: Option<Outbox<Reading>>,
+    /// Readings waiting for the current batch to fill up.
+    pending: Vec<Reading>,
+    /// When the oldest pending reading has to be sent at the latest.
+    flush_at: Option<Instant>,
+    /// Consecutive failed replay attempts, driving the backoff.
+    replay_failures: u32,
+    next_replay: Instant,
+    stats: Arc<SinkStats>,
+    /// Set once the reader shuts down, which ends any retries.
+    stop: Arc<AtomicBool>,
+}
+
+impl<E: Endpoint> Uploader<E> {
+    pub fn new(
+        sink: E,
+        retry: RetryPolicy,
+        batch: BatchConfig,
+        dead_letter: Option<DeadLetter>,
+        outbox: Option<Outbox<Reading>>,
+    ) -> Self {
+        Self {
+            sink,
+            retry,
+            batch,
+            dead_letter,
+            outbox,
+            pending: Vec::new(),
+            flush_at: None,
+            replay_failures: 0,
+            next_replay: Instant::now(),
+            stats: Arc::default(),
+            stop: Arc::default(),
+        }
+    }
+
+    /// Gives up retrying a failed send once `stop` is set, so that a
+    /// shutdown does not wait out the backoff. The batch is spooled or
+    /// dead-lettered as after the last attempt.
+    pub fn stop_retrying_on(mut self, stop: Arc<AtomicBool>) -> Self {
+        self.stop = stop;
+        self
+    }
+
+    /// How long the upload thread may wait for the next reading.
+    fn poll_timeout(&self) -> Duration {
+        match self.flush_at {
+            Some(at) => at
+                .saturating_duration_since(Instant::now())
+                .min(IDLE_INTERVAL),
+            None => IDLE_INTERVAL,
+        }
+    }
+
+    /// Adds a reading to the current batch, sending it once it is full.
+    fn push(&mut self, reading: Reading) {
+        if self.pending.is_empty() {
+            self.flush_at = Some(Instant::now() + self.batch.max_latency);
+        }
+        self.pending.push(reading);
+        if self.pending.len() >= self.batch.max_size {
+            self.flush();
+        }
+    }
+
+    /// Sends the current batch if its latency budget is used up, then
+    /// replays the outbox.
+    fn tick(&mut self) {
+        if self.flush_at.is_some_and(|at| Instant::now() >= at) {
+            self.flush();
+        }
+        self.replay();
+    }
+
+    fn flush(&mut self) {
+        self.flush_at = None;
+        if self.pending.is_empty() {
+            return;
+        }
+        let batch = std::mem::take(&mut self.pending);
+        self.handle(&batch);
+    }
+
+    fn deliver(&self, batch: &[Reading]) -> Result<(), SendError> {
+        let mut attempt = 1;
+        loop {
+            match self.sink.send(batch) {
+                Ok(()) => return Ok(()),
+                Err(SendError::Retryable {
+                    reason,
+                    retry_after,
+                }) if attempt < self.retry.max_attempts && !self.stop.load(Ordering::Relaxed) => {
+                    // The sink thread sleeps here while its queue fills up, so
+                    // the server cannot ask for more than the configured limit
+                    let delay = retry_after
+                        .map(|delay| delay.min(self.retry.max_delay))
+                        .unwrap_or_else(|| self.retry.delay(attempt));
+                    warn!(
+                        "Failed to send to server: {}; retrying in {:.1}s (attempt {}/{})",
+                        reason,
+                        delay.as_secs_f32(),
+                        attempt,
+                        self.retry.max_attempts
+                    );
+                    if !sleep_unless_stopped(delay, &self.stop) {
+                        return Err(SendError::Retryable {
+                            reason,
+                            retry_after,
+                        });
+                    }
+                    attempt += 1;
+                }
+                Err(e) => return Err(e),
+            }
+        }
+    }
+
+    fn report_sent(&self, batch: &[Reading]) {
+        self.stats.delivered(batch.len());
+        match batch.len() {
+            1 => info!("Sent to server"),
+            n => info!("Sent {} readings to server", n),
+        }
+    }
+
+    fn dead_letter(&mut self, batch: &[Reading], error: &SendError) {
+        error!("Failed to send to server: {}", error);
+        self.stats.failed(batch.len());
+        let Some(dead_letter) = &mut self.dead_letter else {
+            return;
+        };
+        for reading in batch {
+            if let Err(e) = dead_letter.write(reading, error) {
+                error!("{:#}", e);
+                return;
+            }
+        }
+    }
+
+    /// Sends a batch on the way to being settled: once if an outbox can
+    /// take it on failure, otherwise with retries in place.
+    fn attempt(&self, batch: &[Reading]) -> Result<(), SendError> {
+        if self.outbox.is_some() {
+            self.sink.send(batch)
+        } else {
+            self.deliver(batch)
+        }
+    }
+
+    /// Handles a permanent rejection according to the batch reject policy.
+    ///
+    /// Returns how many readings at the front of `batch` were settled, i.e.
+    /// sent or dead-lettered. With an outbox, splitting stops at the first
+    /// retryable failure and the caller keeps the rest for a later replay,
+    /// ahead of anything newer.
+    fn reject(&mut self, batch: &[Reading], error: &SendError) -> usize {
+        if self.batch.on_reject == RejectPolicy::DeadLetter || batch.len() == 1 {
+            self.dead_letter(batch, error);
+            return batch.len();
+        }
+
+        warn!(
+            "Server rejected a batch of {} readings ({}); splitting it",
+            batch.len(),
+            error
+        );
+        let (left, right) = batch.split_at(batch.len() / 2);
+        let mut settled = 0;
+        for half in [left, right] {
+            match self.attempt(half) {
+                Ok(()) => self.report_sent(half),
+                Err(e @ SendError::Permanent(_)) => {
+                    let n = self.reject(half, &e);
+                    if n < half.len() {
+                        return settled + n;
+                    }
+                }
+                Err(e) if self.outbox.is_some() => {
+                    warn!("Failed to send to server: {}; keeping it in the outbox", e);
+                    self.backoff(&e);
+                    return settled;
+                }
+                Err(e) => self.dead_letter(half, &e),
+            }
+            settled += half.len();
+        }
+        settled
+    }
+
+    fn handle(&mut self, batch: &[Reading]) {
+        let Some(outbox) = &mut self.outbox else {
+            match self.deliver(batch) {
+                Ok(()) => self.report_sent(batch),
+                Err(e @ SendError::Permanent(_)) => {
+                    self.reject(batch, &e);
+                }
+                Err(e) => self.dead_letter(batch, &e),
+            }
+            return;
+        };
+
+        // Anything already spooled has to go out first to keep the order
+        if !outbox.is_empty() {
+            self.spool(batch);
+            self.replay();
+            return;
+        }
+
+        match self.sink.send(batch) {
+            Ok(()) => self.report_sent(batch),
+            Err(e @ SendError::Retryable { .. }) => {
+                warn!("Failed to send to server: {}; storing in outbox", e);
+                self.spool(batch);
+                self.backoff(&e);
+            }
+            Err(e) => {
+                let settled = self.reject(batch, &e);
+                self.spool(&batch[settled..]);
+            }
+        }
+    }
+
+    fn spool(&mut self, batch: &[Reading]) {
+        let Some(outbox) = &mut self.outbox else {
+            return;
+        };
+        for reading in batch {
+            if let Err(e) = outbox.append(reading) {
+                error!("Failed to store reading in outbox: {:#}", e);
+                return;
+            }
+        }
+    }
+
+    fn backoff(&mut self, error: &SendError) {
+        self.replay_failures += 1;
+        // New readings pile up in the outbox meanwhile, so the server cannot
+        // hold off the replay for longer than the configured limit either
+        let delay = match error {
+            SendError::Retryable {
+                retry_after: Some(delay),
+                ..
+            } => (*delay).min(self.retry.max_delay),
+            _ => self.retry.delay(self.replay_failures),
+        };
+        self.next_replay = Instant::now() + delay;
+    }
+
+    /// Sends spooled readings, oldest first, until the outbox is empty or the
+    /// server fails again.
+    fn replay(&mut self) {
+        if Instant::now() < self.next_replay {
+            return;
+        }
+
+        for _ in 0..REPLAY_BATCHES {
+            let Some(outbox) = &mut self.outbox else {
+                return;
+            };
+            let batch = match outbox.peek(self.batch.max_size) {
+                Ok(batch) if batch.is_empty() => return,
+                Ok(batch) => batch,
+                Err(e) => {
+                    error!("Failed to read outbox: {:#}", e);
+                    return;
+                }
+            };
+
+            let settled = match self.sink.send(&batch) {
+                Ok(()) => {
+                    self.replay_failures = 0;
+                    self.report_sent(&batch);
+                    batch.len()
+                }
+                Err(e @ SendError::Retryable { .. }) => {
+                    warn!("Failed to replay outbox: {}", e);
+                    self.backoff(&e);
+                    return;
+                }
+                Err(e) => self.reject(&batch, &e),
+            };
+
+            // What is left of a split batch stays at the head of the outbox
+            if let Some(outbox) = &mut self.outbox
+                && let Err(e) = outbox.ack(settled)
+            {
+                error!("Failed to update outbox: {:#}", e);
+                return;
+            }
+            if settled < batch.len() {
+                return;
+            }
+        }
+    }
+}
+
+/// Uploads readings from `queue` until it is closed and drained.
+pub fn run<E: Endpoint>(
+    queue: &BoundedQueue<Reading>,
+    stats: Arc<SinkStats>,
+    mut uploader: Uploader<E>,
+) {
+    uploader.stats = stats;
+    loop {
+        match queue.pop_timeout(uploader.poll_timeout()) {
+            Some(reading) => uploader.push(reading),
+            None if queue.is_closed() => {
+                uploader.flush();
+                break;
+            }
+            None => uploader.tick(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::outbox::OutboxConfig;
+    use sensor_reader::{DeviceInfo, SensorData, Stamper};
+    use std::cell::RefCell;
+    use std::net::TcpListener;
+
+    type Respond = Box<dyn FnMut(&[u64]) -> Result<(), SendError> + Send>;
+
+    /// Answers every request from a script and records the `seq` of the
+    /// readings in it.
+    struct Mock {
+        respond: RefCell<Respond>,
+        calls: RefCell<Vec<Vec<u64>>>,
+    }
+
+    impl Mock {
+        fn new(respond: impl FnMut(&[u64]) -> Result<(), SendError> + Send + 'static) -> Self {
+            Self {
+                respond: RefCell::new(Box::new(respond)),
+                calls: RefCell::default(),
+            }
+        }
+    }
+
+    impl Endpoint for Mock {
+        fn send(&self, readings: &[Reading]) -> Result<(), SendError> {
+            let seqs: Vec<u64> = readings.iter().map(|r| r.seq).collect();
+            let result = (self.respond.borrow_mut())(&seqs);
+            self.calls.borrow_mut().push(seqs);
+            result
+        }
+    }
+
+    fn retryable(retry_after: Option<Duration>) -> SendError {
+        SendError::Retryable {
+            reason: "server returned error: 503 Service Unavailable".into(),
+            retry_after,
+        }
+    }
+
+    fn permanent() -> SendError {
+        SendError::Permanent("server returned error: 400 Bad Request".into())
+    }
+
+    fn retry() -> RetryPolicy {
+        RetryPolicy {
+            max_attempts: 3,
+            initial_delay: Duration::ZERO,
+            max_delay: Duration::from_millis(10),
+        }
+    }
+
+    /// The next `n` readings from `stamper`.
+    fn readings(stamper: &mut Stamper, n: usize) -> Vec<Reading> {
+        (0..n)
+            .map(|_| {
+                stamper.stamp(SensorData {
+                    eco2: 400,
+                    ech2o: 5,
+                    tvoc: 10,
+                    pm2_5: 20,
+                    pm10: 30,
+                    temperature: 25.5,
+                    humidity: 50.2,
+                })
+            })
+            .collect()
+    }
+
+    fn dead_lettered(path: &Path) -> Vec<u64> {
+        std::fs::read_to_string(path)
+            .unwrap()
+            .lines()
+            .map(|line| {
+                let record: serde_json::Value = serde_json::from_str(line).unwrap();
+                record["reading"]["seq"].as_u64().unwrap()
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_classifies_client_errors() {
+        let client = Client::new();
+        let malformed = client.post("http://[::1").send().unwrap_err();
+        assert!(matches!(
+            SendError::from(malformed),
+            SendError::Permanent(_)
+        ));
+
+        // Nothing listens on the port of a dropped listener
+        let port = TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap()
+            .port();
+        let refused = client
+            .post(format!("http://127.0.0.1:{port}"))
+            .send()
+            .unwrap_err();
+        assert!(matches!(
+            SendError::from(refused),
+            SendError::Retryable { .. }
+        ));
+    }
+
+    #[test]
+    fn test_retries_transient_errors_then_dead_letters() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("dead.jsonl");
+        let mut uploader = Uploader::new(
+            Mock::new(|_| Err(retryable(None))),
+            retry(),
+            BatchConfig::default(),
+            Some(DeadLetter::open(&path).unwrap()),
+            None,
+        );
+        for reading in readings(&mut Stamper::new(DeviceInfo::default()), 1) {
+            uploader.push(reading);
+        }
+
+        assert_eq!(uploader.sink.calls.borrow().len(), 3);
+        assert_eq!(dead_lettered(&path), [0]);
+        assert_eq!(uploader.stats.failed.load(Ordering::Relaxed), 1);
+    }
+
+    #[test]
+    fn test_stops_retrying_on_shutdown() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("dead.jsonl");
+        let stop = Arc::new(AtomicBool::new(false));
+        let mut uploader = Uploader::new(
+            Mock::new({
+                let stop = Arc::clone(&stop);
+                move |_| {
+                    stop.store(true, Ordering::Relaxed);
+                    Err(retryable(Some(Duration::from_secs(60))))
+                }
+            }),
+            RetryPolicy {
+                max_delay: Duration::from_secs(60),
+                ..retry()
+            },
+            BatchConfig::default(),
+            Some(DeadLetter::open(&path).unwrap()),
+            None,
+        )
+        .stop_retrying_on(stop);
+        let started = Instant::now();
+        for reading in readings(&mut Stamper::new(DeviceInfo::default()), 1) {
+            uploader.push(reading);
+        }
+
+        assert!(started.elapsed() < Duration::from_secs(1));
+        assert_eq!(uploader.sink.calls.borrow().len(), 1);
+        assert_eq!(dead_lettered(&path), [0]);
+    }
+
+    #[test]
+    fn test_permanent_errors_are_not_retried() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("dead.jsonl");
+        let mut uploader = Uploader::new(
+            Mock::new(|_| Err(permanent())),
+            retry(),
+            BatchConfig::default(),
+            Some(DeadLetter::open(&path).unwrap()),
+            None,
+        );
+        for reading in readings(&mut Stamper::new(DeviceInfo::default()), 2) {
+            uploader.push(reading);
+        }
+
+        assert_eq!(*uploader.sink.calls.borrow(), [vec![0], vec![1]]);
+        assert_eq!(dead_lettered(&path), [0, 1]);
+    }
+
+    #[test]
+    fn test_retry_after_is_capped() {
+        let mut failed = false;
+        let mut uploader = Uploader::new(
+            Mock::new(move |_| {
+                if failed {
+                    return Ok(());
+                }
+                failed = true;
+                Err(retryable(Some(Duration::from_secs(86_400))))
+            }),
+            retry(),
+            BatchConfig::default(),
+            None,
+            None,
+        );
+        let started = Instant::now();
+        for reading in readings(&mut Stamper::new(DeviceInfo::default()), 1) {
+            uploader.push(reading);
+        }
+
+        assert!(started.elapsed() < Duration::from_secs(1));
+        assert_eq!(uploader.sink.calls.borrow().len(), 2);
+        assert_eq!(uploader.stats.delivered.load(Ordering::Relaxed), 1);
+    }
+
+    #[test]
+    fn test_replay_retry_after_is_capped() {
+        let dir = tempfile::tempdir().unwrap();
+        let outbox = Outbox::open(&dir.path().join("outbox"), OutboxConfig::default()).unwrap();
+        let mut uploader = Uploader::new(
+            Mock::new(|_| Err(retryable(Some(Duration::from_secs(86_400))))),
+            retry(),
+            BatchConfig::default(),
+            None,
+            Some(outbox),
+        );
+        for reading in readings(&mut Stamper::new(DeviceInfo::default()), 1) {
+            uploader.push(reading);
+        }
+
+        assert_eq!(uploader.sink.calls.borrow().len(), 1);
+        assert!(uploader.next_replay <= Instant::now() + retry().max_delay);
+    }
+
+    #[test]
+    fn test_batches_fill_up_or_flush() {
+        let mut uploader = Uploader::new(
+            Mock::new(|_| Ok(())),
+            retry(),
+            BatchConfig {
+                max_size: 3,
+                ..BatchConfig::default()
+            },
+            None,
+            None,
+        );
+        for reading in readings(&mut Stamper::new(DeviceInfo::default()), 4) {
+            uploader.push(reading);
+        }
+        assert_eq!(*uploader.sink.calls.borrow(), [vec![0, 1, 2]]);
+        uploader.flush();
+        assert_eq!(*uploader.sink.calls.borrow(), [vec![0, 1, 2], vec![3]]);
+    }
+
+    #[test]
+    fn test_split_rejected_batch() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("dead.jsonl");
+        // The server refuses any request holding reading 2
+        let mut uploader = Uploader::new(
+            Mock::new(|seqs| match seqs.contains(&2) {
+                true => Err(permanent()),
+                false => Ok(()),
+            }),
+            retry(),
+            BatchConfig {
+                max_size: 4,
+                ..BatchConfig::default()
+            },
+            Some(DeadLetter::open(&path).unwrap()),
+            None,
+        );
+        for reading in readings(&mut Stamper::new(DeviceInfo::default()), 4) {
+            uploader.push(reading);
+        }
+
+        assert_eq!(
+            *uploader.sink.calls.borrow(),
+            [vec![0, 1, 2, 3], vec![0, 1], vec![2, 3], vec![2], vec![3]]
+        );
+        assert_eq!(dead_lettered(&path), [2]);
+        assert_eq!(uploader.stats.delivered.load(Ordering::Relaxed), 3);
+    }
+
+    #[test]
+    fn test_replay_keeps_order() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("dead.jsonl");
+        let outbox = Outbox::open(&dir.path().join("outbox"), OutboxConfig::default()).unwrap();
+        let mut calls = 0;
+        let mut uploader = Uploader::new(
+            Mock::new(move |seqs| {
+                calls += 1;
+                match seqs {
+                    // The server is down for the first request
+                    _ if calls == 1 => Err(retryable(None)),
+                    // It refuses reading 2, and fails once more on the other
+                    // half of the split batch
+                    _ if seqs.contains(&2) => Err(permanent()),
+                    [3] if calls == 6 => Err(retryable(None)),
+                    _ => Ok(()),
+                }
+            }),
+            retry(),
+            BatchConfig {
+                max_size: 4,
+                ..BatchConfig::default()
+            },
+            Some(DeadLetter::open(&path).unwrap()),
+            Some(outbox),
+        );
+        let mut stamper = Stamper::new(DeviceInfo::default());
+        // The second batch is spooled behind the first and starts the replay
+        for reading in readings(&mut stamper, 8) {
+            uploader.push(reading);
+        }
+        uploader.tick();
+
+        let calls = uploader.sink.calls.borrow();
+        assert_eq!(
+            calls[..6],
+            [
+                vec![0, 1, 2, 3],
+                vec![0, 1, 2, 3],
+                vec![0, 1],
+                vec![2, 3],
+                vec![2],
+                vec![3]
+            ]
+        );
+        // Reading 3 goes out again before any newer reading
+        let delivered: Vec<u64> = [vec![0, 1]]
+            .iter()
+            .chain(&calls[6..])
+            .flatten()
+            .copied()
+            .collect();
+        assert_eq!(delivered, [0, 1, 3, 4, 5, 6, 7]);
+        assert_eq!(dead_lettered(&path), [2]);
+        assert!(uploader.outbox.as_ref().unwrap().is_empty());
+    }
+}
@@ -0,0 +1,421 @@
+//! Writes readings to InfluxDB in line protocol.
+
+use anyhow::{Context, Result, bail};
+use flate2::Compression;
+use flate2::write::GzEncoder;
+use reqwest::Url;
+use reqwest::blocking::{Client, RequestBuilder};
+use reqwest::header::{AUTHORIZATION, CONTENT_ENCODING, CONTENT_TYPE};
+use sensor_reader::{AQI_FIELDS, COMFORT_FIELDS, DeviceInfo, FIELD_NAMES, Reading};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fmt::Write as _;
+use std::io::Write as _;
+
+use crate::config::InfluxSinkConfig;
+use crate::sinks::http::{Endpoint, SendError};
+
+/// Which InfluxDB write API to use.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum InfluxVersion {
+    /// `/write` with a database and optional retention policy.
+    V1,
+    /// `/api/v2/write` with an organization, bucket and token.
+    V2,
+}
+
+/// Fields the sensor reports as integers; the rest are floats.
+const INTEGER_FIELDS: [&str; 5] = ["eco2", "ech2o", "tvoc", "pm2_5", "pm10"];
+
+/// Escapes `chars` and backslashes with a backslash.
+fn escape(value: &str, chars: &[char]) -> String {
+    let mut out = String::with_capacity(value.len());
+    for c in value.chars() {
+        if c == '\\' || chars.contains(&c) {
+            out.push('\\');
+        }
+        out.push(c);
+    }
+    out
+}
+
+/// Substitutes `{device_id}`, `{location}` and `{tag.NAME}` in a tag value
+/// template. Unknown placeholders render as empty.
+fn render_tag(template: &str, device: &DeviceInfo) -> String {
+    let mut value = template
+        .replace("{device_id}", &device.id)
+        .replace("{location}", device.location.as_deref().unwrap_or(""));
+    for (name, tag) in &device.tags {
+        value = value.replace(&format!("{{tag.{}}}", name), tag);
+    }
+    while let Some(start) = value.find("{tag.")
+        && let Some(len) = value[start..].find('}')
+    {
+        value.replace_range(start..start + len + 1, "");
+    }
+    value
+}
+
+/// Renders readings as line protocol.
+#[derive(Debug, Clone)]
+pub struct LineFormat {
+    measurement: String,
+    /// Tag key to value template, see [`render_tag`].
+    tags: BTreeMap<String, String>,
+    /// Reading field to Influx field key; every field under its own name if
+    /// empty.
+    fields: BTreeMap<String, String>,
+}
+
+impl LineFormat {
+    pub fn new(
+        measurement: String,
+        tags: BTreeMap<String, String>,
+        fields: BTreeMap<String, String>,
+    ) -> Self {
+        Self {
+            measurement,
+            tags,
+            fields,
+        }
+    }
+
+    /// Appends one line for `reading`, timestamped in nanoseconds.
+    ///
+    /// Tags that render empty are left out, since InfluxDB rejects empty tag
+    /// values. For the same reason nothing is written if none of the mapped
+    /// fields is set, e.g. only a comfort metric that is not configured.
+    pub fn write(&self, out: &mut String, reading: &Reading) {
+        let start = out.len();
+        out.push_str(&escape(&self.measurement, &[',', ' ']));
+        for (key, template) in &self.tags {
+            let value = render_tag(template, &reading.device);
+            if !value.is_empty() {
+                let _ = write!(
+                    out,
+                    ",{}={}",
+                    escape(key, &[',', '=', ' ']),
+                    escape(&value, &[',', '=', ' '])
+                );
+            }
+        }
+
+        let mut separator = ' ';
+        let fields = reading.data.fields().into_iter();
+        let derived = reading
+            .comfort
+            .fields()
+            .into_iter()
+            .chain(reading.aqi_fields());
+        for (name, value) in fields.chain(derived) {
+            let key = if self.fields.is_empty() {
+                name
+            } else {
+                match self.fields.get(name) {
+                    Some(key) => key.as_str(),
+                    None => continue,
+                }
+            };
+            let _ = write!(
+                out,
+                "{}{}={}",
+                separator,
+                escape(key, &[',', '=', ' ']),
+                value
+            );
+            if INTEGER_FIELDS.contains(&name) || AQI_FIELDS.contains(&name) {
+                out.push('i');
+            }
+            separator = ',';
+            // Summaries carry the window mean in the field itself
+            if let Some(stats) = reading.window.as_ref().and_then(|w| w.fields.get(name)) {
+                let key = escape(key, &[',', '=', ' ']);
+                let _ = write!(out, ",{}_min={},{}_max={}", key, stats.min, key, stats.max);
+            }
+        }
+        if separator == ' ' {
+            out.truncate(start);
+            return;
+        }
+        if let Some(window) = &reading.window {
+            let _ = write!(out, "{}samples={}i", separator, window.samples);
+        }
+
+        // Out of range only past the year 2262
+        if let Some(nanos) = reading.captured_at.timestamp_nanos_opt() {
+            let _ = write!(out, " {}", nanos);
+        }
+        out.push('\n');
+    }
+}
+
+/// Checks that every key of a field mapping names a reading field.
+pub fn check_field_mapping(fields: &BTreeMap<String, String>) -> Result<()> {
+    let known: Vec<&str> = FIELD_NAMES
+        .into_iter()
+        .chain(COMFORT_FIELDS)
+        .chain(AQI_FIELDS)
+        .collect();
+    for name in fields.keys() {
+        if !known.contains(&name.as_str()) {
+            bail!(
+                "unknown field '{}', expected one of {}",
+                name,
+                known.join(", ")
+            );
+        }
+    }
+    Ok(())
+}
+
+/// Posts batches of readings to an InfluxDB write endpoint.
+pub struct InfluxSink {
+    client: Client,
+    url: Url,
+    auth: Auth,
+    gzip: bool,
+    format: LineFormat,
+}
+
+enum Auth {
+    None,
+    Basic(String, Option<String>),
+    Token(String),
+}
+
+impl InfluxSink {
+    pub fn new(config: &InfluxSinkConfig) -> Result<Self> {
+        let mut base = Url::parse(&config.url)
+            .with_context(|| format!("Invalid InfluxDB URL '{}'", config.url))?;
+        // Keep a path prefix such as a reverse proxy's when joining below
+        if !base.path().ends_with('/') {
+            let path = format!("{}/", base.path());
+            base.set_path(&path);
+        }
+        // Timestamps are in nanoseconds, which is also what v1 assumes
+        let (url, auth) = match config.version {
+            InfluxVersion::V1 => {
+                let mut url = base.join("write")?;
+                url.query_pairs_mut().append_pair(
+                    "db",
+                    config
+                        .database
+                        .as_deref()
+                        .context("sinks.influx.database is required for InfluxDB v1")?,
+                );
+                if let Some(rp) = &config.retention_policy {
+                    url.query_pairs_mut().append_pair("rp", rp);
+                }
+                let auth = match &config.username {
+                    Some(user) => Auth::Basic(user.clone(), config.password.clone()),
+                    None => Auth::None,
+                };
+                (url, auth)
+            }
+            InfluxVersion::V2 => {
+                let mut url = base.join("api/v2/write")?;
+                url.query_pairs_mut()
+                    .append_pair(
+                        "org",
+                        config
+                            .org
+                            .as_deref()
+                            .context("sinks.influx.org is required for InfluxDB v2")?,
+                    )
+                    .append_pair(
+                        "bucket",
+                        config
+                            .bucket
+                            .as_deref()
+                            .context("sinks.influx.bucket is required for InfluxDB v2")?,
+                    );
+                url.query_pairs_mut().append_pair("precision", "ns");
+                let auth = match &config.token {
+                    Some(token) => Auth::Token(token.clone()),
+                    None => Auth::None,
+                };
+                (url, auth)
+            }
+        };
+
+        Ok(Self {
+            client: Client::builder().use_rustls_tls().build()?,
+            url,
+            auth,
+            gzip: config.gzip,
+            format: LineFormat::new(
+                config.measurement.clone(),
+                config.tags.clone(),
+                config.fields.clone(),
+            ),
+        })
+    }
+
+    fn request(&self, body: String) -> Result<RequestBuilder, SendError> {
+        let mut req = self
+            .client
+            .post(self.url.clone())
+            .header(CONTENT_TYPE, "text/plain; charset=utf-8");
+        req = match &self.auth {
+            Auth::None => req,
+            Auth::Basic(user, password) => req.basic_auth(user, password.as_ref()),
+            Auth::Token(token) => req.header(AUTHORIZATION, format!("Token {}", token)),
+        };
+        if !self.gzip {
+            return Ok(req.body(body));
+        }
+
+        let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+        encoder
+            .write_all(body.as_bytes())
+            .and_then(|_| encoder.finish())
+            .map(|gzipped| req.header(CONTENT_ENCODING, "gzip").body(gzipped))
+            .map_err(|e| SendError::Permanent(format!("failed to compress batch: {}", e)))
+    }
+}
+
+impl Endpoint for InfluxSink {
+    fn send(&self, readings: &[Reading]) -> Result<(), SendError> {
+        let mut body = String::new();
+        for reading in readings {
+            self.format.write(&mut body, reading);
+        }
+        if body.is_empty() {
+            return Ok(());
+        }
+
+        let resp = self.request(body)?.send()?;
+        if !resp.status().is_success() {
+            return Err(SendError::from_response(&resp));
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::{DateTime, TimeDelta};
+    use sensor_reader::{Aqi, AqiStandard, FieldStats, Pollutant, SensorData, Stamper, Window};
+
+    fn reading(location: Option<&str>) -> Reading {
+        let mut stamper = Stamper::new(DeviceInfo {
+            id: "m701 lab".into(),
+            location: location.map(str::to_string),
+            tags: BTreeMap::from([("floor".into(), "2".into())]),
+        });
+        let at = DateTime::parse_from_rfc3339("2024-05-01T12:00:00.123456789Z")
+            .unwrap()
+            .to_utc();
+        stamper.stamp_at(
+            SensorData {
+                eco2: 400,
+                ech2o: 5,
+                tvoc: 10,
+                pm2_5: 20,
+                pm10: 30,
+                temperature: 25.5,
+                humidity: 50.2,
+            },
+            at,
+        )
+    }
+
+    fn tags(pairs: &[(&str, &str)]) -> BTreeMap<String, String> {
+        pairs
+            .iter()
+            .map(|(k, v)| (k.to_string(), v.to_string()))
+            .collect()
+    }
+
+    #[test]
+    fn test_line() {
+        let format = LineFormat::new(
+            "air quality".into(),
+            tags(&[("device", "{device_id}"), ("location", "{location}")]),
+            BTreeMap::new(),
+        );
+        let mut out = String::new();
+        format.write(&mut out, &reading(Some("lab")));
+        format.write(&mut out, &reading(None));
+        assert_eq!(
+            out,
+            "air\\ quality,device=m701\\ lab,location=lab eco2=400i,ech2o=5i,tvoc=10i,pm2_5=20i,pm10=30i,temperature=25.5,humidity=50.2 1714564800123456789\n\
+             air\\ quality,device=m701\\ lab eco2=400i,ech2o=5i,tvoc=10i,pm2_5=20i,pm10=30i,temperature=25.5,humidity=50.2 1714564800123456789\n"
+        );
+    }
+
+    #[test]
+    fn test_field_mapping() {
+        let format = LineFormat::new(
+            "m701".into(),
+            tags(&[("floor", "{tag.floor}"), ("room", "{tag.room}")]),
+            tags(&[("pm2_5", "pm25"), ("temperature", "temp_c")]),
+        );
+        let mut out = String::new();
+        format.write(&mut out, &reading(None));
+        assert_eq!(
+            out,
+            "m701,floor=2 pm25=20i,temp_c=25.5 1714564800123456789\n"
+        );
+
+        // No line rather than one without fields
+        let format = LineFormat::new("m701".into(), BTreeMap::new(), tags(&[("dew_point", "dp")]));
+        let mut out = String::new();
+        format.write(&mut out, &reading(None));
+        assert_eq!(out, "");
+
+        assert!(check_field_mapping(&tags(&[("pm2_5", "pm25")])).is_ok());
+        assert!(check_field_mapping(&tags(&[("dew_point", "dewpoint")])).is_ok());
+        assert!(check_field_mapping(&tags(&[("pm25", "pm25")])).is_err());
+    }
+
+    #[test]
+    fn test_aqi() {
+        let format = LineFormat::new(
+            "m701".into(),
+            BTreeMap::new(),
+            tags(&[("pm2_5", "pm25"), ("aqi_us_epa", "aqi")]),
+        );
+        let mut reading = reading(None);
+        reading.aqi = vec![Aqi {
+            standard: AqiStandard::UsEpa,
+            index: 57,
+            category: "Moderate".into(),
+            dominant: Some(Pollutant::Pm2_5),
+            pm2_5: 57,
+            pm10: 28,
+        }];
+        let mut out = String::new();
+        format.write(&mut out, &reading);
+        assert_eq!(out, "m701 pm25=20i,aqi=57i 1714564800123456789\n");
+        assert!(check_field_mapping(&tags(&[("aqi_china", "aqi")])).is_ok());
+    }
+
+    #[test]
+    fn test_window_stats() {
+        let format = LineFormat::new("m701".into(), BTreeMap::new(), tags(&[("pm2_5", "pm25")]));
+        let mut summary = reading(None);
+        summary.window = Some(Window {
+            start: summary.captured_at - TimeDelta::minutes(1),
+            end: summary.captured_at,
+            samples: 3,
+            fields: BTreeMap::from([(
+                "pm2_5".to_string(),
+                FieldStats {
+                    min: 10.0,
+                    mean: 20.0,
+                    max: 35.5,
+                    last: 15.0,
+                },
+            )]),
+        });
+        let mut out = String::new();
+        format.write(&mut out, &summary);
+        assert_eq!(
+            out,
+            "m701 pm25=20i,pm25_min=10,pm25_max=35.5,samples=3i 1714564800123456789\n"
+        );
+    }
+}
@@ -0,0 +1,319 @@
+//! Destinations for readings.
+//!
+//! Every sink runs on its own thread behind its own bounded queue, so a slow
+//! or unreachable sink holds up neither the serial reader nor the other sinks.
+//! A sink can be given summaries of time windows instead of every reading,
+//! and only those readings that changed.
+
+pub mod file;
+pub mod homeassistant;
+pub mod http;
+pub mod influx;
+pub mod mqtt;
+pub mod sqlite;
+
+use chrono::TimeDelta;
+use log::{debug, info, warn};
+use sensor_reader::{Aggregator, ChangeFilter, Reading};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::thread::{self, JoinHandle};
+
+use crate::config::{AggregateSection, ChangeSection};
+use crate::queue::{BoundedQueue, OverflowPolicy};
+
+/// Delivery counters a sink worker keeps up to date.
+#[derive(Debug, Default)]
+pub struct SinkStats {
+    delivered: AtomicU64,
+    failed: AtomicU64,
+}
+
+impl SinkStats {
+    /// Counts `n` readings the sink accepted.
+    pub fn delivered(&self, n: usize) {
+        self.delivered.fetch_add(n as u64, Ordering::Relaxed);
+    }
+
+    /// Counts `n` readings given up on.
+    pub fn failed(&self, n: usize) {
+        self.failed.fetch_add(n as u64, Ordering::Relaxed);
+    }
+}
+
+/// Read-only view of a running sink, for reporting.
+#[derive(Clone)]
+pub struct SinkMonitor {
+    pub name: &'static str,
+    queue: Arc<BoundedQueue<Reading>>,
+    stats: Arc<SinkStats>,
+}
+
+impl SinkMonitor {
+    /// Readings waiting in the queue.
+    pub fn queue_depth(&self) -> usize {
+        self.queue.len()
+    }
+
+    /// Readings discarded by the queue's overflow policy.
+    pub fn dropped(&self) -> u64 {
+        self.queue.dropped()
+    }
+
+    pub fn delivered(&self) -> u64 {
+        self.stats.delivered.load(Ordering::Relaxed)
+    }
+
+    pub fn failed(&self) -> u64 {
+        self.stats.failed.load(Ordering::Relaxed)
+    }
+}
+
+/// A sink worker thread and the queue feeding it.
+pub struct SinkHandle {
+    name: &'static str,
+    queue: Arc<BoundedQueue<Reading>>,
+    stats: Arc<SinkStats>,
+    worker: JoinHandle<()>,
+    aggregator: Option<Aggregator>,
+    /// Applied after the aggregator, so windows still see every reading.
+    change: Option<ChangeFilter>,
+}
+
+impl SinkHandle {
+    /// Starts `worker` on a new thread, fed by a queue of `capacity`
+    /// readings. The worker should return once the queue is closed and
+    /// drained.
+    pub fn spawn<F>(name: &'static str, capacity: usize, policy: OverflowPolicy, worker: F) -> Self
+    where
+        F: FnOnce(&BoundedQueue<Reading>, Arc<SinkStats>) + Send + 'static,
+    {
+        let queue = Arc::new(BoundedQueue::new(capacity, policy));
+        let stats = Arc::new(SinkStats::default());
+        let worker = {
+            let queue = Arc::clone(&queue);
+            let stats = Arc::clone(&stats);
+            thread::spawn(move || worker(&queue, stats))
+        };
+        Self {
+            name,
+            queue,
+            stats,
+            worker,
+            aggregator: None,
+            change: None,
+        }
+    }
+
+    /// Queues window summaries as configured in `aggregate` instead of
+    /// every reading.
+    pub fn aggregate(mut self, aggregate: &AggregateSection) -> Self {
+        if aggregate.window_secs > 0 {
+            let window = TimeDelta::seconds(aggregate.window_secs as i64);
+            let step = aggregate
+                .step_secs
+                .map_or(window, |step| TimeDelta::seconds(step as i64));
+            self.aggregator = Some(Aggregator::new(window, step));
+        }
+        self
+    }
+
+    /// Queues only the readings, or window summaries, that changed as
+    /// configured in `change`.
+    pub fn change(mut self, change: &ChangeSection) -> Self {
+        if change.enabled {
+            let heartbeat = (change.heartbeat_secs > 0)
+                .then(|| TimeDelta::seconds(change.heartbeat_secs as i64));
+            self.change = Some(ChangeFilter::new(change.deadband.clone(), heartbeat));
+        }
+        self
+    }
+
+    pub fn monitor(&self) -> SinkMonitor {
+        SinkMonitor {
+            name: self.name,
+            queue: Arc::clone(&self.queue),
+            stats: Arc::clone(&self.stats),
+        }
+    }
+
+    fn send(&mut self, reading: &Reading) {
+        let readings = match &mut self.aggregator {
+            Some(aggregator) => aggregator.push(reading.clone()),
+            None => vec![reading.clone()],
+        };
+        for reading in readings {
+            self.forward(reading);
+        }
+    }
+
+    /// Queues `reading` unless the change filter holds it back.
+    fn forward(&mut self, reading: Reading) {
+        if self.change.as_mut().is_none_or(|c| c.accept(&reading)) {
+            self.push(reading);
+        } else {
+            debug!(
+                "#{} unchanged, not sending it to {}",
+                reading.seq, self.name
+            );
+        }
+    }
+
+    fn push(&self, reading: Reading) {
+        if !self.queue.push(reading) {
+            warn!(
+                "{} queue full, dropped {} readings so far",
+                self.name,
+                self.queue.dropped()
+            );
+        }
+    }
+
+    /// Stops accepting readings; the worker still delivers what is queued,
+    /// including a summary of the unfinished window.
+    fn close(&mut self) {
+        if let Some(summary) = self.aggregator.as_mut().and_then(Aggregator::flush) {
+            self.forward(summary);
+        }
+        if self.queue.len() > 0 {
+            info!(
+                "Flushing {} queued readings to {}...",
+                self.queue.len(),
+                self.name
+            );
+        }
+        self.queue.close();
+    }
+
+    fn join(self) {
+        let _ = self.worker.join();
+        if self.queue.dropped() > 0 {
+            warn!(
+                "Dropped {} readings on {} queue overflow",
+                self.queue.dropped(),
+                self.name
+            );
+        }
+    }
+}
+
+/// All enabled sinks.
+#[derive(Default)]
+pub struct Sinks(Vec<SinkHandle>);
+
+impl Sinks {
+    pub fn add(&mut self, sink: SinkHandle) {
+        self.0.push(sink);
+    }
+
+    pub fn monitors(&self) -> Vec<SinkMonitor> {
+        self.0.iter().map(SinkHandle::monitor).collect()
+    }
+
+    /// Queues a copy of `reading` for every sink.
+    pub fn send(&mut self, reading: &Reading) {
+        for sink in &mut self.0 {
+            sink.send(reading);
+        }
+    }
+
+    /// Shuts every sink down, letting each finish what is already queued.
+    pub fn shutdown(mut self) {
+        for sink in &mut self.0 {
+            sink.close();
+        }
+        for sink in self.0 {
+            sink.join();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::{DateTime, Utc};
+    use sensor_reader::{Deadband, DeviceInfo, SensorData, Stamper};
+    use std::collections::BTreeMap;
+    use std::sync::Mutex;
+
+    fn at(secs: i64) -> DateTime<Utc> {
+        DateTime::from_timestamp(1_714_564_800 + secs, 0).unwrap()
+    }
+
+    /// A sink that keeps what it is sent.
+    fn collector(name: &'static str) -> (SinkHandle, Arc<Mutex<Vec<Reading>>>) {
+        let received = Arc::new(Mutex::new(Vec::new()));
+        let handle = {
+            let received = Arc::clone(&received);
+            SinkHandle::spawn(name, 100, OverflowPolicy::DropOldest, move |queue, _| {
+                while let Some(reading) = queue.pop() {
+                    received.lock().unwrap().push(reading);
+                }
+            })
+        };
+        (handle, received)
+    }
+
+    #[test]
+    fn test_change_filter_after_aggregation() {
+        let (raw, raw_received) = collector("raw");
+        let (summary, summary_received) = collector("summary");
+        let summary = summary
+            .aggregate(&AggregateSection {
+                window_secs: 10,
+                step_secs: None,
+            })
+            .change(&ChangeSection {
+                enabled: true,
+                heartbeat_secs: 0,
+                deadband: BTreeMap::from([(
+                    "pm2_5".to_string(),
+                    Deadband {
+                        absolute: 5.0,
+                        relative: 0.0,
+                    },
+                )]),
+            });
+        let mut sinks = Sinks::default();
+        sinks.add(raw);
+        sinks.add(summary);
+
+        let mut stamper = Stamper::new(DeviceInfo::default());
+        for secs in 0..40 {
+            let pm2_5 = match secs {
+                5 => 29,
+                20.. => 40,
+                _ => 20,
+            };
+            let data = SensorData {
+                eco2: 400,
+                ech2o: 5,
+                tvoc: 10,
+                pm2_5,
+                pm10: 30,
+                temperature: 20.0,
+                humidity: 50.0,
+            };
+            sinks.send(&stamper.stamp_at(data, at(secs)));
+        }
+        sinks.shutdown();
+
+        // The sink without either setting keeps the raw data
+        assert_eq!(raw_received.lock().unwrap().len(), 40);
+
+        let summaries = summary_received.lock().unwrap();
+        let sent: Vec<_> = summaries
+            .iter()
+            .map(|s| (s.captured_at, s.data.pm2_5))
+            .collect();
+        // The window ending at 20 (mean 20) and the flushed one ending at 40
+        // (mean 40) are within the deadband of the last summary sent
+        assert_eq!(sent, [(at(10), 21), (at(30), 40)]);
+
+        // The unchanged readings within a window still count towards it
+        let window = summaries[0].window.as_ref().unwrap();
+        assert_eq!(window.samples, 10);
+        assert_eq!(window.fields["pm2_5"].mean, 20.9);
+        assert_eq!(window.fields["pm2_5"].max, 29.0);
+    }
+}
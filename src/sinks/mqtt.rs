@@ -0,0 +1,656 @@
+//! Publishes readings to an MQTT broker.
+
+use anyhow::{Context, Result, bail};
+use log::{error, info, warn};
+use rumqttc::{
+    Client, Connection, Event, LastWill, MqttOptions, Outgoing, Packet, PubAck, PubComp, QoS,
+    TlsConfiguration, Transport,
+};
+use sensor_reader::{AqiStandard, ComfortMetric, DeviceInfo, Reading};
+use std::collections::{HashMap, VecDeque};
+use std::fs;
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+use crate::config::MqttSinkConfig;
+use crate::queue::BoundedQueue;
+use crate::retry::{RetryPolicy, sleep_unless_stopped};
+use crate::sinks::{SinkStats, homeassistant};
+
+/// Requests buffered between the publisher and the connection thread.
+const REQUEST_CAPACITY: usize = 100;
+
+/// Published (retained) on the availability topic once connected.
+const ONLINE: &str = "online";
+/// Published on the availability topic on shutdown, and by the broker as
+/// the last will if the connection drops.
+const OFFLINE: &str = "offline";
+
+/// How long closing the sink waits for the broker to acknowledge the
+/// readings still in flight.
+const CLOSE_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Splits a broker URL into host, port and whether to connect over TLS.
+pub fn parse_broker_url(url: &str) -> Result<(String, u16, bool)> {
+    let url = reqwest::Url::parse(url)?;
+    let tls = match url.scheme() {
+        "mqtt" | "tcp" => false,
+        "mqtts" | "ssl" => true,
+        other => bail!(
+            "unsupported scheme '{}', expected mqtt:// or mqtts://",
+            other
+        ),
+    };
+    let host = url
+        .host_str()
+        .filter(|host| !host.is_empty())
+        .context("missing broker host")?;
+    let port = url.port().unwrap_or(if tls { 8883 } else { 1883 });
+    Ok((host.to_string(), port, tls))
+}
+
+/// Returns `true` if `topic` can be published to: non-empty and free of the
+/// `+` and `#` wildcards.
+pub fn is_valid_topic(topic: &str) -> bool {
+    !topic.is_empty() && !topic.contains(['+', '#'])
+}
+
+/// Substitutes `{device_id}`, `{location}` and `{field}` in `template`.
+///
+/// A device without a location is published under `unknown`.
+pub fn render_topic(template: &str, device: &DeviceInfo, field: &str) -> String {
+    template
+        .replace("{device_id}", &device.id)
+        .replace(
+            "{location}",
+            device.location.as_deref().unwrap_or("unknown"),
+        )
+        .replace("{field}", field)
+}
+
+/// Builds the messages for `reading`: one plain value per field, derived
+/// metric and air quality index if the template contains `{field}`,
+/// otherwise the whole reading as JSON.
+fn messages(template: &str, reading: &Reading) -> Result<Vec<(String, Vec<u8>)>> {
+    if template.contains("{field}") {
+        Ok(reading
+            .data
+            .fields()
+            .iter()
+            .chain(&reading.comfort.fields())
+            .chain(&reading.aqi_fields())
+            .map(|(name, value)| {
+                (
+                    render_topic(template, &reading.device, name),
+                    value.to_string().into_bytes(),
+                )
+            })
+            .collect())
+    } else {
+        Ok(vec![(
+            render_topic(template, &reading.device, ""),
+            serde_json::to_vec(reading)?,
+        )])
+    }
+}
+
+fn qos(level: u8) -> Result<QoS> {
+    match level {
+        0 => Ok(QoS::AtMostOnce),
+        1 => Ok(QoS::AtLeastOnce),
+        2 => Ok(QoS::ExactlyOnce),
+        _ => bail!("invalid MQTT QoS {}", level),
+    }
+}
+
+fn tls_config(config: &MqttSinkConfig) -> Result<TlsConfiguration> {
+    let read = |path: &Path| {
+        fs::read(path).with_context(|| format!("Failed to read '{}'", path.display()))
+    };
+    let client_auth = match (&config.client_cert, &config.client_key) {
+        (Some(cert), Some(key)) => Some((read(cert)?, read(key)?)),
+        _ => None,
+    };
+    match &config.ca_file {
+        Some(ca) => Ok(TlsConfiguration::Simple {
+            ca: read(ca)?,
+            alpn: None,
+            client_auth,
+        }),
+        None if client_auth.is_none() => Ok(TlsConfiguration::default()),
+        None => bail!("sinks.mqtt.ca_file is required for client certificate authentication"),
+    }
+}
+
+/// Messages published on every (re)connect.
+struct Announcement {
+    availability_topic: String,
+    qos: QoS,
+    /// Home Assistant discovery messages, if enabled.
+    discovery: Vec<(String, Vec<u8>)>,
+    /// Home Assistant's own status topic, watched to republish `discovery`.
+    discovery_status_topic: Option<String>,
+}
+
+impl Announcement {
+    fn publish_discovery(&self, client: &Client, deliveries: &mut Deliveries) {
+        for (topic, payload) in &self.discovery {
+            if let Err(e) = client.try_publish(topic, self.qos, true, payload.clone()) {
+                warn!("Failed to publish Home Assistant discovery: {}", e);
+                return;
+            }
+            deliveries.queued.push_back(Message::Announcement);
+        }
+    }
+
+    // try_publish throughout: blocking here would stall the connection itself
+    fn publish(&self, client: &Client, deliveries: &mut Deliveries) {
+        self.publish_discovery(client, deliveries);
+        match client.try_publish(&self.availability_topic, self.qos, true, ONLINE) {
+            Ok(()) => deliveries.queued.push_back(Message::Announcement),
+            Err(e) => warn!("Failed to publish MQTT availability: {}", e),
+        }
+        if let Some(topic) = &self.discovery_status_topic
+            && let Err(e) = client.try_subscribe(topic, QoS::AtMostOnce)
+        {
+            warn!("Failed to subscribe to {}: {}", topic, e);
+        }
+    }
+}
+
+/// What a message handed to the client belongs to.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Message {
+    Announcement,
+    /// One of the messages of the reading with this id.
+    Reading(u64),
+}
+
+/// Readings handed to the client, counted as delivered once the broker has
+/// acknowledged all of their messages.
+///
+/// The client reports the packet id of every message it sends, in the order
+/// the messages were handed to it, and acknowledgements are matched up by
+/// that id. Messages resent after a reconnect keep theirs.
+#[derive(Default)]
+struct Deliveries {
+    /// Set by [`run`], which owns the counters.
+    stats: Option<Arc<SinkStats>>,
+    /// Messages handed to the client but not sent yet, oldest first.
+    queued: VecDeque<Message>,
+    /// Sent messages awaiting acknowledgement, by packet id.
+    in_flight: HashMap<u16, Message>,
+    /// Unacknowledged message count of every pending reading, by id.
+    pending: HashMap<u64, usize>,
+    next_id: u64,
+}
+
+impl Deliveries {
+    /// Starts tracking a reading of `messages` messages and returns its id.
+    fn add(&mut self, messages: usize) -> u64 {
+        let id = self.next_id;
+        self.next_id += 1;
+        self.pending.insert(id, messages);
+        id
+    }
+
+    /// Counts the message sent with packet id `pkid`, 0 for QoS 0 messages,
+    /// which are never acknowledged.
+    fn sent(&mut self, pkid: u16) {
+        if self.in_flight.contains_key(&pkid) {
+            return;
+        }
+        let Some(message) = self.queued.pop_front() else {
+            return;
+        };
+        if pkid == 0 {
+            self.acked(message);
+        } else {
+            self.in_flight.insert(pkid, message);
+        }
+    }
+
+    /// Counts the acknowledgement of the message sent with packet id `pkid`.
+    fn ack(&mut self, pkid: u16) {
+        if let Some(message) = self.in_flight.remove(&pkid) {
+            self.acked(message);
+        }
+    }
+
+    fn acked(&mut self, message: Message) {
+        let Message::Reading(id) = message else {
+            return;
+        };
+        let Some(left) = self.pending.get_mut(&id) else {
+            return;
+        };
+        *left -= 1;
+        if *left == 0 {
+            self.pending.remove(&id);
+            if let Some(stats) = &self.stats {
+                stats.delivered(1);
+            }
+        }
+    }
+}
+
+/// Connection to the broker, kept alive by a background thread that also
+/// reconnects with backoff whenever the connection drops.
+pub struct MqttSink {
+    client: Client,
+    topic: String,
+    availability_topic: String,
+    qos: QoS,
+    retain: bool,
+    deliveries: Arc<Mutex<Deliveries>>,
+    stopping: Arc<AtomicBool>,
+    connection: JoinHandle<()>,
+}
+
+impl MqttSink {
+    /// Starts connecting to the broker in `config` on behalf of `device`,
+    /// whose readings carry the `comfort` metrics and `aqi` indices.
+    ///
+    /// Returns as soon as the connection thread is running; readings
+    /// published before the broker is reachable wait in the client.
+    pub fn connect(
+        config: &MqttSinkConfig,
+        device: &DeviceInfo,
+        comfort: &[ComfortMetric],
+        aqi: &[AqiStandard],
+    ) -> Result<Self> {
+        let (host, port, tls) = parse_broker_url(&config.url)
+            .with_context(|| format!("Invalid MQTT broker URL '{}'", config.url))?;
+        let client_id = config
+            .client_id
+            .clone()
+            .unwrap_or_else(|| format!("sensor_reader-{}", device.id));
+        let qos = qos(config.qos)?;
+        let availability_topic = render_topic(&config.availability_topic, device, "");
+
+        let mut options = MqttOptions::new(client_id, host, port);
+        options
+            .set_keep_alive(Duration::from_secs(config.keep_alive_secs))
+            .set_last_will(LastWill::new(&availability_topic, OFFLINE, qos, true));
+        if let Some(username) = &config.username {
+            options.set_credentials(username, config.password.clone().unwrap_or_default());
+        }
+        if tls {
+            options.set_transport(Transport::tls_with_config(tls_config(config)?));
+        }
+
+        let (client, connection) = Client::new(options, REQUEST_CAPACITY);
+        let deliveries = Arc::new(Mutex::new(Deliveries::default()));
+        let stopping = Arc::new(AtomicBool::new(false));
+        let backoff = RetryPolicy {
+            max_attempts: u32::MAX,
+            initial_delay: Duration::from_millis(config.reconnect_initial_delay_ms),
+            max_delay: Duration::from_millis(config.reconnect_max_delay_ms),
+        };
+        let discovery = &config.discovery;
+        let announcement = Announcement {
+            availability_topic: availability_topic.clone(),
+            qos,
+            discovery: if discovery.enabled {
+                homeassistant::discovery_messages(
+                    &discovery.prefix,
+                    &config.topic,
+                    &availability_topic,
+                    device,
+                    comfort,
+                    aqi,
+                )
+            } else {
+                Vec::new()
+            },
+            discovery_status_topic: discovery
+                .enabled
+                .then(|| homeassistant::status_topic(&discovery.prefix)),
+        };
+        let connection = {
+            let client = client.clone();
+            let deliveries = Arc::clone(&deliveries);
+            let stopping = Arc::clone(&stopping);
+            thread::spawn(move || {
+                drive(
+                    connection,
+                    &client,
+                    &announcement,
+                    &deliveries,
+                    &backoff,
+                    &stopping,
+                )
+            })
+        };
+
+        info!("Publishing to MQTT broker {}", config.url);
+        Ok(Self {
+            client,
+            topic: config.topic.clone(),
+            availability_topic,
+            qos,
+            retain: config.retain,
+            deliveries,
+            stopping,
+            connection,
+        })
+    }
+
+    /// Queues `reading` for the broker, waiting while the client is backed up.
+    /// It counts as delivered once the broker has acknowledged it, or with
+    /// QoS 0, once it has been sent.
+    ///
+    /// Returns `false` if the reading could not be handed to the client.
+    pub fn publish(&self, reading: &Reading) -> bool {
+        let messages = match messages(&self.topic, reading) {
+            Ok(messages) => messages,
+            Err(e) => {
+                error!("Failed to encode reading #{}: {:#}", reading.seq, e);
+                return false;
+            }
+        };
+        let id = self.deliveries.lock().unwrap().add(messages.len());
+        for (topic, payload) in messages {
+            if !self.send(Message::Reading(id), &topic, payload) {
+                error!("Failed to publish reading #{}", reading.seq);
+                self.deliveries.lock().unwrap().pending.remove(&id);
+                return false;
+            }
+        }
+        true
+    }
+
+    /// Hands one message to the client and queues it in `deliveries` in the
+    /// same step, so that the order of both matches that of the messages the
+    /// connection thread sends. Retries while the client is backed up.
+    ///
+    /// Returns `false` once the connection thread has exited.
+    fn send(&self, message: Message, topic: &str, payload: Vec<u8>) -> bool {
+        loop {
+            {
+                let mut deliveries = self.deliveries.lock().unwrap();
+                if self
+                    .client
+                    .try_publish(topic, self.qos, self.retain, payload.clone())
+                    .is_ok()
+                {
+                    deliveries.queued.push_back(message);
+                    return true;
+                }
+            }
+            if self.connection.is_finished() {
+                return false;
+            }
+            thread::sleep(Duration::from_millis(50));
+        }
+    }
+
+    /// Marks the device offline and disconnects from the broker once it has
+    /// acknowledged the readings in flight, or after [`CLOSE_TIMEOUT`].
+    ///
+    /// Returns the number of readings the broker never acknowledged.
+    pub fn close(self) -> usize {
+        self.stopping.store(true, Ordering::Relaxed);
+        {
+            let mut deliveries = self.deliveries.lock().unwrap();
+            if self
+                .client
+                .try_publish(&self.availability_topic, self.qos, true, OFFLINE)
+                .is_ok()
+            {
+                deliveries.queued.push_back(Message::Announcement);
+            }
+        }
+        // Disconnecting drops whatever the broker has not acknowledged yet.
+        // The connection thread exits early if the broker goes away.
+        let deadline = Instant::now() + CLOSE_TIMEOUT;
+        while !self.deliveries.lock().unwrap().pending.is_empty()
+            && !self.connection.is_finished()
+            && Instant::now() < deadline
+        {
+            thread::sleep(Duration::from_millis(50));
+        }
+        let _ = self.client.try_disconnect();
+        let _ = self.connection.join();
+        self.deliveries.lock().unwrap().pending.len()
+    }
+}
+
+/// Drives `connection` until the sink is closed, announcing the device on
+/// every (re)connect, counting acknowledged messages towards `deliveries`
+/// and backing off while the broker is unreachable.
+fn drive(
+    mut connection: Connection,
+    client: &Client,
+    announcement: &Announcement,
+    deliveries: &Mutex<Deliveries>,
+    backoff: &RetryPolicy,
+    stopping: &AtomicBool,
+) {
+    let mut failures = 0;
+    for event in connection.iter() {
+        match event {
+            Ok(Event::Incoming(Packet::ConnAck(_))) => {
+                if failures > 0 {
+                    info!("Reconnected to MQTT broker");
+                } else {
+                    info!("Connected to MQTT broker");
+                }
+                failures = 0;
+                announcement.publish(client, &mut deliveries.lock().unwrap());
+            }
+            Ok(Event::Incoming(Packet::Publish(publish)))
+                if announcement.discovery_status_topic.as_deref() == Some(&publish.topic)
+                    && &publish.payload[..] == ONLINE.as_bytes() =>
+            {
+                info!("Home Assistant restarted, republishing discovery");
+                announcement.publish_discovery(client, &mut deliveries.lock().unwrap());
+            }
+            Ok(Event::Outgoing(Outgoing::Publish(pkid))) => deliveries.lock().unwrap().sent(pkid),
+            // QoS 1 messages are acknowledged with a PUBACK, QoS 2 ones with
+            // a PUBCOMP
+            Ok(Event::Incoming(Packet::PubAck(PubAck { pkid, .. })))
+            | Ok(Event::Incoming(Packet::PubComp(PubComp { pkid, .. }))) => {
+                deliveries.lock().unwrap().ack(pkid)
+            }
+            Ok(Event::Outgoing(Outgoing::Disconnect)) if stopping.load(Ordering::Relaxed) => {
+                break;
+            }
+            Ok(_) => {}
+            Err(_) if stopping.load(Ordering::Relaxed) => break,
+            Err(e) => {
+                failures += 1;
+                let delay = backoff.delay(failures);
+                warn!(
+                    "MQTT connection failed: {}; retrying in {:.1}s (attempt {})",
+                    e,
+                    delay.as_secs_f32(),
+                    failures
+                );
+                if !sleep_unless_stopped(delay, stopping) {
+                    break;
+                }
+            }
+        }
+    }
+}
+
+/// Publishes readings from `queue` until it is closed and drained.
+pub fn run(queue: &BoundedQueue<Reading>, stats: Arc<SinkStats>, sink: MqttSink) {
+    sink.deliveries.lock().unwrap().stats = Some(Arc::clone(&stats));
+    while let Some(reading) = queue.pop() {
+        if !sink.publish(&reading) {
+            stats.failed(1);
+        }
+    }
+    stats.failed(sink.close());
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::DateTime;
+    use sensor_reader::{Aqi, AqiStandard, Pollutant, SensorData, Stamper};
+
+    fn reading() -> Reading {
+        let mut stamper = Stamper::new(DeviceInfo {
+            id: "m701-lab".into(),
+            location: Some("lab".into()),
+            ..DeviceInfo::default()
+        });
+        let at = DateTime::parse_from_rfc3339("2024-05-01T12:00:00Z")
+            .unwrap()
+            .to_utc();
+        stamper.stamp_at(
+            SensorData {
+                eco2: 400,
+                ech2o: 5,
+                tvoc: 10,
+                pm2_5: 20,
+                pm10: 30,
+                temperature: 25.5,
+                humidity: 50.2,
+            },
+            at,
+        )
+    }
+
+    #[test]
+    fn test_parse_broker_url() {
+        assert_eq!(
+            parse_broker_url("mqtt://broker.lan").unwrap(),
+            ("broker.lan".to_string(), 1883, false)
+        );
+        assert_eq!(
+            parse_broker_url("mqtts://broker.lan").unwrap(),
+            ("broker.lan".to_string(), 8883, true)
+        );
+        assert_eq!(
+            parse_broker_url("tcp://10.0.0.2:1884").unwrap(),
+            ("10.0.0.2".to_string(), 1884, false)
+        );
+        assert!(parse_broker_url("http://broker.lan").is_err());
+        assert!(parse_broker_url("broker.lan:1883").is_err());
+    }
+
+    #[test]
+    fn test_messages() {
+        let mut reading = reading();
+        reading.aqi = vec![Aqi {
+            standard: AqiStandard::UsEpa,
+            index: 57,
+            category: "Moderate".into(),
+            dominant: Some(Pollutant::Pm2_5),
+            pm2_5: 57,
+            pm10: 28,
+        }];
+
+        let json = messages("sensors/{location}/{device_id}", &reading).unwrap();
+        assert_eq!(json.len(), 1);
+        assert_eq!(json[0].0, "sensors/lab/m701-lab");
+        let body: serde_json::Value = serde_json::from_slice(&json[0].1).unwrap();
+        assert_eq!(body["eco2"], 400);
+
+        let fields = messages("sensors/{device_id}/{field}", &reading).unwrap();
+        assert_eq!(fields.len(), 8);
+        assert_eq!(fields[0], ("sensors/m701-lab/eco2".into(), b"400".to_vec()));
+        assert_eq!(
+            fields[6],
+            ("sensors/m701-lab/humidity".into(), b"50.2".to_vec())
+        );
+        assert_eq!(
+            fields[7],
+            ("sensors/m701-lab/aqi_us_epa".into(), b"57".to_vec())
+        );
+    }
+
+    #[test]
+    fn test_deliveries() {
+        let stats = Arc::new(SinkStats::default());
+        let mut deliveries = Deliveries {
+            stats: Some(Arc::clone(&stats)),
+            ..Deliveries::default()
+        };
+        let delivered = || stats.delivered.load(Ordering::Relaxed);
+
+        let first = deliveries.add(1);
+        let second = deliveries.add(2);
+        deliveries.queued.extend([
+            Message::Reading(first),
+            Message::Reading(second),
+            Message::Reading(second),
+            Message::Announcement,
+        ]);
+        deliveries.sent(1);
+        deliveries.sent(2);
+        deliveries.sent(3);
+        deliveries.sent(4);
+        deliveries.ack(2);
+        assert_eq!(delivered(), 0);
+
+        // After a reconnect, the messages in flight are resent ahead of the
+        // announcements and acknowledged in any order
+        deliveries.queued.push_back(Message::Announcement);
+        deliveries.sent(1);
+        deliveries.sent(3);
+        deliveries.sent(4);
+        deliveries.sent(5);
+        deliveries.ack(4);
+        deliveries.ack(5);
+        assert_eq!(delivered(), 0);
+        deliveries.ack(3);
+        assert_eq!(delivered(), 1);
+        deliveries.ack(1);
+        assert_eq!(delivered(), 2);
+        assert!(deliveries.pending.is_empty());
+        assert!(deliveries.in_flight.is_empty());
+
+        // QoS 0 messages count as soon as they are sent
+        let third = deliveries.add(1);
+        deliveries.queued.push_back(Message::Reading(third));
+        deliveries.sent(0);
+        assert_eq!(delivered(), 3);
+    }
+
+    /// Needs a broker, e.g. `mosquitto -p 1883`; point SENSOR_TEST_MQTT_URL
+    /// elsewhere if it does not run on localhost.
+    #[test]
+    #[ignore]
+    fn test_publish_to_local_broker() {
+        let config = MqttSinkConfig {
+            enabled: true,
+            url: std::env::var("SENSOR_TEST_MQTT_URL")
+                .unwrap_or_else(|_| "mqtt://localhost:1883".into()),
+            topic: "sensor_reader-test/{device_id}/{field}".into(),
+            ..MqttSinkConfig::default()
+        };
+        let (host, port, _) = parse_broker_url(&config.url).unwrap();
+        let (subscriber, mut events) =
+            Client::new(MqttOptions::new("sensor_reader-test-sub", host, port), 10);
+        subscriber
+            .subscribe("sensor_reader-test/m701-lab/#", QoS::AtLeastOnce)
+            .unwrap();
+
+        // Publish once the subscription is in place
+        let reading = reading();
+        let mut sink = None;
+        for event in events.iter() {
+            match event.unwrap() {
+                Event::Incoming(Packet::SubAck(_)) => {
+                    let s = MqttSink::connect(&config, &reading.device, &[], &[]).unwrap();
+                    s.publish(&reading);
+                    sink = Some(s);
+                }
+                Event::Incoming(Packet::Publish(p)) if p.topic.ends_with("/humidity") => {
+                    assert_eq!(&p.payload[..], b"50.2");
+                    break;
+                }
+                _ => {}
+            }
+        }
+        sink.unwrap().close();
+    }
+}
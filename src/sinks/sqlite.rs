@@ -0,0 +1,648 @@
+//! Keeps a history of readings in an embedded SQLite database.
+
+use anyhow::{Context, Result};
+use chrono::{DateTime, Utc};
+use log::{error, info};
+use rusqlite::types::Value;
+use rusqlite::{Connection, OpenFlags, Row, TransactionBehavior, params, params_from_iter};
+use sensor_reader::{
+    Comfort, DeviceInfo, FIELD_NAMES, Reading, SCHEMA_VERSION, SensorData, Window,
+};
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::path::Path;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use crate::queue::BoundedQueue;
+use crate::sinks::SinkStats;
+
+/// Bumped whenever [`SCHEMA`] changes, with a matching entry in
+/// [`MIGRATIONS`].
+const SCHEMA_USER_VERSION: i64 = 5;
+
+const SCHEMA: &str = "
+    CREATE TABLE IF NOT EXISTS readings (
+        id          INTEGER PRIMARY KEY,
+        captured_at INTEGER NOT NULL, -- Unix milliseconds, UTC
+        seq         INTEGER NOT NULL,
+        session     TEXT,             -- NULL for readings from before sessions
+        device_id   TEXT NOT NULL,
+        location    TEXT,
+        tags        TEXT,             -- JSON object, NULL if empty
+        eco2        INTEGER NOT NULL,
+        ech2o       INTEGER NOT NULL,
+        tvoc        INTEGER NOT NULL,
+        pm2_5       INTEGER NOT NULL,
+        pm10        INTEGER NOT NULL,
+        temperature REAL NOT NULL,
+        humidity    REAL NOT NULL,
+        -- Derived metrics, NULL unless configured
+        dew_point         REAL,
+        absolute_humidity REAL,
+        heat_index        REAL,
+        humidex           REAL,
+        -- Set on summaries of several readings; captured_at is the window end
+        window_start  INTEGER,          -- Unix milliseconds, UTC
+        samples       INTEGER,
+        window_fields TEXT,             -- JSON object of per-field min/mean/max/last
+        aqi           TEXT              -- JSON array of air quality indices, NULL if none
+    );
+    CREATE INDEX IF NOT EXISTS readings_device_time ON readings (device_id, captured_at);
+    CREATE INDEX IF NOT EXISTS readings_time ON readings (captured_at);
+";
+
+/// Brings a database from schema version `i + 1` to `i + 2`, for the `i`th
+/// entry.
+const MIGRATIONS: [&str; 4] = [
+    "
+    ALTER TABLE readings ADD COLUMN dew_point REAL;
+    ALTER TABLE readings ADD COLUMN absolute_humidity REAL;
+    ALTER TABLE readings ADD COLUMN heat_index REAL;
+    ALTER TABLE readings ADD COLUMN humidex REAL;
+    ",
+    "
+    ALTER TABLE readings ADD COLUMN window_start INTEGER;
+    ALTER TABLE readings ADD COLUMN samples INTEGER;
+    ALTER TABLE readings ADD COLUMN window_fields TEXT;
+    ",
+    "
+    ALTER TABLE readings ADD COLUMN aqi TEXT;
+    ",
+    "
+    ALTER TABLE readings ADD COLUMN session TEXT;
+    ",
+];
+
+/// Returns the schema version of the database at `path`, 0 if it is new.
+fn schema_version(conn: &Connection, path: &Path) -> Result<i64> {
+    let version: i64 = conn.pragma_query_value(None, "user_version", |row| row.get(0))?;
+    if version > SCHEMA_USER_VERSION {
+        anyhow::bail!(
+            "Database '{}' has schema version {}, newer than the supported {}",
+            path.display(),
+            version,
+            SCHEMA_USER_VERSION
+        );
+    }
+    Ok(version)
+}
+
+/// How often old readings are pruned when `max_age` is set.
+const PRUNE_INTERVAL: Duration = Duration::from_secs(3600);
+
+/// Selects readings by time and, optionally, device.
+#[derive(Debug, Clone, Default)]
+pub struct Filter {
+    /// Inclusive lower bound.
+    pub from: Option<DateTime<Utc>>,
+    /// Exclusive upper bound.
+    pub to: Option<DateTime<Utc>>,
+    pub device_id: Option<String>,
+}
+
+impl Filter {
+    /// Returns the `WHERE` clause and its parameters.
+    fn to_sql(&self) -> (String, Vec<Value>) {
+        let mut clauses = Vec::new();
+        let mut values = Vec::new();
+        if let Some(from) = self.from {
+            clauses.push("captured_at >= ?");
+            values.push(Value::Integer(from.timestamp_millis()));
+        }
+        if let Some(to) = self.to {
+            clauses.push("captured_at < ?");
+            values.push(Value::Integer(to.timestamp_millis()));
+        }
+        if let Some(device_id) = &self.device_id {
+            clauses.push("device_id = ?");
+            values.push(Value::Text(device_id.clone()));
+        }
+        if clauses.is_empty() {
+            (String::new(), values)
+        } else {
+            (format!("WHERE {}", clauses.join(" AND ")), values)
+        }
+    }
+}
+
+/// Minimum, mean and maximum of one field over an interval.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Summary {
+    pub min: f64,
+    pub avg: f64,
+    pub max: f64,
+}
+
+/// Statistics for one device over one interval.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Bucket {
+    pub start: DateTime<Utc>,
+    pub device_id: String,
+    pub count: u64,
+    /// Keyed by field name.
+    pub fields: BTreeMap<&'static str, Summary>,
+}
+
+/// The readings database.
+pub struct History {
+    conn: Connection,
+}
+
+impl History {
+    /// Opens the database at `path`, creating it and its schema if needed.
+    pub fn open(path: &Path) -> Result<Self> {
+        let mut conn = Connection::open(path)
+            .with_context(|| format!("Failed to open database '{}'", path.display()))?;
+        // WAL lets `query` read while the reader keeps writing
+        conn.pragma_update(None, "journal_mode", "WAL")?;
+        conn.busy_timeout(Duration::from_secs(5))?;
+
+        // All or nothing, so that a failed migration can be retried
+        let tx = conn.transaction_with_behavior(TransactionBehavior::Immediate)?;
+        let version = schema_version(&tx, path)?;
+        // A new database gets the current schema right away
+        if version > 0 {
+            for migration in &MIGRATIONS[version as usize - 1..] {
+                tx.execute_batch(migration)
+                    .with_context(|| format!("Failed to migrate '{}'", path.display()))?;
+            }
+        }
+        tx.execute_batch(SCHEMA)
+            .with_context(|| format!("Failed to create schema in '{}'", path.display()))?;
+        tx.pragma_update(None, "user_version", SCHEMA_USER_VERSION)?;
+        tx.commit()?;
+        Ok(Self { conn })
+    }
+
+    /// Opens the existing database at `path` for reading only, without
+    /// touching its journal mode or schema, e.g. on a read-only mount.
+    pub fn open_read_only(path: &Path) -> Result<Self> {
+        let conn = Connection::open_with_flags(
+            path,
+            OpenFlags::SQLITE_OPEN_READ_ONLY | OpenFlags::SQLITE_OPEN_NO_MUTEX,
+        )
+        .with_context(|| format!("Failed to open database '{}'", path.display()))?;
+        conn.busy_timeout(Duration::from_secs(5))?;
+
+        let version = schema_version(&conn, path)?;
+        if version < SCHEMA_USER_VERSION {
+            anyhow::bail!(
+                "Database '{}' has schema version {}, older than the supported {}; \
+                 start the reader with it once to upgrade it",
+                path.display(),
+                version,
+                SCHEMA_USER_VERSION
+            );
+        }
+        Ok(Self { conn })
+    }
+
+    pub fn insert(&self, reading: &Reading) -> Result<()> {
+        let tags = if reading.device.tags.is_empty() {
+            None
+        } else {
+            Some(serde_json::to_string(&reading.device.tags)?)
+        };
+        let [eco2, ech2o, tvoc, pm2_5, pm10, temperature, humidity] =
+            reading.data.fields().map(|(_, value)| value);
+        let [dew_point, absolute_humidity, heat_index, humidex] = reading.comfort.values();
+        let window_fields = match &reading.window {
+            Some(window) => Some(serde_json::to_string(&window.fields)?),
+            None => None,
+        };
+        let aqi = if reading.aqi.is_empty() {
+            None
+        } else {
+            Some(serde_json::to_string(&reading.aqi)?)
+        };
+        self.conn.execute(
+            "INSERT INTO readings (captured_at, seq, session, device_id, location, tags,
+                 eco2, ech2o, tvoc, pm2_5, pm10, temperature, humidity,
+                 dew_point, absolute_humidity, heat_index, humidex,
+                 window_start, samples, window_fields, aqi)
+             VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11, ?12, ?13, ?14, ?15, ?16,
+                 ?17, ?18, ?19, ?20, ?21)",
+            params![
+                reading.captured_at.timestamp_millis(),
+                reading.seq as i64,
+                (!reading.session.is_empty()).then_some(&reading.session),
+                reading.device.id,
+                reading.device.location,
+                tags,
+                eco2,
+                ech2o,
+                tvoc,
+                pm2_5,
+                pm10,
+                temperature,
+                humidity,
+                dew_point,
+                absolute_humidity,
+                heat_index,
+                humidex,
+                reading.window.as_ref().map(|w| w.start.timestamp_millis()),
+                reading.window.as_ref().map(|w| w.samples as i64),
+                window_fields,
+                aqi
+            ],
+        )?;
+        Ok(())
+    }
+
+    /// Deletes readings captured before `cutoff`, returning how many.
+    pub fn prune(&self, cutoff: DateTime<Utc>) -> Result<usize> {
+        Ok(self.conn.execute(
+            "DELETE FROM readings WHERE captured_at < ?1",
+            params![cutoff.timestamp_millis()],
+        )?)
+    }
+
+    fn reading(row: &Row) -> rusqlite::Result<Reading> {
+        let millis: i64 = row.get("captured_at")?;
+        let captured_at = DateTime::from_timestamp_millis(millis).unwrap_or_default();
+        let tags: Option<String> = row.get("tags")?;
+        let aqi: Option<String> = row.get("aqi")?;
+        let window_start: Option<i64> = row.get("window_start")?;
+        let window = match window_start {
+            Some(start) => {
+                let fields: Option<String> = row.get("window_fields")?;
+                Some(Window {
+                    start: DateTime::from_timestamp_millis(start).unwrap_or_default(),
+                    end: captured_at,
+                    samples: row.get::<_, i64>("samples")? as usize,
+                    fields: fields
+                        .and_then(|fields| serde_json::from_str(&fields).ok())
+                        .unwrap_or_default(),
+                })
+            }
+            None => None,
+        };
+        Ok(Reading {
+            schema_version: SCHEMA_VERSION,
+            captured_at,
+            seq: row.get::<_, i64>("seq")? as u64,
+            session: row.get::<_, Option<String>>("session")?.unwrap_or_default(),
+            device: DeviceInfo {
+                id: row.get("device_id")?,
+                location: row.get("location")?,
+                tags: tags
+                    .and_then(|tags| serde_json::from_str(&tags).ok())
+                    .unwrap_or_default(),
+            },
+            data: SensorData {
+                eco2: row.get("eco2")?,
+                ech2o: row.get("ech2o")?,
+                tvoc: row.get("tvoc")?,
+                pm2_5: row.get("pm2_5")?,
+                pm10: row.get("pm10")?,
+                temperature: row.get::<_, f64>("temperature")? as f32,
+                humidity: row.get::<_, f64>("humidity")? as f32,
+            },
+            comfort: Comfort {
+                dew_point: row.get("dew_point")?,
+                absolute_humidity: row.get("absolute_humidity")?,
+                heat_index: row.get("heat_index")?,
+                humidex: row.get("humidex")?,
+            },
+            aqi: aqi
+                .and_then(|aqi| serde_json::from_str(&aqi).ok())
+                .unwrap_or_default(),
+            window,
+        })
+    }
+
+    /// Returns the readings matching `filter`, oldest first.
+    pub fn readings(&self, filter: &Filter) -> Result<Vec<Reading>> {
+        let (clause, values) = filter.to_sql();
+        let mut stmt = self.conn.prepare(&format!(
+            "SELECT * FROM readings {} ORDER BY captured_at, id",
+            clause
+        ))?;
+        let rows = stmt.query_map(params_from_iter(values), Self::reading)?;
+        Ok(rows.collect::<rusqlite::Result<_>>()?)
+    }
+
+    /// Computes min/avg/max of every field per device and `interval`,
+    /// oldest interval first. Intervals are aligned to the Unix epoch.
+    pub fn aggregate(&self, filter: &Filter, interval: Duration) -> Result<Vec<Bucket>> {
+        let interval_ms = (interval.as_millis() as i64).max(1);
+        let columns: Vec<_> = FIELD_NAMES
+            .iter()
+            .map(|f| format!("MIN({f}), AVG({f}), MAX({f})"))
+            .collect();
+        let (clause, values) = filter.to_sql();
+        let mut stmt = self.conn.prepare(&format!(
+            "SELECT (captured_at / {interval_ms}) * {interval_ms} AS bucket, device_id, COUNT(*), {}
+             FROM readings {} GROUP BY bucket, device_id ORDER BY bucket, device_id",
+            columns.join(", "),
+            clause
+        ))?;
+        let rows = stmt.query_map(params_from_iter(values), |row| {
+            let mut fields = BTreeMap::new();
+            for (i, name) in FIELD_NAMES.iter().enumerate() {
+                let column = 3 + i * 3;
+                fields.insert(
+                    *name,
+                    Summary {
+                        min: row.get(column)?,
+                        avg: (row.get::<_, f64>(column + 1)? * 100.0).round() / 100.0,
+                        max: row.get(column + 2)?,
+                    },
+                );
+            }
+            Ok(Bucket {
+                start: DateTime::from_timestamp_millis(row.get(0)?).unwrap_or_default(),
+                device_id: row.get(1)?,
+                count: row.get::<_, i64>(2)? as u64,
+                fields,
+            })
+        })?;
+        Ok(rows.collect::<rusqlite::Result<_>>()?)
+    }
+}
+
+/// Stores readings from `queue` until it is closed and drained, pruning
+/// readings older than `max_age` along the way.
+pub fn run(
+    queue: &BoundedQueue<Reading>,
+    stats: Arc<SinkStats>,
+    history: History,
+    max_age: Option<Duration>,
+) {
+    let mut next_prune = Instant::now();
+    while let Some(reading) = queue.pop() {
+        match history.insert(&reading) {
+            Ok(()) => stats.delivered(1),
+            Err(e) => {
+                error!("Failed to store reading #{}: {:#}", reading.seq, e);
+                stats.failed(1);
+            }
+        }
+
+        if let Some(max_age) = max_age
+            && Instant::now() >= next_prune
+        {
+            next_prune = Instant::now() + PRUNE_INTERVAL;
+            match history.prune(Utc::now() - max_age) {
+                Ok(0) => {}
+                Ok(n) => info!("Pruned {} readings from history", n),
+                Err(e) => error!("Failed to prune history: {:#}", e),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeDelta;
+    use sensor_reader::{Aggregator, Aqi, AqiStandard, ComfortMetric, Pollutant, Stamper};
+
+    fn at(s: &str) -> DateTime<Utc> {
+        DateTime::parse_from_rfc3339(s).unwrap().to_utc()
+    }
+
+    fn data(eco2: u16, temperature: f32) -> SensorData {
+        SensorData {
+            eco2,
+            ech2o: 5,
+            tvoc: 10,
+            pm2_5: 20,
+            pm10: 30,
+            temperature,
+            humidity: 50.2,
+        }
+    }
+
+    fn history() -> (tempfile::TempDir, History) {
+        let dir = tempfile::tempdir().unwrap();
+        let history = History::open(&dir.path().join("history.db")).unwrap();
+        let mut lab = Stamper::new(DeviceInfo {
+            id: "lab".into(),
+            location: Some("Lab".into()),
+            tags: BTreeMap::from([("floor".into(), "2".into())]),
+        });
+        let mut office = Stamper::new(DeviceInfo {
+            id: "office".into(),
+            ..DeviceInfo::default()
+        });
+        for (office_reading, eco2, temperature, time) in [
+            (false, 400, 20.0, "2024-05-01T12:00:00Z"),
+            (false, 500, 21.5, "2024-05-01T12:30:00Z"),
+            (false, 900, 22.0, "2024-05-01T13:10:00Z"),
+            (true, 600, 19.0, "2024-05-01T12:15:00Z"),
+        ] {
+            let stamper = if office_reading {
+                &mut office
+            } else {
+                &mut lab
+            };
+            history
+                .insert(&stamper.stamp_at(data(eco2, temperature), at(time)))
+                .unwrap();
+        }
+        (dir, history)
+    }
+
+    #[test]
+    fn test_readings_round_trip() {
+        let (_dir, history) = history();
+        let all = history.readings(&Filter::default()).unwrap();
+        assert_eq!(all.len(), 4);
+        assert_eq!(all[1].device.id, "office");
+        assert_eq!(all[0].device.tags["floor"], "2");
+        assert_eq!(all[0].data, data(400, 20.0));
+
+        let lab = history
+            .readings(&Filter {
+                from: Some(at("2024-05-01T12:30:00Z")),
+                to: Some(at("2024-05-01T13:10:00Z")),
+                device_id: Some("lab".into()),
+            })
+            .unwrap();
+        assert_eq!(lab.len(), 1);
+        assert_eq!(lab[0].data.eco2, 500);
+        assert_eq!(lab[0].captured_at, at("2024-05-01T12:30:00Z"));
+    }
+
+    #[test]
+    fn test_aggregate() {
+        let (_dir, history) = history();
+        let buckets = history
+            .aggregate(&Filter::default(), Duration::from_secs(3600))
+            .unwrap();
+        assert_eq!(buckets.len(), 3);
+
+        assert_eq!(buckets[0].start, at("2024-05-01T12:00:00Z"));
+        assert_eq!(buckets[0].device_id, "lab");
+        assert_eq!(buckets[0].count, 2);
+        assert_eq!(
+            buckets[0].fields["eco2"],
+            Summary {
+                min: 400.0,
+                avg: 450.0,
+                max: 500.0
+            }
+        );
+        assert_eq!(buckets[0].fields["temperature"].avg, 20.75);
+        assert_eq!(buckets[1].device_id, "office");
+        assert_eq!(buckets[2].start, at("2024-05-01T13:00:00Z"));
+    }
+
+    #[test]
+    fn test_window_round_trip() {
+        let dir = tempfile::tempdir().unwrap();
+        let history = History::open(&dir.path().join("history.db")).unwrap();
+        let mut stamper = Stamper::new(DeviceInfo {
+            id: "lab".into(),
+            ..DeviceInfo::default()
+        });
+        let mut aggregator = Aggregator::new(TimeDelta::seconds(60), TimeDelta::seconds(60));
+        let mut summaries = Vec::new();
+        for (eco2, time) in [
+            (400, "2024-05-01T12:00:10Z"),
+            (700, "2024-05-01T12:00:40Z"),
+            (500, "2024-05-01T12:01:10Z"),
+        ] {
+            summaries.extend(aggregator.push(stamper.stamp_at(data(eco2, 20.0), at(time))));
+        }
+        summaries.extend(aggregator.flush());
+        for summary in &summaries {
+            history.insert(summary).unwrap();
+        }
+
+        let all = history.readings(&Filter::default()).unwrap();
+        assert_eq!(all, summaries);
+        let window = all[0].window.as_ref().unwrap();
+        assert_eq!(window.start, at("2024-05-01T12:00:00Z"));
+        assert_eq!(window.samples, 2);
+        assert_eq!(window.fields["eco2"].max, 700.0);
+    }
+
+    #[test]
+    fn test_aqi_round_trip() {
+        let dir = tempfile::tempdir().unwrap();
+        let history = History::open(&dir.path().join("history.db")).unwrap();
+        let mut stamper = Stamper::new(DeviceInfo {
+            id: "lab".into(),
+            ..DeviceInfo::default()
+        });
+        let mut reading = stamper.stamp_at(data(400, 20.0), at("2024-05-01T12:00:00Z"));
+        reading.aqi = vec![Aqi {
+            standard: AqiStandard::UsEpa,
+            index: 57,
+            category: "Moderate".into(),
+            dominant: Some(Pollutant::Pm2_5),
+            pm2_5: 57,
+            pm10: 28,
+        }];
+        history.insert(&reading).unwrap();
+        history
+            .insert(&stamper.stamp_at(data(500, 20.0), at("2024-05-01T12:01:00Z")))
+            .unwrap();
+
+        let all = history.readings(&Filter::default()).unwrap();
+        assert_eq!(all[0].aqi, reading.aqi);
+        assert!(all[1].aqi.is_empty());
+    }
+
+    #[test]
+    fn test_migrates_version_1() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("history.db");
+        let conn = Connection::open(&path).unwrap();
+        conn.execute_batch(
+            "CREATE TABLE readings (
+                 id INTEGER PRIMARY KEY, captured_at INTEGER NOT NULL, seq INTEGER NOT NULL,
+                 device_id TEXT NOT NULL, location TEXT, tags TEXT,
+                 eco2 INTEGER NOT NULL, ech2o INTEGER NOT NULL, tvoc INTEGER NOT NULL,
+                 pm2_5 INTEGER NOT NULL, pm10 INTEGER NOT NULL,
+                 temperature REAL NOT NULL, humidity REAL NOT NULL
+             );
+             INSERT INTO readings VALUES (1, 1714564800000, 0, 'lab', NULL, NULL,
+                 400, 5, 10, 20, 30, 20.0, 50.2);
+             PRAGMA user_version = 1;",
+        )
+        .unwrap();
+        drop(conn);
+
+        let history = History::open(&path).unwrap();
+        let mut stamper = Stamper::new(DeviceInfo {
+            id: "lab".into(),
+            ..DeviceInfo::default()
+        });
+        let mut reading = stamper.stamp_at(data(500, 20.0), at("2024-05-01T12:01:00Z"));
+        reading.comfort = Comfort::compute(&reading.data, &[ComfortMetric::DewPoint]);
+        history.insert(&reading).unwrap();
+
+        let all = history.readings(&Filter::default()).unwrap();
+        assert_eq!(all.len(), 2);
+        assert_eq!(all[0].data, data(400, 20.0));
+        assert_eq!(all[0].comfort, Comfort::default());
+        assert_eq!(all[0].session, "");
+        assert_eq!(all[1].comfort, reading.comfort);
+        assert_eq!(all[1].session, reading.session);
+        assert_eq!(all[1].comfort.dew_point, Some(9.3));
+    }
+
+    #[test]
+    fn test_failed_migration_is_rolled_back() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("history.db");
+        let conn = Connection::open(&path).unwrap();
+        // At version 3, yet with a column the last migration adds
+        conn.execute_batch(
+            "CREATE TABLE readings (
+                 id INTEGER PRIMARY KEY, captured_at INTEGER NOT NULL, seq INTEGER NOT NULL,
+                 device_id TEXT NOT NULL, session TEXT);
+             PRAGMA user_version = 3;",
+        )
+        .unwrap();
+        drop(conn);
+
+        assert!(History::open(&path).is_err());
+        let conn = Connection::open(&path).unwrap();
+        let version: i64 = conn
+            .pragma_query_value(None, "user_version", |row| row.get(0))
+            .unwrap();
+        assert_eq!(version, 3);
+        assert!(conn.prepare("SELECT aqi FROM readings").is_err());
+    }
+
+    #[test]
+    fn test_open_read_only() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("history.db");
+        let conn = Connection::open(&path).unwrap();
+        conn.execute_batch("PRAGMA user_version = 1;").unwrap();
+        drop(conn);
+
+        // An outdated database is left as it is
+        assert!(History::open_read_only(&path).is_err());
+        let conn = Connection::open(&path).unwrap();
+        let version: i64 = conn
+            .pragma_query_value(None, "user_version", |row| row.get(0))
+            .unwrap();
+        assert_eq!(version, 1);
+        drop(conn);
+
+        let path = dir.path().join("current.db");
+        let mut stamper = Stamper::new(DeviceInfo {
+            id: "lab".into(),
+            ..DeviceInfo::default()
+        });
+        let reading = stamper.stamp_at(data(400, 20.0), at("2024-05-01T12:00:00Z"));
+        History::open(&path).unwrap().insert(&reading).unwrap();
+        let history = History::open_read_only(&path).unwrap();
+        assert_eq!(history.readings(&Filter::default()).unwrap()[0], reading);
+        assert!(history.insert(&reading).is_err());
+    }
+
+    #[test]
+    fn test_prune() {
+        let (_dir, history) = history();
+        assert_eq!(history.prune(at("2024-05-01T12:20:00Z")).unwrap(), 2);
+        assert_eq!(history.readings(&Filter::default()).unwrap().len(), 2);
+    }
+}